@@ -0,0 +1,7 @@
+// Builds the C half of guarded calls. Jumping back into a function that called
+// setjmp is only defined in C, so the call is made from there.
+
+fn main() {
+  println!("cargo:rerun-if-changed=src/guard.c");
+  cc::Build::new().file("src/guard.c").compile("guard");
+}
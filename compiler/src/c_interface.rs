@@ -1,8 +1,10 @@
 // external C interface for the compiler (so that the language can use it)
 
 use crate::common::*;
-use crate::{lexer, parser};
+use crate::{lexer, parser, trace, snapshot, guard};
 use crate::compiler::Compiler;
+use crate::region::Region;
+use crate::event_log::EventLog;
 use crate::expr::{Expr, ExprContent};
 
 use std::fs::File;
@@ -15,7 +17,7 @@ use std::mem::ManuallyDrop;
 use std::time::{Instant, Duration};
 use std::sync::mpsc::{channel, TryRecvError, Receiver};
 
-use notify::{Watcher, RecursiveMode, watcher, DebouncedEvent, ReadDirectoryChangesWatcher};
+use notify::{Watcher, RecommendedWatcher, RecursiveMode, watcher, DebouncedEvent};
 use libloading::{Library, Symbol};
 
 use std::{thread, time};
@@ -158,8 +160,19 @@ extern {
 }
 
 #[no_mangle]
-pub extern "C" fn panic(s : SStr) {
-  panic!("EXPLICIT PANIC: {}", s.as_str())
+pub extern "C" fn panic(s : &SStr) {
+  guard::raise_panic(s.as_str().into())
+}
+
+/// Called by bounds-checked code when an array index is out of range.
+/// The message is passed as separate arguments to avoid the struct ABI issue.
+#[no_mangle]
+pub extern "C" fn index_out_of_bounds(message_ptr : *const u8, message_length : u64, index : i64, length : u64) {
+  let message = unsafe {
+    let bytes = std::slice::from_raw_parts(message_ptr, message_length as usize);
+    std::str::from_utf8_unchecked(bytes)
+  };
+  guard::raise_panic(format!("{} (index {}, length {})", message, index, length))
 }
 
 #[no_mangle]
@@ -168,9 +181,10 @@ pub extern "C" fn load_expression(c : *mut Compiler, code_path : SStr) -> Box<Ex
   let mut code = String::new();
   f.read_to_string(&mut code).unwrap();
   let c = unsafe { &mut *c };
-  let aaa = (); // TODO: this is wrong. Use the code store to do this, so that the source id is logged properly.
-  let tokens = lexer::lex(no_source(), &code, &c.cache).unwrap();
-  let expr = parser::parse(no_source(), tokens, &c.cache).unwrap();
+  let source = create_unit(c.gen.next());
+  c.register_source_path(source, Path::new(code_path.as_str()));
+  let tokens = lexer::lex(source, &code, &c.cache).unwrap();
+  let expr = parser::parse(source, tokens, &c.cache).unwrap();
   Box::new(expr)
 }
 
@@ -207,6 +221,106 @@ pub extern "C" fn find_all_dependents(c : *mut Compiler, unit_id : UnitId, out :
   *out = SArray::new(deps);
 }
 
+/// Carries global state over from an old version of a module to a new one
+pub extern "C" fn migrate_state(c : *mut Compiler, old_unit : UnitId, new_unit : UnitId) {
+  let c = unsafe { &mut *c };
+  let report = c.migrate_state(old_unit, new_unit);
+  for name in report.skipped.iter() {
+    println!("Could not migrate global '{}'", name);
+  }
+}
+
+/// Writes the global state of a module to a file. Returns false if the file couldn't be written.
+pub extern "C" fn save_snapshot(c : *mut Compiler, unit_id : UnitId, path : SStr) -> bool {
+  let c = unsafe { &mut *c };
+  let bytes = snapshot::snapshot_globals(&c.code_store, unit_id);
+  match std::fs::write(path.as_str(), bytes) {
+    Ok(_) => true,
+    Err(e) => {
+      println!("Failed to write snapshot '{}': {}", path.as_str(), e);
+      false
+    }
+  }
+}
+
+/// Restores the global state of a module from a file written by `save_snapshot`.
+/// Returns false if the file couldn't be read as a snapshot.
+pub extern "C" fn load_snapshot(c : *mut Compiler, unit_id : UnitId, path : SStr) -> bool {
+  let c = unsafe { &mut *c };
+  let bytes = match std::fs::read(path.as_str()) {
+    Ok(bytes) => bytes,
+    Err(e) => {
+      println!("Failed to read snapshot '{}': {}", path.as_str(), e);
+      return false;
+    }
+  };
+  match snapshot::restore_globals(&c.code_store, unit_id, &bytes) {
+    Ok(report) => {
+      for name in report.skipped.iter() {
+        println!("Could not restore global '{}'", name);
+      }
+      true
+    }
+    Err(e) => {
+      println!("Failed to load snapshot '{}': {}", path.as_str(), e);
+      false
+    }
+  }
+}
+
+/// Replaces a module and its dependents with versions compiled from a new expression.
+/// If anything fails to compile, the old versions are kept and `out` is set to none.
+pub extern "C" fn replace_module(c : *mut Compiler, unit_id : UnitId, e : &Expr, out : &mut SOption<UnitId>) {
+  let c = unsafe { &mut *c };
+  *out = match c.replace_module_with_expr(unit_id, e) {
+    Ok((remapped, reports)) => {
+      for report in reports.iter() {
+        for diff in report.type_diffs.iter() {
+          println!("{}", diff);
+        }
+        for name in report.skipped.iter() {
+          println!("Could not migrate global '{}'", name);
+        }
+      }
+      remapped.get(&unit_id).cloned().into()
+    }
+    Err(e) => {
+      println!("Failed to replace module:\n{}", c.display_error(&e));
+      None.into()
+    }
+  };
+}
+
+/// Sets whether modules loaded from now on are called through patchable function slots
+pub extern "C" fn set_function_indirection(c : *mut Compiler, enabled : bool) {
+  let c = unsafe { &mut *c };
+  c.function_indirection = enabled;
+}
+
+/// Sets whether crashes in compiled code are reported as errors
+pub extern "C" fn set_guarded_execution(c : *mut Compiler, enabled : bool) {
+  let c = unsafe { &mut *c };
+  c.guarded_execution = enabled;
+}
+
+/// Reloads the modules whose source files changed, if the program is being hotloaded
+pub extern "C" fn reload_changed_files(c : *mut Compiler) {
+  let c = unsafe { &mut *c };
+  crate::watcher::reload_changed_files(c);
+}
+
+/// Sets whether modules loaded from now on are compiled with debug info
+pub extern "C" fn set_debug_info(c : *mut Compiler, enabled : bool) {
+  let c = unsafe { &mut *c };
+  c.debug_info = enabled;
+}
+
+/// Sets whether modules loaded from now on have bounds-checked indexing
+pub extern "C" fn set_bounds_checking(c : *mut Compiler, enabled : bool) {
+  let c = unsafe { &mut *c };
+  c.bounds_checking = enabled;
+}
+
 // TODO: panics if there is more than one overload, because no argument types
 // are provided to narrow the search, and it would be very unsafe to return
 // the wrong one.
@@ -219,15 +333,13 @@ pub extern "C" fn get_function(
 )
 {
   let c = unsafe { &mut *c };
-  let types = c.code_store.types(unit_id);
+  let cs = &c.code_store;
+  let types = cs.types(unit_id);
   let name = name.as_str();
   let mut i = types.symbols.values()
-    .filter(|def| def.name.as_ref() == name && def.type_tag.sig().is_some())
-    .flat_map(|def| def.codegen_name());
-  let lu = c.code_store.llvm_unit(unit_id);
-  let address =
-    i.next().and_then(|codegen_name|
-      unsafe { lu.ee.get_function_address(codegen_name) });
+    .filter(|def| def.name.as_ref() == name && def.type_tag.sig().is_some());
+  // Hand out the function's trampoline if it has one, so the pointer survives reloads
+  let address = i.next().and_then(|def| cs.function_address(def));
   *out = if i.next().is_some() {
     println!("two matching overloads for '{}' in get_function_address", name);
     None.into()
@@ -290,8 +402,122 @@ pub extern "C" fn millis_elapsed(timer : TimerHandle) -> u64 {
   v.duration_since(**timer).as_millis() as u64
 }
 
+pub type EventLogHandle = ManuallyDrop<Box<EventLog>>;
+
+#[no_mangle]
+pub extern "C" fn create_event_log(event_size : u64) -> EventLogHandle {
+  ManuallyDrop::new(Box::new(EventLog::new(event_size as usize)))
+}
+
+#[no_mangle]
+pub extern "C" fn drop_event_log(log : EventLogHandle) {
+  ManuallyDrop::into_inner(log);
+}
+
+#[no_mangle]
+pub extern "C" fn event_log_record(mut log : EventLogHandle, event : *const u8, event_size : u64) {
+  let event = unsafe { std::slice::from_raw_parts(event, event_size as usize) };
+  if let Err(message) = log.record(event) {
+    guard::raise_panic(message);
+  }
+}
+
+#[no_mangle]
+pub extern "C" fn event_log_next_frame(mut log : EventLogHandle) {
+  log.next_frame();
+}
+
+#[no_mangle]
+pub extern "C" fn event_log_frame_count(log : EventLogHandle) -> u64 {
+  log.frame_count() as u64
+}
+
+#[no_mangle]
+pub extern "C" fn event_log_event_count(log : EventLogHandle, frame : u64) -> u64 {
+  log.event_count(frame as usize) as u64
+}
+
+#[no_mangle]
+pub extern "C" fn event_log_get(log : EventLogHandle, frame : u64, index : u64) -> *const u8 {
+  log.event(frame as usize, index as usize).as_ptr()
+}
+
+#[no_mangle]
+pub extern "C" fn event_log_seek(mut log : EventLogHandle, frame : u64) {
+  log.seek(frame as usize);
+}
+
+#[no_mangle]
+pub extern "C" fn event_log_truncate(mut log : EventLogHandle, frames : u64) {
+  log.truncate(frames as usize);
+}
+
+/// Passes every event before the playhead to the handler. The events are copied
+/// first, because the handler is free to record more events.
+#[no_mangle]
+pub extern "C" fn event_log_replay(log : EventLogHandle, event_size : u64, handler : extern "C" fn(*const u8)) {
+  if event_size as usize != log.event_size() {
+    guard::raise_panic(format!(
+      "replayed a log of {} byte events as {} byte events", log.event_size(), event_size));
+  }
+  let events = log.replay_events().to_vec();
+  for e in events.chunks(event_size as usize) {
+    handler(e.as_ptr());
+  }
+}
+
+pub type RegionHandle = ManuallyDrop<Box<Region>>;
+
+#[no_mangle]
+pub extern "C" fn create_region() -> RegionHandle {
+  ManuallyDrop::new(Box::new(Region::new()))
+}
+
+#[no_mangle]
+pub extern "C" fn drop_region(r : RegionHandle) {
+  ManuallyDrop::into_inner(r);
+}
+
+/// Allocations are aligned to 8 bytes, the largest alignment of any primitive
+#[no_mangle]
+pub extern "C" fn region_alloc(mut r : RegionHandle, bytes : u64) -> *mut u8 {
+  r.alloc(bytes as usize, 8)
+}
+
+#[no_mangle]
+pub extern "C" fn region_bytes_used(r : RegionHandle) -> u64 {
+  r.bytes_used() as u64
+}
+
+/// Returns the region owned by a module. It is dropped when the module is unloaded,
+/// so it must not be passed to `drop_region`.
+pub extern "C" fn module_region(c : *mut Compiler, unit_id : UnitId) -> *mut Region {
+  let c = unsafe { &mut *c };
+  c.code_store.unit_region(unit_id) as *mut Region
+}
+
+/// Compacts a region by tracing from its root, which must have the named type, as
+/// seen from the given unit. The compacted contents are moved into the existing region,
+/// rather than replacing it, because regions returned by `module_region` are owned by
+/// the code store. The new root is returned.
+#[no_mangle]
+pub extern "C" fn compact_region(
+  c : *mut Compiler, r : &mut RegionHandle, root : *mut u8, unit_id : UnitId, type_name : &SStr)
+    -> *mut u8
+{
+  let c = unsafe { &mut *c };
+  let t = match c.code_store.find_monomorphic_type(unit_id, type_name.as_str()) {
+    Ok(t) => t,
+    Err(e) => guard::raise_panic(format!("compact_region failed. {}", e)),
+  };
+  let (new_region, new_root) = trace::compact(&c.code_store, &***r, root, &t);
+  // Drops the old pages
+  ***r = *new_region;
+  new_root
+}
+
 pub struct FileWatcher {
-  watcher : ReadDirectoryChangesWatcher,
+  watcher : RecommendedWatcher,
   rx : Receiver<DebouncedEvent>,
 }
 
@@ -457,6 +683,7 @@ impl CSymbols {
     sym.insert("free".into(), (free as *const()) as usize);
     sym.insert("memcpy".into(), (memcpy as *const()) as usize);
     sym.insert("panic".into(), (panic as *const()) as usize);
+    sym.insert("index_out_of_bounds".into(), (index_out_of_bounds as *const()) as usize);
     
 
     sym.insert("print_string".into(), (print_string as *const()) as usize);
@@ -475,6 +702,15 @@ impl CSymbols {
     sym.insert("load_module".into(), (load_module as *const()) as usize);
     sym.insert("unload_module".into(), (unload_module as *const()) as usize);
     sym.insert("find_all_dependents".into(), (find_all_dependents as *const()) as usize);
+    sym.insert("migrate_state".into(), (migrate_state as *const()) as usize);
+    sym.insert("replace_module".into(), (replace_module as *const()) as usize);
+    sym.insert("save_snapshot".into(), (save_snapshot as *const()) as usize);
+    sym.insert("load_snapshot".into(), (load_snapshot as *const()) as usize);
+    sym.insert("set_bounds_checking".into(), (set_bounds_checking as *const()) as usize);
+    sym.insert("set_function_indirection".into(), (set_function_indirection as *const()) as usize);
+    sym.insert("set_guarded_execution".into(), (set_guarded_execution as *const()) as usize);
+    sym.insert("set_debug_info".into(), (set_debug_info as *const()) as usize);
+    sym.insert("reload_changed_files".into(), (reload_changed_files as *const()) as usize);
     sym.insert("get_module".into(), (get_module as *const()) as usize);
     sym.insert("get_function".into(), (get_function as *const()) as usize);
 
@@ -482,6 +718,24 @@ impl CSymbols {
     sym.insert("drop_timer".into(), (drop_timer as *const()) as usize);
     sym.insert("millis_elapsed".into(), (millis_elapsed as *const()) as usize);
 
+    sym.insert("create_event_log".into(), (create_event_log as *const()) as usize);
+    sym.insert("drop_event_log".into(), (drop_event_log as *const()) as usize);
+    sym.insert("event_log_record".into(), (event_log_record as *const()) as usize);
+    sym.insert("event_log_next_frame".into(), (event_log_next_frame as *const()) as usize);
+    sym.insert("event_log_frame_count".into(), (event_log_frame_count as *const()) as usize);
+    sym.insert("event_log_event_count".into(), (event_log_event_count as *const()) as usize);
+    sym.insert("event_log_get".into(), (event_log_get as *const()) as usize);
+    sym.insert("event_log_seek".into(), (event_log_seek as *const()) as usize);
+    sym.insert("event_log_truncate".into(), (event_log_truncate as *const()) as usize);
+    sym.insert("event_log_replay".into(), (event_log_replay as *const()) as usize);
+
+    sym.insert("create_region".into(), (create_region as *const()) as usize);
+    sym.insert("drop_region".into(), (drop_region as *const()) as usize);
+    sym.insert("region_alloc".into(), (region_alloc as *const()) as usize);
+    sym.insert("region_bytes_used".into(), (region_bytes_used as *const()) as usize);
+    sym.insert("module_region".into(), (module_region as *const()) as usize);
+    sym.insert("compact_region".into(), (compact_region as *const()) as usize);
+
     sym.insert("poll_watcher_event".into(), (poll_watcher_event as *const()) as usize);
     sym.insert("create_watcher".into(), (create_watcher as *const()) as usize);
     sym.insert("drop_watcher".into(), (drop_watcher as *const()) as usize);
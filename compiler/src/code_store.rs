@@ -2,19 +2,24 @@
 use crate::{
   common, expr, structure,
   llvm_compile, types,
-  compiler,
+  compiler, region, indirection,
 };
 use common::*;
 use expr::Expr;
 use types::{
-  TypeInfo, SymbolId, Type, TypeMapping,
-  SymbolDefinition, TypeDefinition,
+  TypeInfo, SymbolId, Type, TypeMapping, TypeContent,
+  SymbolDefinition, SymbolInit, TypeDefinition,
 };
-use llvm_compile::LlvmUnit;
+use llvm_compile::{LlvmUnit, FunctionRange};
 use compiler::Val;
-use structure::Nodes;
+use structure::{Nodes, Content};
+use region::Region;
+use indirection::IndirectionTable;
 
-use std::collections::{HashMap, HashSet};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::cell::RefCell;
+use std::path::{Path, PathBuf};
+use std::fs;
 
 #[derive(Clone, Copy, PartialEq, Eq, Debug, Hash)]
 pub struct CodegenId(Uid);
@@ -26,6 +31,10 @@ pub struct CodeStore {
   pub code : HashMap<UnitId, RefStr>,
   pub names : HashMap<UnitId, RefStr>,
   pub imports : HashSet<(UnitId, UnitId)>,
+
+  /// Units imported under a namespace, keyed by the importing unit
+  pub namespaces : HashMap<UnitId, HashMap<RefStr, UnitId>>,
+
   pub exprs : HashMap<UnitId, Expr>,
   pub nodes : HashMap<UnitId, Nodes>,
   pub types : HashMap<UnitId, TypeInfo>,
@@ -42,6 +51,30 @@ pub struct CodeStore {
   /// Map from unit_id of a polymorphic instance to the definition
   /// that it is an instance of.
   pub poly_parents : HashMap<UnitId, SymbolId>,
+
+  /// Units that were compiled with bounds checks on array indexing
+  pub bounds_checked : HashSet<UnitId>,
+
+  /// Files that source code was loaded from, keyed by the source id used in text locations
+  pub source_paths : HashMap<SourceId, PathBuf>,
+
+  /// Regions owned by units, which are freed when the unit is removed
+  pub regions : HashMap<UnitId, Box<Region>>,
+
+  /// Stable entry points for the functions of units compiled with indirection
+  pub indirection : IndirectionTable,
+
+  /// Code that no unit maps to any more, but which may still be referenced.
+  /// It is freed once nothing refers to it.
+  pub retired_code : HashMap<CodegenId, LlvmUnit>,
+
+  /// Code whose function addresses were handed out, and may be stored anywhere.
+  /// It is never freed.
+  pub pinned_code : RefCell<HashSet<CodegenId>>,
+
+  /// The units that the running calls into compiled code were made from, innermost
+  /// last. No code is freed while there are any, because it may be on the stack.
+  pub running_units : RefCell<Vec<UnitId>>,
 }
 
 impl CodeStore {
@@ -61,22 +94,122 @@ impl CodeStore {
   }
 
   pub fn remove_unit(&mut self, uid : UnitId) {
-    let aaa = (); // TODO: remove the source. I'm not sure if the source ID is stored anywhere yet. It's supposed to be stored in TextLocations.
-    self.names.remove(&uid);
+    if let Some(name) = self.names.remove(&uid) {
+      self.indirection.retire_unit(&name);
+    }
+    self.code.remove(&uid);
+    self.source_paths.remove(&uid);
+    // Expressions read from a file are keyed by the source id in their locations
+    if let Some(source) = self.exprs.get(&uid).map(|e| e.loc.source) {
+      let shared = self.exprs.iter().any(|(&id, e)| id != uid && e.loc.source == source);
+      if !shared {
+        self.source_paths.remove(&source);
+      }
+    }
+    self.imports.retain(|(importer, _)| *importer != uid);
+    self.namespaces.remove(&uid);
     self.exprs.remove(&uid);
     self.nodes.remove(&uid);
     self.types.remove(&uid);
     self.type_mappings.remove(&uid);
     if let Some(codegen_id) = self.codegen_mapping.remove(&uid) {
-      let aaa = (); // TODO: I'm not convinced that this is sufficient to clean up the llvm module & its jitted binary code
-      self.llvm_units.remove(&codegen_id);
+      self.retire_code(codegen_id);
     }
     self.vals.remove(&uid);
+    self.bounds_checked.remove(&uid);
+    self.regions.remove(&uid);
     if let Some(sid) = self.poly_parents.remove(&uid) {
       if let Some(map) = self.poly_instances.get_mut(&sid) {
         map.retain(|_, sid| sid.uid != uid);
       }
     }
+    self.free_unused_code();
+  }
+
+  /// Stops mapping any unit to some code, unless other units of its group still use it.
+  /// The code is kept until `free_unused_code` finds that nothing refers to it.
+  pub fn retire_code(&mut self, codegen_id : CodegenId) {
+    if self.codegen_mapping.values().any(|&id| id == codegen_id) {
+      return;
+    }
+    if let Some(lu) = self.llvm_units.remove(&codegen_id) {
+      self.retired_code.insert(codegen_id, lu);
+    }
+  }
+
+  /// Frees retired code that can no longer be reached. Code is reachable if it is pinned,
+  /// if a function slot points into it, or if reachable code was linked against it.
+  /// Nothing is freed while compiled code is running.
+  pub fn free_unused_code(&mut self) {
+    if self.running_units.borrow().len() > 0 || self.retired_code.is_empty() {
+      return;
+    }
+    let mut reachable : HashSet<CodegenId> = self.pinned_code.borrow().clone();
+    for address in self.indirection.targets() {
+      let target = self.retired_code.iter().find(|(_, lu)| {
+        lu.function_ranges.iter().any(|r| r.start == address)
+      });
+      if let Some((&id, _)) = target {
+        reachable.insert(id);
+      }
+    }
+    let mut queue : VecDeque<CodegenId> =
+      self.llvm_units.keys().chain(reachable.iter()).cloned().collect();
+    while let Some(id) = queue.pop_front() {
+      let lu = self.llvm_units.get(&id).or_else(|| self.retired_code.get(&id));
+      for &linked in lu.iter().flat_map(|lu| lu.links_to.iter()) {
+        if reachable.insert(linked) {
+          queue.push_back(linked);
+        }
+      }
+    }
+    self.retired_code.retain(|id, _| reachable.contains(id));
+  }
+
+  /// Pins the code of functions that a unit takes the address of, rather than just calling
+  pub fn pin_escaping_functions(&self, unit_id : UnitId) {
+    let nodes = self.nodes(unit_id);
+    let mapping = self.type_mapping(unit_id);
+    let called : HashSet<_> = nodes.nodes.values().filter_map(|n| match &n.content {
+      Content::FunctionCall { function, .. } => Some(*function),
+      _ => None,
+    }).collect();
+    for (node_id, &sid) in mapping.symbol_references.iter() {
+      if called.contains(node_id) {
+        continue;
+      }
+      if let Content::Reference { .. } = nodes.node(*node_id).content {
+        let def = self.symbol_def(sid);
+        // Polymorphic references are to the instance of the referring node's type
+        let def = if def.is_polymorphic() {
+          match mapping.node_type.get(node_id).and_then(|t| self.poly_instance(sid, t)) {
+            Some(id) => self.symbol_def(id),
+            None => continue,
+          }
+        }
+        else {
+          def
+        };
+        if let SymbolInit::Function(_) = def.initialiser {
+          self.pin_function(def);
+        }
+      }
+    }
+  }
+
+  /// Pins the code of a function whose address was handed out, unless calls to it
+  /// go through a trampoline
+  fn pin_function(&self, def : &SymbolDefinition) {
+    if self.indirection.trampoline(self, def).is_none() {
+      if let Some(&codegen_id) = self.codegen_mapping.get(&def.unit_id) {
+        self.pinned_code.borrow_mut().insert(codegen_id);
+      }
+    }
+  }
+
+  /// Whether a call into a unit's compiled code is running, such as its top level
+  pub fn is_running(&self, unit_id : UnitId) -> bool {
+    self.running_units.borrow().contains(&unit_id)
   }
 
   pub fn name(&self, unit_id : UnitId) -> RefStr {
@@ -87,6 +220,37 @@ impl CodeStore {
     self.names.iter().find(|x| x.1.as_ref() == name).map(|x| *x.0)
   }
 
+  /// The file that a unit was loaded from, if any
+  pub fn source_path(&self, unit_id : UnitId) -> Option<&PathBuf> {
+    self.source_paths.get(&unit_id).or_else(|| {
+      // The unit may have been loaded from an expression that was read from a file
+      let source = self.exprs.get(&unit_id)?.loc.source;
+      self.source_paths.get(&source)
+    })
+  }
+
+  /// The files that the currently loaded units were compiled from
+  pub fn source_files(&self) -> HashSet<PathBuf> {
+    self.names.keys().flat_map(|&uid| self.source_path(uid)).cloned().collect()
+  }
+
+  /// The source files whose contents differ from the code that was loaded from them
+  pub fn changed_source_files(&self) -> Vec<PathBuf> {
+    self.source_files().into_iter().filter(|path| {
+      let loaded = self.unit_for_path(path).and_then(|uid| self.code.get(&uid));
+      match (fs::read_to_string(path), loaded) {
+        (Ok(code), Some(loaded)) => code.as_str() != loaded.as_ref(),
+        _ => true,
+      }
+    }).collect()
+  }
+
+  /// Finds the unit that was loaded from a file
+  pub fn unit_for_path(&self, path : &Path) -> Option<UnitId> {
+    let path = fs::canonicalize(path).ok()?;
+    self.names.keys().find(|&&uid| self.source_path(uid) == Some(&path)).cloned()
+  }
+
   pub fn nodes(&self, unit_id : UnitId) -> &Nodes {
     if let Some(parent_id) = self.poly_parents.get(&unit_id) {
       return self.nodes(parent_id.uid);
@@ -116,6 +280,32 @@ impl CodeStore {
     self.imports.iter().filter(move |(_, b)| *b == unit_id).map(|(a, _)| a)
   }
 
+  /// The address that a compiled function can be called at. This is the function's
+  /// trampoline if it has one, so that calls follow reloads. Otherwise the function's
+  /// code is pinned, so that it is never freed.
+  pub fn function_address(&self, def : &SymbolDefinition) -> Option<usize> {
+    self.indirection.trampoline(self, def).or_else(|| {
+      let codegen_name = def.codegen_name()?;
+      let lu = self.llvm_unit(def.unit_id);
+      let address = unsafe { lu.ee.get_function_address(codegen_name) }.map(|a| a as usize);
+      self.pin_function(def);
+      address
+    })
+  }
+
+  /// Finds the compiled function whose machine code contains an address
+  pub fn function_at(&self, address : usize) -> Option<&SymbolDefinition> {
+    self.function_range_at(address)
+      .and_then(|r| self.types.get(&r.symbol.uid)?.symbols.get(&r.symbol))
+  }
+
+  /// Finds the machine code of the compiled function that contains an address
+  pub fn function_range_at(&self, address : usize) -> Option<&FunctionRange> {
+    self.llvm_units.values()
+      .flat_map(|lu| lu.function_ranges.iter())
+      .find(|r| r.start <= address && address < r.end)
+  }
+
   pub fn symbol_def(&self, symbol_id : SymbolId) -> &SymbolDefinition {
     self.types(symbol_id.uid).symbols.get(&symbol_id).unwrap()
   }
@@ -128,6 +318,29 @@ impl CodeStore {
     self.type_mappings.get(&unit_id).unwrap()
   }
 
+  /// Returns the unit's region, creating it if necessary
+  pub fn unit_region(&mut self, unit_id : UnitId) -> &mut Region {
+    self.regions.entry(unit_id).or_insert_with(|| Box::new(Region::new()))
+  }
+
+  /// Finds a monomorphic type that a unit defines or imports
+  pub fn find_monomorphic_type(&self, unit_id : UnitId, name : &str) -> Result<Type, String> {
+    let types = self.types.get(&unit_id).ok_or("module is not loaded")?;
+    match types.find_type_def(name) {
+      Some(def) if !def.is_polymorphic() =>
+        Ok(Type::new(TypeContent::Def(def.name.clone(), def.unit_id), vec![])),
+      _ => Err(format!("module '{}' has no monomorphic type called '{}'", self.name(unit_id), name)),
+    }
+  }
+
+  /// Polymorphic instances inherit the setting of the unit that defines them
+  pub fn is_bounds_checked(&self, unit_id : UnitId) -> bool {
+    if let Some(parent_id) = self.poly_parents.get(&unit_id) {
+      return self.is_bounds_checked(parent_id.uid);
+    }
+    self.bounds_checked.contains(&unit_id)
+  }
+
   pub fn poly_instance(&self, poly_symbol_id : SymbolId, instance_type : &Type)
     -> Option<SymbolId>
   {
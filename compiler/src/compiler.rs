@@ -1,26 +1,44 @@
 
 use crate::{
   common, error, expr, c_interface, llvm_compile, code_store,
-  structure, lexer, parser, types, intrinsics, graph,
+  structure, lexer, parser, types, intrinsics, graph, migrate,
+  dependencies, layout, indirection, llvm_codegen, inspect, embed, guard, watcher,
 };
 use common::*;
 use expr::Expr;
 use c_interface::CSymbols;
-use code_store::CodeStore;
-use types::{TypeContent, PType, TypeInfo, TypeMapping };
-use llvm_compile::{LlvmCompiler, execute_function};
-use error::{Error, error, ErrorContent};
-use structure::TOP_LEVEL_FUNCTION_NAME;
+use code_store::{CodeStore, CodegenId};
+use types::{Type, TypeContent, PType, TypeInfo, TypeMapping, SymbolId, SymbolInit, SymbolDefinition};
+use llvm_compile::{LlvmCompiler, CompiledSymbols, FunctionRange, execute_function};
+use llvm_codegen::result_writer_name;
+use error::{Error, error, error_raw, ErrorContent, TextLocation, TextMarker, StackFrame};
+use structure::{TOP_LEVEL_FUNCTION_NAME, Nodes, NodeId, Content};
 use graph::DirectedGraph;
+use migrate::MigrationReport;
+use dependencies::{UnitChanges, SymbolChange};
+use layout::type_layout;
+use indirection::{IndirectionTable, SlotKey};
+use embed::HostFunction;
+use watcher::Hotloader;
+
+use itertools::Itertools;
 
 use std::fmt;
-use std::collections::{VecDeque, HashSet};
+use std::fs;
+use std::ptr;
+use std::slice;
+use std::path::{Path, PathBuf};
+use std::collections::{VecDeque, HashSet, HashMap};
 
 // TODO: Put these options somewhere more sensible
 pub static DEBUG_PRINTING_IR : bool = false;
 pub static ENABLE_IR_OPTIMISATION : bool = false;
 pub static DEBUG_PRINTING_DEPENDENCY_GRAPH : bool = false;
 pub static DEBUG_PRINTING_TYPE_INFERENCE : bool = false;
+pub static ENABLE_BOUNDS_CHECKS_BY_DEFAULT : bool = false;
+pub static ENABLE_FUNCTION_INDIRECTION_BY_DEFAULT : bool = false;
+pub static ENABLE_GUARDED_EXECUTION_BY_DEFAULT : bool = true;
+pub static ENABLE_DEBUG_INFO_BY_DEFAULT : bool = false;
 
 pub struct Compiler {
   pub code_store : CodeStore,
@@ -28,7 +46,27 @@ pub struct Compiler {
   pub gen : UIDGenerator,
   pub cache : StringCache,
   pub c_symbols : CSymbols,
+
+  /// Whether newly loaded modules are compiled with bounds-checked indexing
+  pub bounds_checking : bool,
+
+  /// Whether newly loaded modules are called through patchable function slots
+  pub function_indirection : bool,
+
+  /// Whether crashes and panics in compiled code are returned as errors
+  pub guarded_execution : bool,
+
+  /// Whether newly loaded modules are compiled with debug info, for native debuggers
+  pub debug_info : bool,
+
+  /// Watches source files while hotloading, so that running code can reload them.
+  /// Files are watched as soon as code is loaded from them.
+  pub hotloader : Option<Hotloader>,
+
   intrinsics : UnitId,
+
+  /// Holds the functions registered by the program embedding the compiler
+  host : UnitId,
 }
 
 impl Compiler {
@@ -39,40 +77,152 @@ impl Compiler {
     let intrinsics_id = code_store.create_unit(gen.next(), Some(cache.get("intrinsics")));
     let i_types = intrinsics::get_intrinsics(intrinsics_id, &mut gen, &cache);
     code_store.types.insert(intrinsics_id, i_types);
+    let host_id = code_store.create_unit(gen.next(), Some(cache.get("host")));
+    code_store.types.insert(host_id, TypeInfo::new(host_id));
     let llvm_compiler = LlvmCompiler::new();
     let c_symbols = CSymbols::new_populated();
     let mut c = Box::new(Compiler { 
       code_store, llvm_compiler, gen, cache,
-      c_symbols, intrinsics: intrinsics_id,
+      c_symbols, bounds_checking: ENABLE_BOUNDS_CHECKS_BY_DEFAULT,
+      function_indirection: ENABLE_FUNCTION_INDIRECTION_BY_DEFAULT,
+      guarded_execution: ENABLE_GUARDED_EXECUTION_BY_DEFAULT,
+      debug_info: ENABLE_DEBUG_INFO_BY_DEFAULT,
+      hotloader: None, intrinsics: intrinsics_id, host: host_id,
     });
     let cptr = (&mut *c) as *mut Compiler;
     c.c_symbols.add_symbol("compiler", cptr);
     c
   }
 
+  /// Makes a Rust function callable from code, as if it had been declared with a
+  /// cbind. The signature is derived from the function's type, so it can't drift
+  /// from the Rust definition. Strings are taken as `&SStr`. Registering a name again
+  /// replaces the function, but modules that are already loaded keep calling the old one.
+  ///
+  ///   c.register_fn("add", add as extern "C" fn(i64, i64) -> i64);
+  pub fn register_fn<F : HostFunction>(&mut self, name : &str, f : F) {
+    let name = self.cache.get(name);
+    let type_tag = F::signature(&self.code_store, &self.cache);
+    let host = self.code_store.types.get_mut(&self.host).unwrap();
+    // Loaded units may refer to the old definition, so it is kept. If its type is
+    // the same it is reused, and otherwise it is hidden from units loaded later.
+    let old = host.symbols.values_mut().find(|def| def.name == name && def.is_public);
+    let id = match old {
+      Some(def) if def.type_tag == type_tag => def.id,
+      Some(def) => {
+        def.is_public = false;
+        self.host.new_symbol_id(&mut self.gen)
+      }
+      None => self.host.new_symbol_id(&mut self.gen),
+    };
+    let def = SymbolDefinition {
+      id, unit_id: self.host, name: name.clone(), type_tag,
+      initialiser: SymbolInit::CBind, type_vars: vec![], is_public: true,
+    };
+    host.symbols.insert(id, def);
+    self.c_symbols.local_symbol_table.insert(name, f.address());
+  }
+
   pub fn load_expr_as_module(&mut self, expr : &Expr, name : Option<&str>, imports : &[UnitId])
     -> Result<(UnitId, Val), Error>
+  {
+    self.load_module_from_expr(expr, name, imports, true)
+  }
+
+  /// Compiles an expression as a module. Its top level is only run if `run` is true.
+  fn load_module_from_expr(&mut self, expr : &Expr, name : Option<&str>, imports : &[UnitId], run : bool)
+    -> Result<(UnitId, Val), Error>
   {
     let name = name.map(|s| self.cache.get(s));
     let unit_id = self.code_store.create_unit(self.gen.next(), name);
     self.code_store.exprs.insert(unit_id, expr.clone());
-    self.load_module_from_expr_internal(unit_id, imports.iter().cloned().collect())?;
-    let val = self.code_store.vals.get(&unit_id).unwrap().clone();
+    self.load_module_from_expr_internal(unit_id, imports.iter().cloned().collect(), run)?;
+    let val = self.code_store.vals.get(&unit_id).cloned().unwrap_or(Val::Void);
     Ok((unit_id, val))
   }
 
   pub fn load_module(&mut self, code : &str, name : Option<&str>, imports : &[UnitId])
     -> Result<(UnitId, Val), Error>
   {
+    self.load_module_from_source(code, name, None, imports, true)
+  }
+
+  /// Loads a source file as a module named after its path. A file can only be loaded
+  /// once; use `reload_file` to load it again.
+  pub fn load_file(&mut self, path : &str, imports : &[UnitId])
+    -> Result<(UnitId, Val), Error>
+  {
+    if self.code_store.unit_for_path(Path::new(path)).is_some() {
+      return error(TextLocation::zero(), format!("file '{}' is already loaded", path));
+    }
+    let code = fs::read_to_string(path).map_err(|_|
+      error_raw(TextLocation::zero(), format!("file '{}' not found", path)))?;
+    self.load_module_from_source(&code, Some(path), Some(Path::new(path)), imports, true)
+  }
+
+  /// Compiles source code as a module. Its top level is only run if `run` is true.
+  fn load_module_from_source(
+    &mut self, code : &str, name : Option<&str>, path : Option<&Path>, imports : &[UnitId], run : bool)
+      -> Result<(UnitId, Val), Error>
+  {
+    if let Some(name) = name {
+      if self.code_store.named_unit(name).is_some() {
+        return error(TextLocation::zero(), format!("a module called '{}' is already loaded", name));
+      }
+    }
     let name = name.map(|s| self.cache.get(s));
     let unit_id = self.code_store.create_unit(self.gen.next(), name);
+    if let Some(path) = path {
+      self.register_source_path(unit_id, path);
+    }
     self.code_store.code.insert(unit_id, code.into());
-    self.parse(unit_id)?;
-    self.load_module_from_expr_internal(unit_id, imports.iter().cloned().collect())?;
-    let val = self.code_store.vals.get(&unit_id).unwrap().clone();
+    if let Err(e) = self.parse(unit_id) {
+      self.code_store.remove_unit(unit_id);
+      return Err(e);
+    }
+    self.load_module_from_expr_internal(unit_id, imports.iter().cloned().collect(), run)?;
+    let val = self.code_store.vals.get(&unit_id).cloned().unwrap_or(Val::Void);
     Ok((unit_id, val))
   }
 
+  /// Typechecks code without compiling or running it. Returns the type of its value.
+  pub fn expression_type(&mut self, code : &str, imports : &[UnitId]) -> Result<Type, Error> {
+    let unit_id = self.code_store.create_unit(self.gen.next(), None);
+    self.code_store.code.insert(unit_id, code.into());
+    let mut imports = imports.to_vec();
+    imports.push(self.intrinsics);
+    imports.push(self.host);
+    for &i in imports.iter() {
+      self.code_store.add_import(unit_id, i);
+    }
+    let mut new_units = vec![unit_id];
+    let result =
+      self.parse(unit_id)
+      .and_then(|_| self.structure(unit_id))
+      .and_then(|_| self.typecheck(unit_id, imports, &mut new_units))
+      .map(|_| {
+        let types = self.code_store.types(unit_id);
+        let def =
+          types.symbols.values()
+          .find(|def| def.name.as_ref() == TOP_LEVEL_FUNCTION_NAME).unwrap();
+        def.type_tag.sig().unwrap().return_type.clone()
+      });
+    for uid in new_units {
+      self.code_store.remove_unit(uid);
+    }
+    result
+  }
+
+  /// Records the file that some source code was loaded from
+  pub fn register_source_path(&mut self, source : SourceId, path : &Path) {
+    let path = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+    self.code_store.source_paths.insert(source, path);
+    if let Some(mut hotloader) = self.hotloader.take() {
+      hotloader.update_watched_files(self);
+      self.hotloader = Some(hotloader);
+    }
+  }
+
   pub fn find_all_dependents(&mut self, uid : UnitId) -> Vec<UnitId> {
     let mut uids = HashSet::new();
     let mut queue = VecDeque::new();
@@ -89,6 +239,617 @@ impl Compiler {
     uids.into_iter().collect()
   }
 
+  /// Reloads a unit from new source code, along with every unit that depends on it.
+  /// The replacements start from their own initial state. Returns a map from the ids of
+  /// the old units to the ids of their replacements. If any unit fails to load, the old
+  /// units are kept.
+  pub fn reload_module(&mut self, unit_id : UnitId, code : &str)
+    -> Result<HashMap<UnitId, UnitId>, Error>
+  {
+    self.reload_module_checked(unit_id, code, |_, _| Ok(()))
+  }
+
+  /// Recompiles a unit from new source code in place, so that it keeps its id. Only the
+  /// functions that changed are compiled again, along with the functions that call them.
+  /// The rest of the unit is linked against the code it already has. Its top level isn't
+  /// run again, so its globals keep their values.
+  ///
+  /// Dependents are only typechecked again if they refer to a symbol that was removed or
+  /// whose type changed, or to an overloaded name that gained a definition. Those units are
+  /// replaced along with everything that depends on them, and their state is migrated. The
+  /// other dependents keep their type information and global state. They are only
+  /// code-generated again if they were linked directly against code that moved, i.e. they
+  /// refer to a function that was compiled again and isn't called through a trampoline, or
+  /// to a dependent that was code-generated again. Returns a map from the ids of replaced
+  /// units to their replacements.
+  ///
+  /// If anything fails, the update is rolled back and the old code keeps running. The old
+  /// code is freed once nothing refers to it. Changes to type definitions, to polymorphic
+  /// functions that have been instanced, or that add a global fall back to `replace_module`.
+  ///
+  /// Top levels can't be run again while they are running, so units whose code is running
+  /// can't be updated or replaced.
+  pub fn update_module(&mut self, unit_id : UnitId, code : &str)
+    -> Result<HashMap<UnitId, UnitId>, Error>
+  {
+    self.check_not_running(unit_id)?;
+    let has_poly_instances = self.code_store.types(unit_id).symbols.values().any(|def| {
+      def.is_polymorphic() &&
+        self.code_store.poly_instances.get(&def.id).map(|m| m.len() > 0).unwrap_or(false)
+    });
+    if has_poly_instances {
+      return self.replace_module(unit_id, code).map(|(remapped, _)| remapped);
+    }
+    // Compile the new version in place of the old one
+    let old = SavedUnit::take(&mut self.code_store, unit_id);
+    self.code_store.code.insert(unit_id, code.into());
+    // Namespaced imports are resolved again from the new code
+    let namespaced : HashSet<UnitId> =
+      old.namespaces.iter().flat_map(|ns| ns.values().cloned()).collect();
+    let imports : Vec<UnitId> =
+      old.imports.iter().cloned()
+      .filter(|i| !self.code_store.poly_parents.contains_key(i) && !namespaced.contains(i))
+      .collect();
+    let mut new_units = vec![unit_id];
+    let result =
+      self.parse(unit_id)
+      .and_then(|_| self.structure(unit_id))
+      .and_then(|_| self.typecheck(unit_id, imports, &mut new_units));
+    if let Err(e) = result {
+      println!("{}", self.display_error(&e));
+      self.restore_unit(old, &new_units);
+      return Err(e);
+    }
+    // Find the dependents that have to be typechecked again
+    let changes = UnitChanges::new(&old.types, self.code_store.types(unit_id));
+    let dependents : Vec<UnitId> =
+      self.find_all_dependents(unit_id).into_iter().filter(|&uid| uid != unit_id).collect();
+    let mut full_reload = changes.type_defs_changed;
+    let mut invalidated = vec![];
+    for &uid in dependents.iter() {
+      let imports_unit = self.code_store.get_imports(uid).any(|&i| i == unit_id);
+      let mapping = self.code_store.type_mappings.get(&uid);
+      if imports_unit && mapping.map(|m| changes.invalidates(&self.code_store, m)).unwrap_or(false) {
+        // Polymorphic instances can't be typechecked again without their callers
+        if self.code_store.poly_parents.contains_key(&uid) {
+          full_reload = true;
+        }
+        invalidated.push(uid);
+      }
+    }
+    let old_codegen_id = *self.code_store.codegen_mapping.get(&unit_id).unwrap();
+    let compiled = match (full_reload, self.compiled_symbols(&old, &changes, old_codegen_id)) {
+      (false, Some(compiled)) => compiled,
+      _ => {
+        self.restore_unit(old, &new_units);
+        return self.replace_module(unit_id, code).map(|(remapped, _)| remapped);
+      }
+    };
+    let mut update = UpdateLog {
+      old_codegen_ids: vec![(unit_id, old_codegen_id)],
+      references: vec![],
+      patches: vec![],
+    };
+    let result =
+      self.apply_update(&old, &changes, &compiled, dependents, invalidated, &new_units, &mut update);
+    match result {
+      Ok(remapped) => {
+        for (_, codegen_id) in update.old_codegen_ids {
+          self.code_store.retire_code(codegen_id);
+        }
+        self.code_store.free_unused_code();
+        Ok(remapped)
+      }
+      Err(e) => {
+        println!("{}", self.display_error(&e));
+        self.roll_back_update(old, &new_units, update);
+        Err(e)
+      }
+    }
+  }
+
+  /// Code-generates the changed functions of an updated unit, and brings its dependents
+  /// up to date. What was changed is recorded in `update`, so that it can be rolled back.
+  fn apply_update(
+    &mut self, old : &SavedUnit, changes : &UnitChanges, compiled : &CompiledSymbols,
+    dependents : Vec<UnitId>, invalidated : Vec<UnitId>, new_units : &[UnitId],
+    update : &mut UpdateLog)
+      -> Result<HashMap<UnitId, UnitId>, Error>
+  {
+    let unit_id = changes.unit_id;
+    let mut patches = self.codegen(new_units, Some(compiled))?;
+    self.carry_function_ranges(old, changes, compiled);
+    let mut reload = HashSet::new();
+    for uid in invalidated {
+      reload.extend(self.find_all_dependents(uid));
+    }
+    for &uid in reload.iter() {
+      self.check_not_running(uid)?;
+    }
+    // Find the dependents linked directly against code that moved, in import order,
+    // so that moving one of them is seen by the units that depend on it
+    let mut moved = HashSet::new();
+    moved.insert(unit_id);
+    let mut relink = vec![];
+    let candidates = dependents.into_iter().filter(|uid| !reload.contains(uid)).collect();
+    for uid in self.load_order(candidates) {
+      if self.links_directly(uid, &moved, changes, compiled) {
+        moved.insert(uid);
+        relink.push(uid);
+      }
+    }
+    // Units compiled together have to be code-generated again together
+    let groups : HashSet<_> =
+      relink.iter().map(|uid| self.code_store.codegen_mapping[uid]).collect();
+    let grouped : Vec<UnitId> =
+      self.code_store.codegen_mapping.iter()
+      .filter(|(uid, id)| groups.contains(*id) && !moved.contains(*uid) && !reload.contains(*uid))
+      .map(|(uid, _)| *uid).collect();
+    relink.extend(grouped);
+    // Code-generate them again, carrying over their global state
+    let mut globals = vec![];
+    for &uid in relink.iter() {
+      update.old_codegen_ids.push((uid, self.code_store.codegen_mapping[&uid]));
+      if let Some(m) = self.code_store.type_mappings.get_mut(&uid) {
+        update.references.push((uid, m.symbol_references.clone()));
+        changes.remap_references(m);
+      }
+      globals.extend(self.save_globals(uid));
+    }
+    patches.extend(self.codegen(relink.as_slice(), None)?);
+    for (uid, name, bytes) in globals {
+      let lu = self.code_store.llvm_unit(uid);
+      if let Some(address) = unsafe { lu.ee.get_global_address(&name) } {
+        unsafe { ptr::copy_nonoverlapping(bytes.as_ptr(), address as *mut u8, bytes.len()) };
+      }
+    }
+    // Everything compiled, so existing callers can be pointed at the new code
+    update.patches = self.patch_function_slots(patches);
+    // Replace the dependents that have to be typechecked again
+    let reload : Vec<UnitId> = reload.into_iter().collect();
+    let reloads = self.unit_reloads(&reload, None);
+    let remapped = self.compile_replacements(&reloads)?;
+    if let Err(e) = self.run_replacements(&reloads, &remapped) {
+      self.discard_replacements(&remapped);
+      return Err(e);
+    }
+    self.swap_replacements(&reload, reloads, &remapped, true);
+    Ok(remapped)
+  }
+
+  /// Whether a unit refers to a global, or to a function without a trampoline, in any
+  /// of a set of units. Such references are linked directly against their code. Symbols
+  /// of the updated unit that kept their code are left out.
+  fn links_directly(
+    &self, unit_id : UnitId, units : &HashSet<UnitId>, changes : &UnitChanges,
+    compiled : &CompiledSymbols)
+      -> bool
+  {
+    let cs = &self.code_store;
+    let mapping = match cs.type_mappings.get(&unit_id) {
+      Some(m) => m,
+      None => return false,
+    };
+    let references = mapping.symbol_references.values().any(|&sid| {
+      if !units.contains(&sid.uid) {
+        return false;
+      }
+      // References to the updated unit still use the ids of its old version
+      let sid = if sid.uid == changes.unit_id {
+        match changes.symbols.get(&sid) {
+          Some(SymbolChange::Retained(id)) if compiled.names.contains_key(id) => return false,
+          Some(SymbolChange::Retained(id)) => *id,
+          _ => return true,
+        }
+      }
+      else {
+        sid
+      };
+      let def = cs.symbol_def(sid);
+      match def.initialiser {
+        SymbolInit::Function(_) => cs.indirection.trampoline(cs, def).is_none(),
+        SymbolInit::Expression(_) => true,
+        _ => false,
+      }
+    });
+    // Polymorphic instances are never called through trampolines
+    references || mapping.polymorphic_references.iter().any(|(sid, t)| {
+      cs.poly_instance(*sid, t).map(|id| units.contains(&id.uid)).unwrap_or(false)
+    })
+  }
+
+  /// Finds the symbols of an updated unit that can keep the code they were compiled into.
+  /// Globals keep their values, so returns None if the update adds one. Functions keep
+  /// their code if their definitions are unchanged and refer to the same symbols. Calls
+  /// within a unit are linked directly, so functions that refer to a function that is
+  /// compiled again are compiled again too. The top level is always compiled again.
+  fn compiled_symbols(&self, old : &SavedUnit, changes : &UnitChanges, codegen_id : CodegenId)
+    -> Option<CompiledSymbols>
+  {
+    let unit_id = changes.unit_id;
+    let cs = &self.code_store;
+    let mut names = HashMap::new();
+    let mut references = HashMap::new();
+    for (old_id, change) in changes.symbols.iter() {
+      let id = match change {
+        SymbolChange::Retained(id) => *id,
+        SymbolChange::Invalidated => continue,
+      };
+      let def = cs.symbol_def(id);
+      match (&old.types.symbols[old_id].initialiser, &def.initialiser) {
+        (SymbolInit::Expression(_), SymbolInit::Expression(_)) => {
+          names.insert(id, def.name.clone());
+        }
+        (SymbolInit::Function(init), SymbolInit::Function(_)) => {
+          if def.is_polymorphic() || def.name.as_ref() == TOP_LEVEL_FUNCTION_NAME {
+            continue;
+          }
+          if let Some(refs) = self.unchanged_references(old, changes, *old_id, id) {
+            names.insert(id, init.name_for_codegen.clone());
+            references.insert(id, refs);
+          }
+        }
+        _ => (),
+      }
+    }
+    let adds_global = cs.types(unit_id).symbols.values().any(|def| {
+      if let SymbolInit::Expression(_) = def.initialiser { !names.contains_key(&def.id) } else { false }
+    });
+    if adds_global {
+      return None;
+    }
+    loop {
+      let changed : Vec<SymbolId> =
+        references.iter()
+        .filter(|(_, refs)| refs.iter().any(|sid| {
+          if sid.uid != unit_id || names.contains_key(sid) {
+            return false;
+          }
+          let def = cs.symbol_def(*sid);
+          if let SymbolInit::Function(_) = def.initialiser { !def.is_polymorphic() } else { false }
+        }))
+        .map(|(id, _)| *id).collect();
+      if changed.is_empty() {
+        break;
+      }
+      for id in changed {
+        names.remove(&id);
+        references.remove(&id);
+      }
+    }
+    Some(CompiledSymbols { codegen_id, names })
+  }
+
+  /// The symbols that a function of an updated unit refers to, or None if its definition
+  /// changed, or if it refers to different symbols than it did before
+  fn unchanged_references(
+    &self, old : &SavedUnit, changes : &UnitChanges, old_id : SymbolId, id : SymbolId)
+      -> Option<Vec<SymbolId>>
+  {
+    let unit_id = changes.unit_id;
+    let cs = &self.code_store;
+    let (old_nodes, old_mapping, old_code) =
+      (old.nodes.as_ref()?, old.mapping.as_ref()?, old.code.as_ref()?);
+    let (nodes, mapping, code) =
+      (cs.nodes(unit_id), cs.type_mapping(unit_id), cs.code.get(&unit_id)?);
+    let old_loc = old_nodes.node(*old_mapping.symbol_def_nodes.get(&old_id)?).loc;
+    let loc = nodes.node(*mapping.symbol_def_nodes.get(&id)?).loc;
+    if old_loc.slice_text(old_code) != loc.slice_text(code) {
+      return None;
+    }
+    let mut old_references = HashMap::new();
+    for (sid, count) in references_within(old_nodes, old_mapping, old_loc) {
+      // Symbols of the updated unit have new ids
+      let sid = if sid.uid == unit_id {
+        match changes.symbols.get(&sid) {
+          Some(SymbolChange::Retained(id)) => *id,
+          _ => return None,
+        }
+      }
+      else {
+        sid
+      };
+      old_references.insert(sid, count);
+    }
+    let references = references_within(nodes, mapping, loc);
+    if references != old_references {
+      return None;
+    }
+    Some(references.into_iter().map(|(sid, _)| sid).collect())
+  }
+
+  /// Gives the functions of an updated unit that kept their code the machine code they
+  /// had before, so that faults in them can still be located. Their call sites are moved
+  /// along with their definitions.
+  fn carry_function_ranges(
+    &mut self, old : &SavedUnit, changes : &UnitChanges, compiled : &CompiledSymbols)
+  {
+    let unit_id = changes.unit_id;
+    let cs = &self.code_store;
+    let (old_nodes, old_mapping) = match (&old.nodes, &old.mapping) {
+      (Some(nodes), Some(mapping)) => (nodes, mapping),
+      _ => return,
+    };
+    let (nodes, mapping) = (cs.nodes(unit_id), cs.type_mapping(unit_id));
+    let mut ranges = vec![];
+    for r in cs.llvm_units.get(&compiled.codegen_id).unwrap().function_ranges.iter() {
+      let id = match changes.symbols.get(&r.symbol) {
+        Some(SymbolChange::Retained(id)) if compiled.names.contains_key(id) => *id,
+        _ => continue,
+      };
+      let from = old_nodes.node(old_mapping.symbol_def_nodes[&r.symbol]).loc;
+      let to = nodes.node(mapping.symbol_def_nodes[&id]).loc;
+      let call_sites =
+        r.call_sites.iter().map(|&(address, loc)| (address, moved_location(loc, from, to))).collect();
+      ranges.push(FunctionRange { start: r.start, end: r.end, symbol: id, call_sites });
+    }
+    let codegen_id = cs.codegen_mapping[&unit_id];
+    self.code_store.llvm_units.get_mut(&codegen_id).unwrap().function_ranges.extend(ranges);
+  }
+
+  fn check_not_running(&self, unit_id : UnitId) -> Result<(), Error> {
+    if self.code_store.is_running(unit_id) {
+      let loc = self.code_store.nodes(unit_id).root().loc;
+      let name = self.code_store.name(unit_id);
+      return error(loc, format!("can't reload module '{}' while its code is running", name));
+    }
+    Ok(())
+  }
+
+  /// Recompiles the module loaded from a file with the file's current contents, along
+  /// with whatever depends on what changed. Returns false if no module was loaded from it.
+  pub fn reload_file(&mut self, path : &Path) -> Result<bool, Error> {
+    let unit_id = match self.code_store.unit_for_path(path) {
+      Some(uid) => uid,
+      None => return Ok(false),
+    };
+    let code = fs::read_to_string(path).map_err(|_|
+      error_raw(TextLocation::zero(), format!("failed to read '{}'", path.display())))?;
+    self.update_module(unit_id, &code)?;
+    Ok(true)
+  }
+
+  /// Undoes a failed update, so that the old code runs again
+  fn roll_back_update(&mut self, old : SavedUnit, new_units : &[UnitId], update : UpdateLog) {
+    self.patch_function_slots(update.patches);
+    for (uid, codegen_id) in update.old_codegen_ids {
+      if let Some(new_id) = self.code_store.codegen_mapping.insert(uid, codegen_id) {
+        if new_id != codegen_id {
+          self.code_store.retire_code(new_id);
+        }
+      }
+    }
+    for (uid, references) in update.references {
+      if let Some(m) = self.code_store.type_mappings.get_mut(&uid) {
+        m.symbol_references = references;
+      }
+    }
+    self.restore_unit(old, new_units);
+  }
+
+  /// Puts a unit back the way it was before a failed update
+  fn restore_unit(&mut self, old : SavedUnit, new_units : &[UnitId]) {
+    for &uid in new_units.iter().filter(|&&uid| uid != old.unit_id) {
+      self.code_store.remove_unit(uid);
+    }
+    old.restore(&mut self.code_store);
+  }
+
+  /// Copies the value of every global in a unit
+  fn save_globals(&self, unit_id : UnitId) -> Vec<(UnitId, RefStr, Vec<u8>)> {
+    let lu = self.code_store.llvm_unit(unit_id);
+    let mut globals = vec![];
+    for def in self.code_store.types(unit_id).symbols.values() {
+      if let SymbolInit::Expression(_) = def.initialiser {
+        if let Some(address) = unsafe { lu.ee.get_global_address(&def.name) } {
+          let size = type_layout(&self.code_store, &def.type_tag).size;
+          let bytes = unsafe { slice::from_raw_parts(address as *const u8, size) }.to_vec();
+          globals.push((unit_id, def.name.clone(), bytes));
+        }
+      }
+    }
+    globals
+  }
+
+  /// Replaces a unit with a version compiled from new source code, along with every unit
+  /// that depends on it. The replacements are all compiled before anything is swapped, so
+  /// if any of them fails to compile, the old versions are left loaded and running.
+  ///
+  /// On success the old units are unloaded, after their global state has been migrated to
+  /// the replacements. Returns a map from the ids of the old units to the ids of their
+  /// replacements, along with the reports from migrating their state.
+  pub fn replace_module(&mut self, unit_id : UnitId, code : &str)
+    -> Result<(HashMap<UnitId, UnitId>, Vec<MigrationReport>), Error>
+  {
+    self.replace_units(unit_id, UnitSource::Code(code.into()), true, |_, _| Ok(()))
+  }
+
+  /// Like `replace_module`, but the replacement is an expression
+  pub fn replace_module_with_expr(&mut self, unit_id : UnitId, expr : &Expr)
+    -> Result<(HashMap<UnitId, UnitId>, Vec<MigrationReport>), Error>
+  {
+    self.replace_units(unit_id, UnitSource::Expr(expr.clone()), true, |_, _| Ok(()))
+  }
+
+  /// Like `replace_module`, but the replacements start from their own initial state
+  /// instead of the old units' state. `check` is called with the compiled replacements
+  /// before any of their top levels run, and if it fails the old units are kept.
+  pub fn reload_module_checked(
+    &mut self, unit_id : UnitId, code : &str,
+    check : impl FnOnce(&Compiler, &HashMap<UnitId, UnitId>) -> Result<(), Error>)
+      -> Result<HashMap<UnitId, UnitId>, Error>
+  {
+    let (remapped, _) = self.replace_units(unit_id, UnitSource::Code(code.into()), false, check)?;
+    Ok(remapped)
+  }
+
+  fn replace_units(
+    &mut self, unit_id : UnitId, source : UnitSource, migrate : bool,
+    check : impl FnOnce(&Compiler, &HashMap<UnitId, UnitId>) -> Result<(), Error>)
+      -> Result<(HashMap<UnitId, UnitId>, Vec<MigrationReport>), Error>
+  {
+    let dependents = self.find_all_dependents(unit_id);
+    let reloads = self.unit_reloads(&dependents, Some((unit_id, source)));
+    let remapped = self.compile_replacements(&reloads)?;
+    // Top levels only run once everything has compiled
+    let result =
+      check(self, &remapped)
+      .and_then(|_| self.run_replacements(&reloads, &remapped));
+    if let Err(e) = result {
+      self.discard_replacements(&remapped);
+      return Err(e);
+    }
+    let reports = self.swap_replacements(&dependents, reloads, &remapped, migrate);
+    Ok((remapped, reports))
+  }
+
+  /// Compiles replacements for a set of units alongside the old ones, without running
+  /// their top levels. Returns a map from the ids of the old units to their replacements.
+  /// If any of them fails to compile, those already compiled are unloaded again.
+  fn compile_replacements(&mut self, reloads : &[UnitReload])
+    -> Result<HashMap<UnitId, UnitId>, Error>
+  {
+    let mut remapped = HashMap::new();
+    for r in reloads.iter() {
+      let imports : Vec<UnitId> =
+        r.imports.iter().map(|i| *remapped.get(i).unwrap_or(i)).collect();
+      // Named so that modules importing this one by name find the replacement.
+      // They get their real names once swapped in.
+      let temporary_name = format!("@replacement[{}]", r.name);
+      match self.load_unit_source(&r.source, Some(&temporary_name), None, &imports, false) {
+        Ok(new_uid) => { remapped.insert(r.uid, new_uid); }
+        Err(e) => {
+          self.discard_replacements(&remapped);
+          return Err(e);
+        }
+      }
+    }
+    Ok(remapped)
+  }
+
+  /// Runs the top levels of compiled replacements, in load order
+  fn run_replacements(&mut self, reloads : &[UnitReload], remapped : &HashMap<UnitId, UnitId>)
+    -> Result<(), Error>
+  {
+    for r in reloads.iter() {
+      let new_uid = remapped[&r.uid];
+      self.initialise(new_uid)?;
+    }
+    Ok(())
+  }
+
+  /// Unloads replacements that won't be swapped in, along with anything generated for them
+  fn discard_replacements(&mut self, remapped : &HashMap<UnitId, UnitId>) {
+    let mut new_units = HashSet::new();
+    for &new_uid in remapped.values() {
+      new_units.extend(self.find_all_dependents(new_uid));
+    }
+    for uid in new_units {
+      self.code_store.remove_unit(uid);
+    }
+  }
+
+  /// Migrates state from the old units to their replacements if `migrate` is set,
+  /// unloads the old units and gives the replacements their names.
+  fn swap_replacements(
+    &mut self, old_units : &[UnitId], reloads : Vec<UnitReload>,
+    remapped : &HashMap<UnitId, UnitId>, migrate : bool)
+      -> Vec<MigrationReport>
+  {
+    let mut reports = vec![];
+    if migrate {
+      for r in reloads.iter() {
+        reports.push(self.migrate_state(r.uid, remapped[&r.uid]));
+      }
+    }
+    for &uid in old_units {
+      self.code_store.remove_unit(uid);
+    }
+    for r in reloads {
+      let new_uid = remapped[&r.uid];
+      let temporary_name = self.code_store.name(new_uid);
+      self.code_store.indirection.rename_unit(&temporary_name, &r.name);
+      self.code_store.names.insert(new_uid, r.name);
+      if let Some(path) = r.path {
+        self.register_source_path(new_uid, &path);
+      }
+    }
+    reports
+  }
+
+  /// Captures what is needed to load each of a set of units again, in an order that
+  /// respects their imports. The source of one of them can be replaced.
+  fn unit_reloads(&self, units : &[UnitId], changed : Option<(UnitId, UnitSource)>)
+    -> Vec<UnitReload>
+  {
+    // Polymorphic instances are regenerated when the units that use them are reloaded
+    let load_order = self.load_order(units.iter().cloned()
+      .filter(|uid| !self.code_store.poly_parents.contains_key(uid)).collect());
+    let (changed_uid, mut changed_source) = match changed {
+      Some((uid, source)) => (Some(uid), Some(source)),
+      None => (None, None),
+    };
+    let mut reloads = vec![];
+    for uid in load_order {
+      let name = self.code_store.name(uid);
+      let path = self.code_store.source_path(uid).cloned();
+      let imports : Vec<UnitId> =
+        self.code_store.get_imports(uid).cloned()
+        .filter(|&i| i != self.intrinsics && i != self.host)
+        .filter(|&i| !self.code_store.poly_parents.contains_key(&i))
+        .collect();
+      let source = {
+        if Some(uid) == changed_uid { changed_source.take().unwrap() }
+        else if let Some(code) = self.code_store.code.get(&uid) { UnitSource::Code(code.clone()) }
+        else { UnitSource::Expr(self.code_store.exprs.get(&uid).unwrap().clone()) }
+      };
+      reloads.push(UnitReload { uid, name, path, imports, source });
+    }
+    reloads
+  }
+
+  fn load_unit_source(
+    &mut self, source : &UnitSource, name : Option<&str>, path : Option<&Path>,
+    imports : &[UnitId], run : bool)
+      -> Result<UnitId, Error>
+  {
+    let (new_uid, _) = match source {
+      UnitSource::Code(code) => self.load_module_from_source(code, name, path, imports, run)?,
+      UnitSource::Expr(expr) => {
+        let (new_uid, val) = self.load_module_from_expr(expr, name, imports, run)?;
+        if let Some(path) = path {
+          self.register_source_path(new_uid, path);
+        }
+        (new_uid, val)
+      }
+    };
+    Ok(new_uid)
+  }
+
+  /// Sorts units so that each one comes after any of the others that it imports
+  fn load_order(&self, units : Vec<UnitId>) -> Vec<UnitId> {
+    let mut remaining : HashSet<UnitId> = units.into_iter().collect();
+    let mut ordered = vec![];
+    while remaining.len() > 0 {
+      let next = *remaining.iter()
+        .filter(|&&uid| self.code_store.get_imports(uid).all(|i| !remaining.contains(i)))
+        .min().expect("import graph contained cycles!");
+      remaining.remove(&next);
+      ordered.push(next);
+    }
+    ordered
+  }
+
+  /// Carries the global state of a unit over to a newly loaded version of it.
+  /// The old unit's region is merged into the new unit's region.
+  pub fn migrate_state(&mut self, old_unit : UnitId, new_unit : UnitId) -> MigrationReport {
+    let report = migrate::migrate_globals(&self.code_store, old_unit, new_unit);
+    if let Some(old_region) = self.code_store.regions.remove(&old_unit) {
+      self.code_store.unit_region(new_unit).merge(*old_region);
+    }
+    report
+  }
+
   fn parse(&mut self, unit_id : UnitId) -> Result<(), Error> {
     let code = self.code_store.code.get(&unit_id).unwrap();
     let tokens =
@@ -99,25 +860,32 @@ impl Compiler {
     Ok(())
   }
 
-  fn load_module_from_expr_internal(&mut self, unit_id : UnitId, imports : Vec<UnitId>)
+  fn load_module_from_expr_internal(&mut self, unit_id : UnitId, imports : Vec<UnitId>, run : bool)
     -> Result<(), Error>
   {
-    fn inner(c : &mut Compiler, unit_id : UnitId, mut imports : Vec<UnitId>, new_units : &mut Vec<UnitId>) -> Result<(), Error> {
+    fn inner(c : &mut Compiler, unit_id : UnitId, mut imports : Vec<UnitId>, run : bool, new_units : &mut Vec<UnitId>) -> Result<(), Error> {
       imports.push(c.intrinsics);
+      imports.push(c.host);
       // Remove duplicates
       imports.sort_unstable();
       imports.dedup();
       for &i in imports.iter() {
         c.code_store.add_import(unit_id, i);
       }
+      if c.bounds_checking {
+        c.code_store.bounds_checked.insert(unit_id);
+      }
       c.structure(unit_id)?;
       c.typecheck(unit_id, imports, new_units)?;
-      c.codegen(new_units.as_slice())?;
-      c.initialise(unit_id)?;
+      let patches = c.codegen(new_units.as_slice(), None)?;
+      c.patch_function_slots(patches);
+      if run {
+        c.initialise(unit_id)?;
+      }
       Ok(())
     }
     let mut new_units = vec![unit_id];
-    match inner(self, unit_id, imports, &mut new_units) {
+    match inner(self, unit_id, imports, run, &mut new_units) {
       Ok(()) => Ok(()),
       Err(e) => {
         println!("{}", self.display_error(&e));
@@ -137,13 +905,76 @@ impl Compiler {
     Ok(())
   }
 
-  fn typecheck(&mut self, unit_id : UnitId, imports : Vec<UnitId>, new_units : &mut Vec<UnitId>) -> Result<(), Error> {
+  fn typecheck(&mut self, unit_id : UnitId, mut imports : Vec<UnitId>, new_units : &mut Vec<UnitId>) -> Result<(), Error> {
+    let (flat_imports, namespaced) = self.resolve_imports(unit_id, &imports)?;
+    imports.extend(flat_imports);
+    imports.retain(|i| !namespaced.contains(i));
+    imports.sort_unstable();
+    imports.dedup();
     types::typecheck_module(
       unit_id, &mut self.code_store, &self.cache, &mut self.gen, imports)?;
     self.typecheck_new_polymorphic_instances(unit_id, new_units)?;
     Ok(())
   }
 
+  /// Finds a loaded unit by name. A unit that is being replaced is found in place of
+  /// the old version.
+  fn imported_unit(&self, name : &str) -> Option<UnitId> {
+    self.code_store.named_unit(&format!("@replacement[{}]", name))
+      .or_else(|| self.code_store.named_unit(name))
+  }
+
+  /// Finds or loads the modules named by the unit's import statements, and records
+  /// them as imports. Returns the units imported without a namespace, and those
+  /// imported with one, which must not also be imported without it.
+  fn resolve_imports(&mut self, unit_id : UnitId, imports : &[UnitId])
+    -> Result<(Vec<UnitId>, HashSet<UnitId>), Error>
+  {
+    let mut import_nodes : Vec<_> =
+      self.code_store.nodes.get(&unit_id).unwrap().nodes.values()
+      .flat_map(|n| match &n.content {
+        Content::Import{ path, namespace } => Some((n.loc, path.clone(), namespace.clone())),
+        _ => None,
+      })
+      .collect();
+    import_nodes.sort_by_key(|(loc, _, _)| loc.start);
+    let (mut flat, mut namespaced) = (vec![], HashSet::new());
+    let mut namespaces = HashMap::new();
+    for (loc, path, namespace) in import_nodes {
+      // Files are found relative to the file that imports them, if there is one
+      let file = match self.code_store.source_path(unit_id).and_then(|p| p.parent()) {
+        Some(dir) => dir.join(path.as_ref()),
+        None => PathBuf::from(path.as_ref()),
+      };
+      let file_name = file.to_string_lossy().into_owned();
+      let existing =
+        self.imported_unit(&path)
+        .or_else(|| self.imported_unit(&file_name));
+      let uid = match existing {
+        Some(uid) => uid,
+        None if file.is_file() =>
+          self.load_file(&file_name, imports).map_err(|e| error_raw(loc,
+            ErrorContent::InnerErrors(format!("failed to import module '{}'", path), vec![e])))?.0,
+        None => return error(loc, format!("module '{}' was not found", path)),
+      };
+      if uid == unit_id {
+        return error(loc, format!("module '{}' imports itself", path));
+      }
+      self.code_store.add_import(unit_id, uid);
+      match namespace {
+        Some(ns) => {
+          namespaces.insert(ns, uid);
+          namespaced.insert(uid);
+        }
+        None => flat.push(uid),
+      }
+    }
+    if namespaces.len() > 0 {
+      self.code_store.namespaces.insert(unit_id, namespaces);
+    }
+    Ok((flat, namespaced))
+  }
+
   fn typecheck_new_polymorphic_instances(&mut self, calling_unit : UnitId, new_units : &mut Vec<UnitId>) -> Result<(), Error> {
     // Typecheck any new polymorphic function instances
     let mut search_queue = VecDeque::new();
@@ -157,10 +988,13 @@ impl Compiler {
           self.code_store.add_import(psid, id.uid);
         }
         else {
-          // Create a unique name for the new unit
+          // Create a unique name for the new unit. Unit names must be unique, and the
+          // replacements compiled by `replace_module` live alongside the units they
+          // replace, so the name of the unit that defines the symbol is included.
           let poly_unit_name = {
             let name = self.code_store.symbol_def(poly_symbol_id).name.as_ref();
-            self.cache.get(format!("@poly[{}][{}]", name, instance_type))
+            let unit_name = self.code_store.name(poly_symbol_id.uid);
+            self.cache.get(format!("@poly[{}.{}][{}]", unit_name, name, instance_type))
           };
           // Create the new unit and register it
           let instance_unit_id = self.code_store.create_unit(self.gen.next(), Some(poly_unit_name));
@@ -196,7 +1030,12 @@ impl Compiler {
     Ok(())
   }
 
-  fn codegen(&mut self, new_units : &[UnitId]) -> Result<(), Error> {
+  /// Code-generates and links a set of newly typechecked units. Symbols that are already
+  /// `compiled` are linked against their existing code. Returns the patches that point
+  /// the units' existing function slots at the new code.
+  fn codegen(&mut self, new_units : &[UnitId], compiled : Option<&CompiledSymbols>)
+    -> Result<SlotPatches, Error>
+  {
     if DEBUG_PRINTING_DEPENDENCY_GRAPH {
       println!("units {{");
       for (i, u) in new_units.iter().cloned().enumerate() {
@@ -240,6 +1079,7 @@ impl Compiler {
     }
     // Codegen the strongly-connected subgraphs together
     let mut unit_group = vec![];
+    let mut patches = vec![];
     for subgraph_index in ordering {
       let g = &strongly_connected_components[subgraph_index];
       // build unit group
@@ -249,14 +1089,90 @@ impl Compiler {
       }
       // codegen group
       let codegen_id = self.gen.next().into();
-      let lu = self.llvm_compiler.compile_unit_group(codegen_id, unit_group.as_slice(), &self.code_store)?;
+      let lu = self.llvm_compiler.compile_unit_group(
+        codegen_id, unit_group.as_slice(), &self.code_store, compiled, self.debug_info)?;
       for &unit_id in unit_group.iter() {
         self.code_store.codegen_mapping.insert(unit_id, codegen_id);
       }
       self.code_store.llvm_units.insert(codegen_id, lu);
-      llvm_compile::link_unit(codegen_id, &self.code_store, &self.c_symbols);
+      let links_to = llvm_compile::link_unit(codegen_id, &self.code_store, &self.c_symbols);
+      let lu = self.code_store.llvm_units.get(&codegen_id).unwrap();
+      let ranges = llvm_compile::function_ranges(lu, unit_group.as_slice(), &self.code_store);
+      let lu = self.code_store.llvm_units.get_mut(&codegen_id).unwrap();
+      lu.function_ranges = ranges;
+      lu.links_to = links_to;
+      for &unit_id in unit_group.iter() {
+        self.code_store.pin_escaping_functions(unit_id);
+      }
+      patches.extend(self.update_function_slots(unit_group.as_slice()));
     }
-    Ok(())
+    Ok(patches)
+  }
+
+  /// Points function slots at new code. Returns the addresses they pointed at before,
+  /// so that the patches can be undone.
+  fn patch_function_slots(&self, patches : SlotPatches) -> SlotPatches {
+    patches.into_iter()
+      .map(|(key, address)| {
+        let old_address = self.code_store.indirection.patch(&key, address);
+        (key, old_address)
+      })
+      .collect()
+  }
+
+  /// Creates function slots for newly compiled units, if function indirection is enabled.
+  /// New slots point at the new code straight away. Returns the patches that would point
+  /// the existing slots at the new code, which are applied once the new code is committed.
+  /// Existing slots of functions that no longer exist are retired by the patches.
+  fn update_function_slots(&mut self, units : &[UnitId]) -> SlotPatches {
+    let mut new_slots = vec![];
+    let mut new_keys = HashSet::new();
+    let mut new_patches = vec![];
+    let mut patches = vec![];
+    for &uid in units {
+      let lu = self.code_store.llvm_unit(uid);
+      let mut unit_keys = HashSet::new();
+      for sid in IndirectionTable::slot_functions(&self.code_store, uid) {
+        let def = self.code_store.symbol_def(sid);
+        let key = IndirectionTable::slot_key(&self.code_store, def);
+        let has_slot =
+          new_keys.contains(&key) || self.code_store.indirection.trampoline(&self.code_store, def).is_some();
+        if !has_slot {
+          if !self.function_indirection {
+            continue;
+          }
+          let n = self.gen.next();
+          new_slots.push((key.clone(), format!("trampoline.{}", n), format!("slot.{}", n), sid));
+          new_keys.insert(key.clone());
+        }
+        let address = unsafe { lu.ee.get_function_address(def.codegen_name().unwrap()) }
+          .expect("function pointer was null") as usize;
+        unit_keys.insert(key.clone());
+        if new_keys.contains(&key) {
+          new_patches.push((key, address));
+        }
+        else {
+          patches.push((key, address));
+        }
+      }
+      // Functions that were removed, or whose signature changed, keep their old slots
+      let unit_name = self.code_store.name(uid);
+      for key in self.code_store.indirection.unit_slots(&unit_name) {
+        if !unit_keys.contains(&key) {
+          patches.push((key, indirection::removed_function_address()));
+        }
+      }
+    }
+    if new_slots.len() > 0 {
+      let trampolines : Vec<_> =
+        new_slots.iter().map(|(_, t, s, sid)| (*sid, t.clone(), s.clone())).collect();
+      let codegen_id = self.gen.next().into();
+      let lu = self.llvm_compiler.compile_trampolines(codegen_id, &trampolines, &self.code_store);
+      let slots = new_slots.into_iter().map(|(key, t, s, _)| (key, t, s)).collect();
+      self.code_store.indirection.add_slots(lu, slots);
+    }
+    self.patch_function_slots(new_patches);
+    patches
   }
 
   fn initialise(&mut self, unit_id : UnitId) -> Result<(), Error> {
@@ -274,7 +1190,12 @@ impl Compiler {
     let f = def.codegen_name().unwrap();
     let sig = if let Some(sig) = def.type_tag.sig() {sig} else {panic!()};
     let lu = self.code_store.llvm_unit(unit_id);
-    let value = match &sig.return_type.content {
+    let t = sig.return_type;
+    if !t.is_concrete() {
+      let loc = self.code_store.nodes(unit_id).root().loc;
+      return error(loc, format!("can't return value of type {} from a top-level function", t));
+    }
+    self.call_guarded(unit_id, &def.name, || match &t.content {
       Prim(Bool) => Val::Bool(execute_function(f, lu)),
       Prim(F64) => Val::F64(execute_function(f, lu)),
       Prim(F32) => Val::F32(execute_function(f, lu)),
@@ -288,20 +1209,186 @@ impl Compiler {
         execute_function::<()>(f, lu);
         Val::Void
       }
-      t => {
-        let loc = self.code_store.nodes(unit_id).root().loc;
-        return error(loc, format!("can't return value of type {:?} from a top-level function", t));
+      _ => {
+        // The result is written to a buffer, which is aligned to 8 bytes like any value
+        let size = type_layout(&self.code_store, t).size;
+        let mut buffer = vec![0u64; (size + 7) / 8];
+        let out = buffer.as_mut_ptr() as *mut u8;
+        unsafe {
+          let writer =
+            lu.ee.get_function::<unsafe extern "C" fn(*mut u8)>(&result_writer_name(f))
+            .expect("could not find function in JIT-compiled module");
+          writer.call(out);
+          inspect::read_value(&self.code_store, t, out)
+        }
       }
+    })
+  }
+
+  /// Calls into compiled code from a unit. If guarded execution is enabled, a crash
+  /// or panic is returned as an error, and the compiler can still be used. The error
+  /// names the innermost compiled function that was running, and has a stack trace.
+  pub fn call_guarded<T, F : FnOnce() -> T>(&self, unit_id : UnitId, function : &RefStr, f : F)
+    -> Result<T, Error>
+  {
+    // Code that is retired during the call can't be freed until it returns
+    self.code_store.running_units.borrow_mut().push(unit_id);
+    let result = if self.guarded_execution { guard::guarded(f) } else { Ok(f()) };
+    self.code_store.running_units.borrow_mut().pop();
+    result.map_err(|fault| {
+      // Every compiled frame was stopped at a call, except for the one that crashed
+      let crashed = if let guard::FaultCause::Signal(_) = fault.cause { true } else { false };
+      let mut trace : Vec<StackFrame> = vec![];
+      for &address in fault.frames.iter() {
+        let at_call = !(crashed && trace.is_empty());
+        trace.extend(self.stack_frame(address, at_call));
+      }
+      let (unit, function) = match trace.first() {
+        Some(frame) => (frame.unit.clone(), frame.function.clone()),
+        None => (self.code_store.name(unit_id), function.clone()),
+      };
+      let loc = trace.first().map(|frame| frame.location).or_else(||
+        self.code_store.nodes.get(&unit_id).map(|n| n.root().loc));
+      let content = ErrorContent::Fault { message: fault.to_string(), unit, function, trace };
+      error_raw(loc.unwrap_or(TextLocation::zero()), content)
+    })
+  }
+
+  /// Describes the compiled function that contains an address, if there is one. If the
+  /// address is the return address of a call, the frame is located at the call.
+  /// Otherwise it is located at the function.
+  fn stack_frame(&self, address : usize, at_call : bool) -> Option<StackFrame> {
+    let cs = &self.code_store;
+    let range = cs.function_range_at(address)?;
+    let def = cs.function_at(address)?;
+    // Polymorphic instances are located at the function they are instances of
+    let source_id = cs.poly_parents.get(&def.unit_id).cloned().unwrap_or(def.id);
+    let source_unit = source_id.uid;
+    let def_node =
+      cs.type_mappings.get(&source_unit).and_then(|m| m.symbol_def_nodes.get(&source_id));
+    // A call returns to the nearest call site after it, as the code in between only
+    // stores the call's result
+    let call_site = range.call_sites.iter().find(|(a, _)| at_call && *a >= address);
+    let location = match (call_site, cs.nodes.get(&source_unit), def_node) {
+      (Some(&(_, loc)), _, _) => loc,
+      (None, Some(nodes), Some(&n)) => nodes.node(n).loc,
+      (None, Some(nodes), None) => nodes.root().loc,
+      (None, None, _) => TextLocation::zero(),
+    };
+    let source = match cs.source_path(source_unit) {
+      Some(path) => path.display().to_string(),
+      None => cs.name(source_unit).to_string(),
     };
-    Ok(value)
+    Some(StackFrame { function: def.name.clone(), unit: cs.name(def.unit_id), source, location })
   }
 
-  fn display_error<'l>(&'l self, error : &'l Error) -> SourcedError<'l> {
+  pub fn display_error<'l>(&'l self, error : &'l Error) -> SourcedError<'l> {
     SourcedError { e: error, c: &self.code_store }
   }
 
 }
 
+/// The source that a unit can be loaded from again
+enum UnitSource { Code(RefStr), Expr(Expr) }
+
+/// Everything needed to load a unit again
+struct UnitReload {
+  uid : UnitId,
+  name : RefStr,
+  path : Option<PathBuf>,
+  imports : Vec<UnitId>,
+  source : UnitSource,
+}
+
+/// The compiled state of a unit that is being recompiled in place
+/// Function slots paired with the addresses to point them at
+type SlotPatches = Vec<(SlotKey, usize)>;
+
+/// How many times each symbol is referred to from within a location
+fn references_within(nodes : &Nodes, mapping : &TypeMapping, loc : TextLocation)
+  -> HashMap<SymbolId, usize>
+{
+  let mut references = HashMap::new();
+  for (node_id, &sid) in mapping.symbol_references.iter() {
+    let l = match nodes.nodes.get(node_id) {
+      Some(node) => node.loc,
+      None => continue,
+    };
+    if l.source == loc.source && l.start >= loc.start && l.end <= loc.end {
+      *references.entry(sid).or_insert(0) += 1;
+    }
+  }
+  references
+}
+
+/// Moves a location within a definition along with the definition
+fn moved_location(loc : TextLocation, from : TextLocation, to : TextLocation) -> TextLocation {
+  let move_marker = |m : TextMarker| {
+    if m.line == from.start.line {
+      TextMarker { line: to.start.line, col: m.col - from.start.col + to.start.col }
+    }
+    else {
+      TextMarker { line: m.line - from.start.line + to.start.line, col: m.col }
+    }
+  };
+  TextLocation { source: to.source, start: move_marker(loc.start), end: move_marker(loc.end) }
+}
+
+/// What an update changed outside of the updated unit, so that it can be rolled back
+struct UpdateLog {
+  /// The code that each unit mapped to before the update
+  old_codegen_ids : Vec<(UnitId, CodegenId)>,
+
+  /// The symbol references of the units that were remapped to the new version
+  references : Vec<(UnitId, HashMap<NodeId, SymbolId>)>,
+
+  /// The addresses that the patched function slots pointed at
+  patches : SlotPatches,
+}
+
+struct SavedUnit {
+  unit_id : UnitId,
+  code : Option<RefStr>,
+  expr : Option<Expr>,
+  nodes : Option<Nodes>,
+  types : TypeInfo,
+  mapping : Option<TypeMapping>,
+  namespaces : Option<HashMap<RefStr, UnitId>>,
+  val : Option<Val>,
+  imports : Vec<UnitId>,
+}
+
+impl SavedUnit {
+  fn take(cs : &mut CodeStore, unit_id : UnitId) -> SavedUnit {
+    SavedUnit {
+      unit_id,
+      code: cs.code.remove(&unit_id),
+      expr: cs.exprs.remove(&unit_id),
+      nodes: cs.nodes.remove(&unit_id),
+      types: cs.types.remove(&unit_id).unwrap(),
+      mapping: cs.type_mappings.remove(&unit_id),
+      namespaces: cs.namespaces.remove(&unit_id),
+      val: cs.vals.get(&unit_id).cloned(),
+      imports: cs.get_imports(unit_id).cloned().collect(),
+    }
+  }
+
+  fn restore(self, cs : &mut CodeStore) {
+    let uid = self.unit_id;
+    match self.code { Some(v) => { cs.code.insert(uid, v); } None => { cs.code.remove(&uid); } }
+    match self.expr { Some(v) => { cs.exprs.insert(uid, v); } None => { cs.exprs.remove(&uid); } }
+    match self.nodes { Some(v) => { cs.nodes.insert(uid, v); } None => { cs.nodes.remove(&uid); } }
+    match self.mapping { Some(v) => { cs.type_mappings.insert(uid, v); } None => { cs.type_mappings.remove(&uid); } }
+    match self.namespaces { Some(v) => { cs.namespaces.insert(uid, v); } None => { cs.namespaces.remove(&uid); } }
+    match self.val { Some(v) => { cs.vals.insert(uid, v); } None => { cs.vals.remove(&uid); } }
+    cs.imports.retain(|(importer, _)| *importer != uid);
+    for i in self.imports {
+      cs.imports.insert((uid, i));
+    }
+    cs.types.insert(uid, self.types);
+  }
+}
+
 #[derive(Clone, PartialEq, Debug)]
 pub enum Val {
   Void,
@@ -315,6 +1402,64 @@ pub enum Val {
   U8(u8),
   String(String),
   Bool(bool),
+  Ptr(u64),
+  Array(Vec<Val>),
+  Struct(RefStr, Vec<(RefStr, Val)>),
+}
+
+impl Val {
+  /// Writes the value on one line if it is short enough, and otherwise puts each
+  /// element or field on its own line
+  fn pretty(&self, f : &mut fmt::Formatter, indent : usize) -> fmt::Result {
+    let one_line = format!("{:#}", self);
+    let (elements, open, close) = match self {
+      Val::Array(vs) if one_line.len() > 60 =>
+        (vs.iter().map(|v| (None, v)).collect::<Vec<_>>(), "[", "]"),
+      Val::Struct(name, fs) if one_line.len() > 60 => {
+        write!(f, "{} ", name)?;
+        (fs.iter().map(|(n, v)| (Some(n), v)).collect(), "{", "}")
+      }
+      _ => return write!(f, "{}", one_line),
+    };
+    writeln!(f, "{}", open)?;
+    for (name, v) in elements {
+      write!(f, "{:width$}", "", width = indent + 2)?;
+      if let Some(name) = name {
+        write!(f, "{}: ", name)?;
+      }
+      v.pretty(f, indent + 2)?;
+      writeln!(f, ",")?;
+    }
+    write!(f, "{:width$}{}", "", close, width = indent)
+  }
+}
+
+/// Values are pretty printed, unless the alternate flag is used
+impl fmt::Display for Val {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    if !f.alternate() {
+      return self.pretty(f, 0);
+    }
+    match self {
+      Val::Void => write!(f, "()"),
+      Val::F64(v) => write!(f, "{}", v),
+      Val::F32(v) => write!(f, "{}", v),
+      Val::I64(v) => write!(f, "{}", v),
+      Val::U64(v) => write!(f, "{}", v),
+      Val::I32(v) => write!(f, "{}", v),
+      Val::U32(v) => write!(f, "{}", v),
+      Val::U16(v) => write!(f, "{}", v),
+      Val::U8(v) => write!(f, "{}", v),
+      Val::String(s) => write!(f, "{:?}", s),
+      Val::Bool(b) => write!(f, "{}", b),
+      Val::Ptr(p) => write!(f, "0x{:x}", p),
+      Val::Array(vs) => write!(f, "[{}]", vs.iter().map(|v| format!("{:#}", v)).join(", ")),
+      Val::Struct(name, fs) => {
+        let fields = fs.iter().map(|(n, v)| format!("{}: {:#}", n, v)).join(", ");
+        write!(f, "{} {{ {} }}", name, fields)
+      }
+    }
+  }
 }
 
 pub struct SourcedError<'l> {
@@ -327,7 +1472,7 @@ impl <'l> fmt::Display for SourcedError<'l> {
     let mut errors = vec![];
     fn find_errors<'e>(e : &'e Error, errors : &mut Vec<&'e Error>) {
       match &e.message {
-        ErrorContent::Message(_) => {
+        ErrorContent::Message(_) | ErrorContent::Fault { .. } => {
           errors.push(e);
         },
         ErrorContent::InnerErrors(_, es) => {
@@ -343,6 +1488,9 @@ impl <'l> fmt::Display for SourcedError<'l> {
       if let Some(name) = self.c.names.get(&e.location.source) {
         writeln!(f, "In unit {}:", name)?;
       }
+      else if let Some(path) = self.c.source_paths.get(&e.location.source) {
+        writeln!(f, "In file {}:", path.display())?;
+      }
       writeln!(f, "{}", e.display())?;
       writeln!(f)?;
     }
@@ -0,0 +1,266 @@
+// Generates DWARF debug info for compiled code, so that it can be stepped through in a
+// native debugger. MCJIT registers every object it loads with the GDB JIT interface,
+// so the debug info is found by gdb and lldb without any extra work.
+//
+// Inkwell doesn't wrap LLVM's debug info API, so this calls the C API directly.
+
+use crate::{common, error, types, code_store, layout, structure};
+use common::*;
+use error::TextLocation;
+use types::{Type, TypeContent, PType};
+use code_store::CodeStore;
+use layout::{type_layout, field_layouts, type_def};
+use structure::TypeKind;
+
+use inkwell::builder::Builder;
+use inkwell::values::{AsValueRef, FunctionValue, PointerValue};
+
+use llvm_sys::prelude::*;
+use llvm_sys::core::*;
+use llvm_sys::debuginfo::*;
+
+use std::collections::HashMap;
+use std::ptr;
+
+static PRODUCER : &'static str = "cauldron";
+
+// DWARF type encodings
+const DW_ATE_BOOLEAN : u32 = 0x02;
+const DW_ATE_FLOAT : u32 = 0x04;
+const DW_ATE_SIGNED : u32 = 0x05;
+const DW_ATE_UNSIGNED : u32 = 0x08;
+
+const DW_TAG_STRUCTURE_TYPE : u32 = 0x13;
+
+/// Builds the debug info of one LLVM module
+pub struct DebugInfo {
+  /// Created with the first function, as inkwell doesn't expose the module
+  builder : LLVMDIBuilderRef,
+  context : LLVMContextRef,
+
+  /// LLVM only allows one compile unit per module, so units that are compiled
+  /// together share one, but each has its own file
+  compile_unit : LLVMMetadataRef,
+  files : HashMap<UnitId, LLVMMetadataRef>,
+
+  types : HashMap<Type, LLVMMetadataRef>,
+}
+
+impl DebugInfo {
+  pub fn new() -> DebugInfo {
+    DebugInfo {
+      builder: ptr::null_mut(),
+      context: ptr::null_mut(),
+      compile_unit: ptr::null_mut(),
+      files: HashMap::new(),
+      types: HashMap::new(),
+    }
+  }
+
+  unsafe fn init(&mut self, f : FunctionValue) {
+    if !self.builder.is_null() {
+      return;
+    }
+    let module = LLVMGetGlobalParent(f.as_value_ref());
+    self.context = LLVMGetModuleContext(module);
+    self.builder = LLVMCreateDIBuilder(module);
+    // Without this flag, LLVM strips the debug info
+    let i32_type = LLVMInt32TypeInContext(self.context);
+    let key = "Debug Info Version";
+    let mut flag = [
+      LLVMConstInt(i32_type, 2, 0), // Warning
+      LLVMMDStringInContext(self.context, key.as_ptr() as *const i8, key.len() as u32),
+      LLVMConstInt(i32_type, LLVMDebugMetadataVersion() as u64, 0),
+    ];
+    let node = LLVMMDNodeInContext(self.context, flag.as_mut_ptr(), flag.len() as u32);
+    LLVMAddNamedMetadataOperand(module, "llvm.module.flags\0".as_ptr() as *const i8, node);
+  }
+
+  /// Finds the file of a unit. Polymorphic instances belong to the unit of the
+  /// function they are instances of.
+  unsafe fn file(&mut self, cs : &CodeStore, unit_id : UnitId) -> LLVMMetadataRef {
+    let unit_id = cs.poly_parents.get(&unit_id).map(|p| p.uid).unwrap_or(unit_id);
+    if let Some(f) = self.files.get(&unit_id) {
+      return *f;
+    }
+    let (name, directory) = match cs.source_path(unit_id) {
+      Some(path) => {
+        let name = path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+        let directory = path.parent().map(|d| d.display().to_string()).unwrap_or_default();
+        (name, directory)
+      }
+      None => (cs.name(unit_id).to_string(), String::new()),
+    };
+    let b = self.builder;
+    let file = LLVMDIBuilderCreateFile(
+      b, name.as_ptr() as *const i8, name.len(), directory.as_ptr() as *const i8, directory.len());
+    if self.compile_unit.is_null() {
+      self.compile_unit = LLVMDIBuilderCreateCompileUnit(
+        b, LLVMDWARFSourceLanguage::LLVMDWARFSourceLanguageC, file,
+        PRODUCER.as_ptr() as *const i8, PRODUCER.len(), 0, ptr::null(), 0, 0, ptr::null(), 0,
+        LLVMDWARFEmissionKind::LLVMDWARFEmissionKindFull, 0, 0, 0);
+    }
+    self.files.insert(unit_id, file);
+    file
+  }
+
+  unsafe fn basic_type(&self, name : &str, bits : u64, encoding : u32) -> LLVMMetadataRef {
+    LLVMDIBuilderCreateBasicType(
+      self.builder, name.as_ptr() as *const i8, name.len(), bits, encoding, LLVMDIFlagZero)
+  }
+
+  /// Describes a type. Returns null for void.
+  unsafe fn di_type(&mut self, cs : &CodeStore, file : LLVMMetadataRef, t : &Type) -> LLVMMetadataRef {
+    if let Some(dt) = self.types.get(t) {
+      return *dt;
+    }
+    use PType::*;
+    let dt = match &t.content {
+      TypeContent::Prim(p) => {
+        let bits = type_layout(cs, t).size as u64 * 8;
+        let name = format!("{:?}", p).to_lowercase();
+        match p {
+          Void => ptr::null_mut(),
+          F64 | F32 => self.basic_type(&name, bits, DW_ATE_FLOAT),
+          I64 | I32 => self.basic_type(&name, bits, DW_ATE_SIGNED),
+          U64 | U32 | U16 | U8 => self.basic_type(&name, bits, DW_ATE_UNSIGNED),
+          Bool => self.basic_type(&name, bits, DW_ATE_BOOLEAN),
+        }
+      }
+      TypeContent::Ptr | TypeContent::Fun => {
+        let pointee = match t.ptr() {
+          Some(inner) => self.di_type(cs, file, inner),
+          None => ptr::null_mut(),
+        };
+        let name = format!("{}", t);
+        LLVMDIBuilderCreatePointerType(
+          self.builder, pointee, 64, 0, 0, name.as_ptr() as *const i8, name.len())
+      }
+      TypeContent::Def(_, _) => self.composite_type(cs, file, t),
+      TypeContent::Polytype(_) | TypeContent::Abstract(_) => ptr::null_mut(),
+    };
+    self.types.insert(t.clone(), dt);
+    dt
+  }
+
+  /// Describes a struct or union. A placeholder is used while the fields are
+  /// described, as they may refer back to the type.
+  unsafe fn composite_type(&mut self, cs : &CodeStore, file : LLVMMetadataRef, t : &Type) -> LLVMMetadataRef {
+    let b = self.builder;
+    let name = format!("{}", t);
+    let layout = type_layout(cs, t);
+    let (size, align) = (layout.size as u64 * 8, layout.align as u32 * 8);
+    let placeholder = LLVMDIBuilderCreateReplaceableCompositeType(
+      b, DW_TAG_STRUCTURE_TYPE, name.as_ptr() as *const i8, name.len(), file, file, 0, 0,
+      size, align, LLVMDIFlagZero, ptr::null(), 0);
+    self.types.insert(t.clone(), placeholder);
+    let mut members = vec![];
+    for f in field_layouts(cs, t) {
+      let field_type = self.di_type(cs, file, &f.t);
+      let field_layout = type_layout(cs, &f.t);
+      members.push(LLVMDIBuilderCreateMemberType(
+        b, file, f.name.as_ptr() as *const i8, f.name.len(), file, 0,
+        field_layout.size as u64 * 8, field_layout.align as u32 * 8, f.offset as u64 * 8,
+        LLVMDIFlagZero, field_type));
+    }
+    let kind = type_def(cs, t).map(|def| def.kind);
+    let dt = match kind {
+      Some(TypeKind::Union) => LLVMDIBuilderCreateUnionType(
+        b, file, name.as_ptr() as *const i8, name.len(), file, 0, size, align,
+        LLVMDIFlagZero, members.as_mut_ptr(), members.len() as u32, 0, ptr::null(), 0),
+      _ => LLVMDIBuilderCreateStructType(
+        b, file, name.as_ptr() as *const i8, name.len(), file, 0, size, align,
+        LLVMDIFlagZero, ptr::null_mut(), members.as_mut_ptr(), members.len() as u32, 0,
+        ptr::null_mut(), ptr::null(), 0),
+    };
+    LLVMMetadataReplaceAllUsesWith(placeholder, dt);
+    dt
+  }
+
+  /// Describes a function, and returns the scope that its code is located in
+  pub fn function(
+    &mut self, cs : &CodeStore, unit_id : UnitId, f : FunctionValue,
+    name : &str, loc : TextLocation, args : &[Type], return_type : &Type)
+      -> LLVMMetadataRef
+  {
+    unsafe {
+      self.init(f);
+      let file = self.file(cs, unit_id);
+      let mut types = vec![self.di_type(cs, file, return_type)];
+      for a in args {
+        types.push(self.di_type(cs, file, a));
+      }
+      let b = self.builder;
+      let fn_type = LLVMDIBuilderCreateSubroutineType(
+        b, file, types.as_mut_ptr(), types.len() as u32, LLVMDIFlagZero);
+      let linkage_name = f.get_name().to_string_lossy();
+      let line = loc.start.line as u32;
+      let subprogram = LLVMDIBuilderCreateFunction(
+        b, file, name.as_ptr() as *const i8, name.len(),
+        linkage_name.as_ptr() as *const i8, linkage_name.len(),
+        file, line, fn_type, 0, 1, line, LLVMDIFlagZero, 0);
+      LLVMSetSubprogram(f.as_value_ref(), subprogram);
+      subprogram
+    }
+  }
+
+  fn location(&self, scope : LLVMMetadataRef, loc : TextLocation) -> LLVMMetadataRef {
+    let (line, col) = (loc.start.line as u32, loc.start.col as u32 + 1);
+    unsafe { LLVMDIBuilderCreateDebugLocation(self.context, line, col, scope, ptr::null_mut()) }
+  }
+
+  /// Gives the instructions that a builder builds from now on the location of some code
+  pub fn set_location(&self, builder : &Builder, scope : LLVMMetadataRef, loc : TextLocation) {
+    unsafe {
+      let location = LLVMMetadataAsValue(self.context, self.location(scope, loc));
+      LLVMSetCurrentDebugLocation(builder_ref(builder), location);
+    }
+  }
+
+  /// Describes a local variable, which is stored at `pointer`. Argument numbers
+  /// start at 1, and are `None` for variables that aren't arguments.
+  pub fn variable(
+    &mut self, cs : &CodeStore, unit_id : UnitId, scope : LLVMMetadataRef,
+    pointer : PointerValue, name : &str, t : &Type, loc : TextLocation, arg_number : Option<u32>)
+  {
+    unsafe {
+      let file = self.file(cs, unit_id);
+      let var_type = self.di_type(cs, file, t);
+      let b = self.builder;
+      let line = loc.start.line as u32;
+      let var = match arg_number {
+        Some(n) => LLVMDIBuilderCreateParameterVariable(
+          b, scope, name.as_ptr() as *const i8, name.len(), n, file, line, var_type, 1, LLVMDIFlagZero),
+        None => LLVMDIBuilderCreateAutoVariable(
+          b, scope, name.as_ptr() as *const i8, name.len(), file, line, var_type, 1, LLVMDIFlagZero, 0),
+      };
+      let expr = LLVMDIBuilderCreateExpression(b, ptr::null_mut(), 0);
+      // Variables are always stored before they are used, so the alloca is never the
+      // last instruction in its block
+      let alloca = pointer.as_value_ref();
+      let next = LLVMGetNextInstruction(alloca);
+      LLVMDIBuilderInsertDeclareBefore(b, alloca, var, expr, self.location(scope, loc), next);
+    }
+  }
+
+  /// Completes the debug info. This must be called before the module is compiled.
+  pub fn finalize(&self) {
+    if !self.builder.is_null() {
+      unsafe { LLVMDIBuilderFinalize(self.builder) };
+    }
+  }
+}
+
+/// The LLVM builder behind an inkwell builder. Inkwell doesn't expose it, but the
+/// builder is nothing more than the reference.
+unsafe fn builder_ref(builder : &Builder) -> LLVMBuilderRef {
+  std::mem::transmute_copy(builder)
+}
+
+impl Drop for DebugInfo {
+  fn drop(&mut self) {
+    if !self.builder.is_null() {
+      unsafe { LLVMDisposeDIBuilder(self.builder) };
+    }
+  }
+}
@@ -0,0 +1,108 @@
+// Tracks which symbols a unit refers to in the units it imports, so that
+// recompiling a unit only invalidates the dependents that use what changed.
+
+use crate::{common, code_store, types, structure};
+use common::*;
+use code_store::CodeStore;
+use types::{TypeInfo, TypeMapping, TypeDefinition, SymbolId};
+use structure::TypeKind;
+
+use std::collections::{HashMap, HashSet};
+
+/// What happened to a symbol when the unit defining it was recompiled
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SymbolChange {
+  /// The symbol still exists with the same type, under a new id
+  Retained(SymbolId),
+
+  /// The symbol was removed, or its type changed
+  Invalidated,
+}
+
+/// The differences between two typechecked versions of the same unit
+pub struct UnitChanges {
+  pub unit_id : UnitId,
+  pub symbols : HashMap<SymbolId, SymbolChange>,
+
+  /// Names of symbols that only exist in the new version
+  pub added : HashSet<RefStr>,
+
+  pub type_defs_changed : bool,
+}
+
+impl UnitChanges {
+
+  /// Matches each symbol in the old version to the symbol in the new version
+  /// with the same name, type and visibility. Overloads are told apart by their types.
+  pub fn new(old : &TypeInfo, new : &TypeInfo) -> UnitChanges {
+    let mut symbols = HashMap::new();
+    let mut retained = HashSet::new();
+    for old_def in old.symbols.values() {
+      let new_def = new.symbols.values().find(|def| {
+        def.name == old_def.name && def.type_tag == old_def.type_tag &&
+          def.is_public == old_def.is_public
+      });
+      let change = match new_def {
+        Some(def) => {
+          retained.insert(def.id);
+          SymbolChange::Retained(def.id)
+        }
+        None => SymbolChange::Invalidated,
+      };
+      symbols.insert(old_def.id, change);
+    }
+    let added =
+      new.symbols.values()
+      .filter(|def| !retained.contains(&def.id))
+      .map(|def| def.name.clone())
+      .collect();
+    let type_defs_changed =
+      old.type_defs.len() != new.type_defs.len() ||
+      old.type_defs.iter().any(|(name, a)| {
+        new.type_defs.get(name).map(|b| !same_type_def(a, b)).unwrap_or(true)
+      });
+    UnitChanges { unit_id: old.unit_id, symbols, added, type_defs_changed }
+  }
+
+  /// Returns true if a unit with this type mapping has to be typechecked again.
+  /// That is the case if it refers to a symbol that was invalidated, or if a new
+  /// symbol shares a name with one it refers to, as that may change which
+  /// overload it resolves to.
+  pub fn invalidates(&self, cs : &CodeStore, mapping : &TypeMapping) -> bool {
+    referenced_symbols(mapping).any(|sid| {
+      if sid.uid == self.unit_id {
+        self.symbols.get(&sid).map(|c| *c == SymbolChange::Invalidated).unwrap_or(true)
+      }
+      else {
+        self.added.contains(&cs.symbol_def(sid).name)
+      }
+    })
+  }
+
+  /// Points a unit's references at the new ids of the symbols they refer to
+  pub fn remap_references(&self, mapping : &mut TypeMapping) {
+    for sid in mapping.symbol_references.values_mut() {
+      if let Some(SymbolChange::Retained(new_id)) = self.symbols.get(sid) {
+        *sid = *new_id;
+      }
+    }
+  }
+}
+
+/// Every symbol that a unit refers to, including its own
+fn referenced_symbols<'l>(mapping : &'l TypeMapping) -> impl Iterator<Item=SymbolId> + 'l {
+  mapping.symbol_references.values().cloned()
+    .chain(mapping.polymorphic_references.iter().map(|(sid, _)| *sid))
+}
+
+fn same_type_def(a : &TypeDefinition, b : &TypeDefinition) -> bool {
+  let same_kind = match (&a.kind, &b.kind) {
+    (TypeKind::Struct, TypeKind::Struct) | (TypeKind::Union, TypeKind::Union) => true,
+    _ => false,
+  };
+  same_kind && a.type_vars == b.type_vars && a.fields.len() == b.fields.len() &&
+    a.is_public == b.is_public && a.private_fields == b.private_fields &&
+    a.fields.iter().zip(b.fields.iter()).all(|((ra, ta), (rb, tb))| {
+      ra.name == rb.name && ta == tb
+    })
+}
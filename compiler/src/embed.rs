@@ -0,0 +1,309 @@
+// Typed access to compiled functions, for programs that embed the compiler.
+//
+// A function is looked up by unit and name, and its signature is checked against the
+// Rust types it will be called with:
+//
+//   let update : ScriptFunction<(f32,), i64> = get_function(&c, unit_id, "update")?;
+//   let result = update.call(0.5)?;
+//
+// Structs can only be passed behind pointers, because compiled code passes struct
+// values as LLVM aggregates, which don't follow the C calling convention.
+
+use crate::{common, error, compiler, code_store, types, layout, c_interface};
+use common::*;
+use error::{Error, error, TextLocation};
+use compiler::Compiler;
+use code_store::CodeStore;
+use types::{Type, TypeContent, PType, SymbolInit, SignatureBuilder};
+use c_interface::SStr;
+use layout::{type_layout, field_layouts};
+
+use std::marker::PhantomData;
+use std::mem;
+
+/// A Rust type that has the same representation as a type in the language.
+///
+/// This is unsafe to implement, because values are passed to compiled code without
+/// any conversion. Structs must be `#[repr(C)]`, and are best implemented with
+/// `script_struct!`, which also checks their layout.
+pub unsafe trait ScriptType {
+  /// Whether values of the language type `t` can be passed as this type
+  fn matches(cs : &CodeStore, t : &Type) -> bool;
+
+  fn describe() -> String;
+}
+
+/// A `ScriptType` that is passed to and returned from compiled code in the same way as
+/// in C, so that it can be used by value in the signature of a `ScriptFunction`.
+/// Struct types aren't, so they are passed behind pointers instead.
+pub unsafe trait ScriptValue : ScriptType {}
+
+/// A `ScriptType` that corresponds to exactly one type in the language, so that
+/// the signatures of host functions can be derived from their Rust types
+pub trait HostType : ScriptType {
+  fn host_type(cs : &CodeStore, cache : &StringCache) -> Type;
+}
+
+macro_rules! prim_script_type {
+  ($($rust_type:ty => $ptype:ident),*) => {
+    $(
+      unsafe impl ScriptType for $rust_type {
+        fn matches(_cs : &CodeStore, t : &Type) -> bool {
+          t.content == TypeContent::Prim(PType::$ptype)
+        }
+
+        fn describe() -> String {
+          format!("{:?}", PType::$ptype)
+        }
+      }
+
+      unsafe impl ScriptValue for $rust_type {}
+
+      impl HostType for $rust_type {
+        fn host_type(_cs : &CodeStore, _cache : &StringCache) -> Type {
+          PType::$ptype.into()
+        }
+      }
+    )*
+  }
+}
+
+prim_script_type!(
+  f64 => F64, f32 => F32, i64 => I64, i32 => I32,
+  u64 => U64, u32 => U32, u16 => U16, u8 => U8, bool => Bool);
+
+unsafe impl ScriptType for () {
+  fn matches(_cs : &CodeStore, t : &Type) -> bool {
+    t.content == TypeContent::Prim(PType::Void)
+  }
+
+  fn describe() -> String { "Void".into() }
+}
+
+unsafe impl ScriptValue for () {}
+
+impl HostType for () {
+  fn host_type(_cs : &CodeStore, _cache : &StringCache) -> Type {
+    PType::Void.into()
+  }
+}
+
+unsafe impl ScriptType for SStr {
+  fn matches(cs : &CodeStore, t : &Type) -> bool {
+    match &t.content {
+      TypeContent::Def(name, uid) =>
+        name.as_ref() == "string" && cs.name(*uid).as_ref() == "intrinsics",
+      _ => false,
+    }
+  }
+
+  fn describe() -> String { "string".into() }
+}
+
+/// Strings are structs, so host functions only take them behind a pointer, as `&SStr`
+impl HostType for SStr {
+  fn host_type(cs : &CodeStore, cache : &StringCache) -> Type {
+    let intrinsics = cs.named_unit("intrinsics").unwrap();
+    TypeContent::Def(cache.get("string"), intrinsics).into()
+  }
+}
+
+unsafe impl <'a> ScriptType for &'a SStr {
+  fn matches(cs : &CodeStore, t : &Type) -> bool {
+    <*const SStr>::matches(cs, t)
+  }
+
+  fn describe() -> String { <*const SStr>::describe() }
+}
+
+unsafe impl <'a> ScriptValue for &'a SStr {}
+
+impl <'a> HostType for &'a SStr {
+  fn host_type(cs : &CodeStore, cache : &StringCache) -> Type {
+    SStr::host_type(cs, cache).ptr_to()
+  }
+}
+
+unsafe impl <T : ScriptType> ScriptType for *const T {
+  fn matches(cs : &CodeStore, t : &Type) -> bool {
+    t.ptr().map(|inner| T::matches(cs, inner)).unwrap_or(false)
+  }
+
+  fn describe() -> String { format!("ptr({})", T::describe()) }
+}
+
+unsafe impl <T : ScriptType> ScriptType for *mut T {
+  fn matches(cs : &CodeStore, t : &Type) -> bool {
+    <*const T>::matches(cs, t)
+  }
+
+  fn describe() -> String { <*const T>::describe() }
+}
+
+unsafe impl <T : ScriptType> ScriptValue for *const T {}
+
+unsafe impl <T : ScriptType> ScriptValue for *mut T {}
+
+impl <T : HostType> HostType for *const T {
+  fn host_type(cs : &CodeStore, cache : &StringCache) -> Type {
+    T::host_type(cs, cache).ptr_to()
+  }
+}
+
+impl <T : HostType> HostType for *mut T {
+  fn host_type(cs : &CodeStore, cache : &StringCache) -> Type {
+    T::host_type(cs, cache).ptr_to()
+  }
+}
+
+/// Checks that a struct type has the expected fields, and the same size as the Rust type
+pub fn struct_matches(
+  cs : &CodeStore, t : &Type, name : &str, size : usize,
+  fields : &[(&str, fn(&CodeStore, &Type) -> bool)])
+    -> bool
+{
+  match &t.content {
+    TypeContent::Def(def_name, _) if def_name.as_ref() == name => (),
+    _ => return false,
+  }
+  let layouts = field_layouts(cs, t);
+  type_layout(cs, t).size == size && layouts.len() == fields.len() &&
+    layouts.iter().zip(fields.iter()).all(|(l, (field_name, matches))| {
+      l.name.as_ref() == *field_name && matches(cs, &l.t)
+    })
+}
+
+/// Implements `ScriptType` for a `#[repr(C)]` struct, which must have the same
+/// fields, in the same order, as a struct type in the language:
+///
+///   script_struct!(Point, "point", x : i64, y : f32);
+///
+/// The struct isn't a `ScriptValue`, so functions can only take it as a pointer.
+#[macro_export]
+macro_rules! script_struct {
+  ($rust_type:ty, $name:expr, $($field:ident : $field_type:ty),*) => {
+    unsafe impl $crate::embed::ScriptType for $rust_type {
+      fn matches(cs : &$crate::code_store::CodeStore, t : &$crate::types::Type) -> bool {
+        $crate::embed::struct_matches(
+          cs, t, $name, ::std::mem::size_of::<$rust_type>(),
+          &[$((stringify!($field), <$field_type as $crate::embed::ScriptType>::matches)),*])
+      }
+
+      fn describe() -> String { $name.into() }
+    }
+  }
+}
+
+/// Arguments that a compiled function can be called with
+pub trait ScriptArgs {
+  fn matches(cs : &CodeStore, args : &[Type]) -> bool;
+
+  fn describe() -> Vec<String>;
+}
+
+/// A Rust function that can be registered with `Compiler::register_fn`
+pub trait HostFunction {
+  fn address(&self) -> usize;
+
+  fn signature(cs : &CodeStore, cache : &StringCache) -> Type;
+}
+
+/// A compiled function with a checked signature. It borrows the compiler, so that
+/// the function can't be reloaded or unloaded while this is in use.
+pub struct ScriptFunction<'c, Args, R> {
+  address : usize,
+  pub unit_id : UnitId,
+  pub name : RefStr,
+  signature : PhantomData<fn(Args) -> R>,
+  compiler : &'c Compiler,
+}
+
+macro_rules! script_function_arity {
+  ($($arg:ident),*) => {
+    impl <$($arg : ScriptValue),*> ScriptArgs for ($($arg,)*) {
+      #[allow(unused_variables, unused_mut)]
+      fn matches(cs : &CodeStore, args : &[Type]) -> bool {
+        let mut i = args.iter();
+        $(
+          if !i.next().map(|t| $arg::matches(cs, t)).unwrap_or(false) {
+            return false;
+          }
+        )*
+        i.next().is_none()
+      }
+
+      fn describe() -> Vec<String> {
+        vec![$($arg::describe()),*]
+      }
+    }
+
+    impl <'c, $($arg : ScriptValue,)* R : ScriptValue> ScriptFunction<'c, ($($arg,)*), R> {
+      /// Calls the function with `Compiler::call_guarded`, so that a crash or panic
+      /// is returned as an error if guarded execution is enabled
+      #[allow(non_snake_case)]
+      pub fn call(&self, $($arg : $arg),*) -> Result<R, Error> {
+        let f : extern "C" fn($($arg),*) -> R = unsafe { mem::transmute(self.address) };
+        self.compiler.call_guarded(self.unit_id, &self.name, || f($($arg),*))
+      }
+    }
+
+    impl <$($arg : HostType + ScriptValue,)* R : HostType + ScriptValue> HostFunction
+      for extern "C" fn($($arg),*) -> R
+    {
+      fn address(&self) -> usize {
+        *self as usize
+      }
+
+      #[allow(unused_mut)]
+      fn signature(cs : &CodeStore, cache : &StringCache) -> Type {
+        let mut sig = SignatureBuilder::new(R::host_type(cs, cache));
+        $(sig.append_arg($arg::host_type(cs, cache));)*
+        sig.into()
+      }
+    }
+  }
+}
+
+script_function_arity!();
+script_function_arity!(A);
+script_function_arity!(A, B);
+script_function_arity!(A, B, C);
+script_function_arity!(A, B, C, D);
+script_function_arity!(A, B, C, D, E);
+script_function_arity!(A, B, C, D, E, F);
+
+/// Finds a function in a unit whose signature matches the Rust types `Args` and `R`.
+/// Overloads that don't match are ignored.
+pub fn get_function<'c, Args : ScriptArgs, R : ScriptValue>(
+  c : &'c Compiler, unit_id : UnitId, name : &str)
+    -> Result<ScriptFunction<'c, Args, R>, Error>
+{
+  let cs = &c.code_store;
+  let types = match cs.types.get(&unit_id) {
+    Some(types) => types,
+    None => return error(TextLocation::zero(), "unit is not loaded"),
+  };
+  let matching : Vec<_> =
+    types.symbols.values()
+    .filter(|def| def.name.as_ref() == name && !def.is_polymorphic())
+    .filter(|def| if let SymbolInit::Function(_) = def.initialiser { true } else { false })
+    .filter(|def| {
+      let sig = def.type_tag.sig().unwrap();
+      Args::matches(cs, sig.args) && R::matches(cs, sig.return_type)
+    })
+    .collect();
+  match matching.as_slice() {
+    [def] => {
+      let address = cs.function_address(def).unwrap();
+      Ok(ScriptFunction {
+        address, unit_id, name: def.name.clone(), signature: PhantomData, compiler: c,
+      })
+    }
+    [] => {
+      let s = format!("no function '{}' of type fun({}) => {} in unit '{}'",
+        name, Args::describe().join(", "), R::describe(), cs.name(unit_id));
+      error(TextLocation::zero(), s)
+    }
+    _ => error(TextLocation::zero(), format!("more than one function '{}' matches", name)),
+  }
+}
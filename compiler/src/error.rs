@@ -101,6 +101,20 @@ impl <S : Into<String>> From<S> for ErrorContent {
 pub enum ErrorContent {
   Message(String),
   InnerErrors(String, Vec<Error>),
+
+  /// Compiled code crashed or panicked while running a function
+  Fault { message : String, unit : RefStr, function : RefStr, trace : Vec<StackFrame> },
+}
+
+/// A compiled function that was running when a fault happened
+#[derive(Debug, Clone, PartialEq)]
+pub struct StackFrame {
+  pub function : RefStr,
+  pub unit : RefStr,
+
+  /// The file that the function was loaded from, or the name of its unit
+  pub source : String,
+  pub location : TextLocation,
 }
 
 #[derive(Debug, PartialEq)]
@@ -134,6 +148,14 @@ impl <'l> fmt::Display for UnsourcedError<'l> {
         }
         Ok(())
       },
+      ErrorContent::Fault { message, unit, function, trace } => {
+        write!(f, ", message: {} in function '{}' of unit '{}'", message, function, unit)?;
+        for frame in trace.iter() {
+          write!(f, "\n  at {} in {} ({}:{})",
+            frame.function, frame.unit, frame.source, frame.location.start.line)?;
+        }
+        Ok(())
+      },
     }
   }
 }
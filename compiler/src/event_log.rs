@@ -0,0 +1,93 @@
+// Records a program's input events frame by frame, so that a session can be
+// replayed, for example through a newly reloaded version of the program.
+
+/// Events are stored as fixed-size blobs, because the log doesn't know their type
+pub struct EventLog {
+  event_size : usize,
+  data : Vec<u8>,
+
+  /// Offset into `data` of the start of each frame. The last frame is the one
+  /// that events are currently recorded into.
+  frames : Vec<usize>,
+
+  /// Set after seeking back to an earlier frame. Recording anything from there
+  /// discards the frames that came after it.
+  playhead : Option<usize>,
+}
+
+impl EventLog {
+  pub fn new(event_size : usize) -> EventLog {
+    EventLog { event_size, data: vec![], frames: vec![0], playhead: None }
+  }
+
+  pub fn event_size(&self) -> usize {
+    self.event_size
+  }
+
+  /// Records an event, unless it is the wrong size for the log
+  pub fn record(&mut self, event : &[u8]) -> Result<(), String> {
+    if event.len() != self.event_size {
+      return Err(format!(
+        "recorded event of {} bytes in a log of {} byte events", event.len(), self.event_size));
+    }
+    if let Some(frame) = self.playhead {
+      self.truncate(frame);
+    }
+    self.data.extend_from_slice(event);
+    Ok(())
+  }
+
+  /// Starts recording a new frame
+  pub fn next_frame(&mut self) {
+    if let Some(frame) = self.playhead {
+      self.truncate(frame);
+    }
+    else {
+      self.frames.push(self.data.len());
+    }
+  }
+
+  /// The number of frames, including the one being recorded
+  pub fn frame_count(&self) -> usize {
+    self.frames.len()
+  }
+
+  pub fn frame_events(&self, frame : usize) -> &[u8] {
+    let start = self.frames[frame];
+    let end = self.frames.get(frame + 1).cloned().unwrap_or(self.data.len());
+    &self.data[start..end]
+  }
+
+  pub fn event_count(&self, frame : usize) -> usize {
+    self.frame_events(frame).len() / self.event_size
+  }
+
+  pub fn event(&self, frame : usize, index : usize) -> &[u8] {
+    let events = self.frame_events(frame);
+    &events[index * self.event_size..(index + 1) * self.event_size]
+  }
+
+  /// Moves the playhead to the start of a frame, so that replaying stops there.
+  /// Seeking to the frame count moves the playhead back to the end of the log.
+  pub fn seek(&mut self, frame : usize) {
+    self.playhead = if frame >= self.frames.len() { None } else { Some(frame) };
+  }
+
+  /// The events before the playhead, in the order they were recorded
+  pub fn replay_events(&self) -> &[u8] {
+    match self.playhead {
+      Some(frame) => &self.data[..self.frames[frame]],
+      None => &self.data,
+    }
+  }
+
+  /// Keeps the first `frames` frames, and starts recording a new frame after them
+  pub fn truncate(&mut self, frames : usize) {
+    if frames < self.frames.len() {
+      self.data.truncate(self.frames[frames]);
+      self.frames.truncate(frames);
+      self.frames.push(self.data.len());
+    }
+    self.playhead = None;
+  }
+}
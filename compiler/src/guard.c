@@ -0,0 +1,65 @@
+// Makes guarded calls for guard.rs. A guarded call records a jump target, which
+// `cauldron_guard_jump` returns to if the call crashes or panics. Rust can't call
+// setjmp itself, as a function that returns twice is undefined behaviour there.
+
+#include <setjmp.h>
+#include <stddef.h>
+
+#if defined(_MSC_VER)
+  #define THREAD_LOCAL __declspec(thread)
+#else
+  #define THREAD_LOCAL _Thread_local
+#endif
+
+#if defined(_WIN32)
+  typedef jmp_buf jump_buffer;
+  #define SET_JUMP(env) setjmp(env)
+  #define LONG_JUMP(env) longjmp(env, 1)
+#else
+  // The signal mask is saved, so that it is restored when jumping out of a handler
+  typedef sigjmp_buf jump_buffer;
+  #define SET_JUMP(env) sigsetjmp(env, 1)
+  #define LONG_JUMP(env) siglongjmp(env, 1)
+#endif
+
+typedef struct guard {
+  jump_buffer env;
+  struct guard *outer;
+} guard;
+
+// The innermost guarded call on this thread, or null if there isn't one
+static THREAD_LOCAL guard *innermost = NULL;
+
+// Calls `f` with `data`. Returns 0 if it returned, or 1 if it was abandoned by a jump.
+int cauldron_guarded_call(void (*f)(void *), void *data) {
+  guard g;
+  g.outer = innermost;
+  if (SET_JUMP(g.env) != 0) {
+    // The jump has already restored the outer guard
+    return 1;
+  }
+#if defined(_WIN64)
+  // A null frame stops longjmp from trying to unwind through the compiled code,
+  // which has no unwind information
+  ((_JUMP_BUFFER *)&g.env)->Frame = 0;
+#endif
+  innermost = &g;
+  f(data);
+  innermost = g.outer;
+  return 0;
+}
+
+// Whether a guarded call is running on this thread
+int cauldron_has_guard(void) {
+  return innermost != NULL;
+}
+
+// Abandons the innermost guarded call. Does nothing if there isn't one.
+void cauldron_guard_jump(void) {
+  guard *g = innermost;
+  if (g == NULL) {
+    return;
+  }
+  innermost = g->outer;
+  LONG_JUMP(g->env);
+}
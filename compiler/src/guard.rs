@@ -0,0 +1,201 @@
+// Runs compiled code so that crashes are reported as faults, rather than killing
+// the process. Signal handlers catch hardware faults, and jump back to the start of
+// the guarded call with `longjmp`. The `panic` cbind jumps back the same way. The
+// call and the jump are made by a small C shim (guard.c), because a function that
+// calls setjmp can't be written in Rust.
+//
+// Jumping skips the destructors of every frame in between. Guarded calls should only
+// wrap compiled code, so that the skipped frames belong to it, or to the cbinds that
+// it calls.
+//
+// The stack is captured before jumping, so that the compiled functions that were
+// running can be reported.
+
+use std::cell::{Cell, RefCell};
+use std::fmt;
+#[cfg(unix)]
+use std::mem;
+use std::panic::{self, AssertUnwindSafe};
+#[cfg(unix)]
+use std::ptr;
+use std::sync::Once;
+use std::thread;
+
+extern "C" {
+  fn cauldron_guarded_call(f : extern "C" fn(*mut u8), data : *mut u8) -> i32;
+  fn cauldron_has_guard() -> i32;
+  fn cauldron_guard_jump();
+}
+
+/// The most stack frames that a fault records
+const MAX_FRAMES : usize = 64;
+
+thread_local! {
+  /// The cause of the last jump
+  static FAULT_CAUSE : Cell<Option<FaultCause>> = Cell::new(None);
+
+  /// The stack when the last jump happened. It is allocated in advance, because the
+  /// signal handler must not allocate.
+  static FAULT_FRAMES : RefCell<[usize; MAX_FRAMES]> = RefCell::new([0; MAX_FRAMES]);
+  static FAULT_FRAME_COUNT : Cell<usize> = Cell::new(0);
+}
+
+/// A crash or panic that abandoned a guarded call
+#[derive(Debug, Clone, PartialEq)]
+pub struct Fault {
+  pub cause : FaultCause,
+
+  /// The instruction addresses of the stack frames when the fault happened,
+  /// innermost first. Only the innermost `MAX_FRAMES` are kept.
+  pub frames : Vec<usize>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum FaultCause {
+  Signal(i32),
+  Panic(String),
+}
+
+impl fmt::Display for Fault {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match &self.cause {
+      FaultCause::Signal(s) => write!(f, "{}", signal_name(*s)),
+      FaultCause::Panic(m) => write!(f, "panicked: {}", m),
+    }
+  }
+}
+
+fn signal_name(signal : i32) -> &'static str {
+  match signal {
+    libc::SIGSEGV => "segmentation fault",
+    libc::SIGILL => "illegal instruction",
+    libc::SIGFPE => "arithmetic error",
+    #[cfg(unix)]
+    libc::SIGBUS => "bus error",
+    _ => "unexpected signal",
+  }
+}
+
+#[cfg(unix)]
+static SIGNALS : &'static [i32] = &[libc::SIGSEGV, libc::SIGILL, libc::SIGFPE, libc::SIGBUS];
+
+#[cfg(windows)]
+static SIGNALS : &'static [i32] = &[libc::SIGSEGV, libc::SIGILL, libc::SIGFPE];
+
+/// Abandons the innermost guarded call, if there is one
+fn jump_to_guard(cause : FaultCause) -> bool {
+  if unsafe { cauldron_has_guard() } == 0 {
+    return false;
+  }
+  let mut count = 0;
+  FAULT_FRAMES.with(|frames| {
+    let mut frames = frames.borrow_mut();
+    // The synchronised version takes a lock, which may already be held if the
+    // fault happened while taking another trace
+    unsafe {
+      backtrace::trace_unsynchronized(|frame| {
+        frames[count] = frame.ip() as usize;
+        count += 1;
+        count < MAX_FRAMES
+      });
+    }
+  });
+  FAULT_FRAME_COUNT.with(|c| c.set(count));
+  FAULT_CAUSE.with(|c| c.set(Some(cause)));
+  unsafe { cauldron_guard_jump() };
+  unreachable!("guarded call disappeared")
+}
+
+#[cfg(unix)]
+static mut PREVIOUS_HANDLERS : Vec<(i32, libc::sigaction)> = Vec::new();
+
+#[cfg(unix)]
+extern "C" fn handle_signal(signal : i32, _info : *mut libc::siginfo_t, _context : *mut libc::c_void) {
+  if !jump_to_guard(FaultCause::Signal(signal)) {
+    // The fault didn't happen in guarded code. Restore the previous handler, which
+    // will run when the faulting instruction is retried.
+    unsafe {
+      if let Some((_, previous)) = PREVIOUS_HANDLERS.iter().find(|(s, _)| *s == signal) {
+        libc::sigaction(signal, previous, ptr::null_mut());
+      }
+    }
+  }
+}
+
+#[cfg(unix)]
+unsafe fn install_handlers() {
+  for &signal in SIGNALS {
+    let mut action : libc::sigaction = mem::zeroed();
+    action.sa_sigaction = handle_signal as usize;
+    // Run on the alternate stack set up by the Rust runtime, so that stack
+    // overflows can be caught
+    action.sa_flags = libc::SA_SIGINFO | libc::SA_ONSTACK;
+    libc::sigemptyset(&mut action.sa_mask);
+    let mut previous : libc::sigaction = mem::zeroed();
+    libc::sigaction(signal, &action, &mut previous);
+    PREVIOUS_HANDLERS.push((signal, previous));
+  }
+}
+
+#[cfg(windows)]
+extern "C" fn handle_signal(signal : i32) {
+  // The C runtime resets the handler before calling it
+  unsafe { libc::signal(signal, handle_signal as libc::sighandler_t) };
+  if !jump_to_guard(FaultCause::Signal(signal)) {
+    unsafe { libc::signal(signal, libc::SIG_DFL) };
+  }
+}
+
+#[cfg(windows)]
+unsafe fn install_handlers() {
+  for &signal in SIGNALS {
+    libc::signal(signal, handle_signal as libc::sighandler_t);
+  }
+}
+
+static INSTALL_HANDLERS : Once = Once::new();
+
+/// The closure of a guarded call, and its result once it has returned
+struct GuardedCall<T, F> {
+  f : Option<F>,
+  result : Option<thread::Result<T>>,
+}
+
+/// Runs a guarded closure on behalf of the C shim. Rust panics are caught, so that
+/// they don't unwind through C, and resumed once the guarded call has returned.
+extern "C" fn call_closure<T, F : FnOnce() -> T>(data : *mut u8) {
+  let call = unsafe { &mut *(data as *mut GuardedCall<T, F>) };
+  let f = call.f.take().unwrap();
+  call.result = Some(panic::catch_unwind(AssertUnwindSafe(f)));
+}
+
+/// Calls `f`, returning a fault instead if it crashes or panics. Guarded calls can be
+/// nested, in which case a fault is caught by the innermost one.
+pub fn guarded<T, F : FnOnce() -> T>(f : F) -> Result<T, Fault> {
+  INSTALL_HANDLERS.call_once(|| unsafe { install_handlers() });
+  // Thread locals with destructors may allocate when first used, so this happens
+  // here rather than in the signal handler
+  FAULT_CAUSE.with(|c| c.set(None));
+  let mut call = GuardedCall { f: Some(f), result: None };
+  let data = &mut call as *mut GuardedCall<T, F> as *mut u8;
+  let jumped = unsafe { cauldron_guarded_call(call_closure::<T, F>, data) };
+  if jumped == 0 {
+    match call.result.take().unwrap() {
+      Ok(v) => Ok(v),
+      Err(payload) => panic::resume_unwind(payload),
+    }
+  }
+  else {
+    let cause = FAULT_CAUSE.with(|c| c.take()).unwrap();
+    let count = FAULT_FRAME_COUNT.with(|c| c.get());
+    let frames = FAULT_FRAMES.with(|frames| frames.borrow()[..count].to_vec());
+    Err(Fault { cause, frames })
+  }
+}
+
+/// Reports a panic in compiled code. This abandons the innermost guarded call, or
+/// panics if there isn't one.
+pub fn raise_panic(message : String) -> ! {
+  jump_to_guard(FaultCause::Panic(message.clone()));
+  panic!("{}", message)
+}
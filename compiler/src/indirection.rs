@@ -0,0 +1,137 @@
+// Stable entry points for functions. When indirection is enabled, code is linked
+// against trampolines that jump through a patchable slot, instead of against the
+// functions themselves, so that reloading a unit redirects every existing caller
+// and every stored function pointer to the new code.
+
+use crate::{common, code_store, types, llvm_compile, guard};
+use common::*;
+use code_store::CodeStore;
+use types::{SymbolId, SymbolInit, SymbolDefinition};
+use llvm_compile::LlvmUnit;
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Identifies a function across reloads of the unit that defines it. Signatures
+/// are compared as text, because the types in them refer to units by id.
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub struct SlotKey {
+  pub unit_name : RefStr,
+  pub function_name : RefStr,
+  pub signature : String,
+}
+
+pub struct FunctionSlot {
+  /// Address of the trampoline that callers are linked against
+  pub trampoline : usize,
+
+  /// The slots that callers jump through. There is more than one if the slots
+  /// of a renamed unit were merged into this one.
+  targets : Vec<*const AtomicUsize>,
+}
+
+#[derive(Default)]
+pub struct IndirectionTable {
+  slots : HashMap<SlotKey, FunctionSlot>,
+
+  /// Units holding the trampolines. They are never unloaded, so that the
+  /// addresses handed out stay valid.
+  trampoline_units : Vec<LlvmUnit>,
+}
+
+impl IndirectionTable {
+
+  pub fn slot_key(cs : &CodeStore, def : &SymbolDefinition) -> SlotKey {
+    SlotKey {
+      unit_name: cs.name(def.unit_id),
+      function_name: def.name.clone(),
+      signature: format!("{}", def.type_tag),
+    }
+  }
+
+  /// Returns the functions in a unit that could be reached through a slot
+  pub fn slot_functions(cs : &CodeStore, unit_id : UnitId) -> Vec<SymbolId> {
+    cs.types(unit_id).symbols.values()
+      .filter(|def| !def.is_polymorphic())
+      .filter(|def| if let SymbolInit::Function(_) = def.initialiser { true } else { false })
+      .map(|def| def.id)
+      .collect()
+  }
+
+  /// Returns the address of the trampoline for a function, if it has one
+  pub fn trampoline(&self, cs : &CodeStore, def : &SymbolDefinition) -> Option<usize> {
+    self.slots.get(&IndirectionTable::slot_key(cs, def)).map(|s| s.trampoline)
+  }
+
+  /// Adds the slots in a newly compiled trampoline unit. `slots` pairs each key
+  /// with the names of its trampoline and slot in that unit.
+  pub fn add_slots(&mut self, lu : LlvmUnit, slots : Vec<(SlotKey, String, String)>) {
+    for (key, trampoline_name, slot_name) in slots {
+      let trampoline = unsafe { lu.ee.get_function_address(&trampoline_name) }
+        .expect("trampoline was not found") as usize;
+      let target = unsafe { lu.ee.get_global_address(&slot_name) }
+        .expect("function slot was not found") as *const AtomicUsize;
+      self.slots.insert(key, FunctionSlot { trampoline, targets: vec![target] });
+    }
+    self.trampoline_units.push(lu);
+  }
+
+  /// Points a slot at a new function. Callers see either the old or the new
+  /// function, never a mix of the two. Returns the address it pointed at before.
+  pub fn patch(&self, key : &SlotKey, address : usize) -> usize {
+    let slot = self.slots.get(key).expect("function slot was not found");
+    let mut old_address = 0;
+    for &target in slot.targets.iter() {
+      old_address = unsafe { (*target).swap(address, Ordering::SeqCst) };
+    }
+    old_address
+  }
+
+  /// The slots of a unit's functions
+  pub fn unit_slots(&self, unit_name : &str) -> Vec<SlotKey> {
+    self.slots.keys().filter(|k| k.unit_name.as_ref() == unit_name).cloned().collect()
+  }
+
+  /// Points the slots of a unit that was removed at a stub that panics, so that
+  /// stale callers don't run freed code. The slots are kept, in case the unit is
+  /// loaded again.
+  pub fn retire_unit(&self, unit_name : &str) {
+    for key in self.unit_slots(unit_name) {
+      self.patch(&key, removed_function_address());
+    }
+  }
+
+  /// The addresses that the slots currently point at
+  pub fn targets<'l>(&'l self) -> impl Iterator<Item=usize> + 'l {
+    self.slots.values().map(|s| unsafe { (*s.targets[0]).load(Ordering::SeqCst) })
+  }
+
+  /// Slots are keyed by unit name, so they have to follow a unit that is renamed
+  pub fn rename_unit(&mut self, old_name : &str, new_name : &RefStr) {
+    let keys : Vec<SlotKey> =
+      self.slots.keys().filter(|k| k.unit_name.as_ref() == old_name).cloned().collect();
+    for key in keys {
+      let slot = self.slots.remove(&key).unwrap();
+      let new_key = SlotKey { unit_name: new_name.clone(), .. key };
+      // Callers of either slot are redirected whenever the function is patched
+      if let Some(existing) = self.slots.get_mut(&new_key) {
+        let address = unsafe { (*slot.targets[0]).load(Ordering::SeqCst) };
+        existing.targets.extend(slot.targets);
+        self.patch(&new_key, address);
+      }
+      else {
+        self.slots.insert(new_key, slot);
+      }
+    }
+  }
+}
+
+/// What the slot of a function points at once the function has been removed, or its
+/// signature has changed. It never returns, so the arguments it is called with don't matter.
+pub fn removed_function_address() -> usize {
+  removed_function as usize
+}
+
+extern "C" fn removed_function() {
+  guard::raise_panic("called a function that was removed by a reload".into())
+}
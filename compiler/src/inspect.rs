@@ -0,0 +1,86 @@
+// Reads values of any concrete type out of memory, so that the results of
+// evaluated code can be printed and compared.
+
+use crate::{common, code_store, types, layout, compiler, structure};
+use common::*;
+use code_store::CodeStore;
+use types::{Type, TypeContent, PType, TypeDefinition};
+use structure::TypeKind;
+use layout::{type_layout, field_layouts, type_def};
+use compiler::Val;
+
+use std::ptr;
+use std::slice;
+
+/// Whether a type definition is one of the compiler's built-in types
+fn is_intrinsic_type(cs : &CodeStore, def : &TypeDefinition, name : &str) -> bool {
+  def.name.as_ref() == name && cs.name(def.unit_id).as_ref() == "intrinsics"
+}
+
+unsafe fn read_prim(p : PType, address : *const u8) -> Val {
+  use PType::*;
+  match p {
+    Void => Val::Void,
+    F64 => Val::F64(ptr::read_unaligned(address as *const f64)),
+    F32 => Val::F32(ptr::read_unaligned(address as *const f32)),
+    I64 => Val::I64(ptr::read_unaligned(address as *const i64)),
+    I32 => Val::I32(ptr::read_unaligned(address as *const i32)),
+    U64 => Val::U64(ptr::read_unaligned(address as *const u64)),
+    U32 => Val::U32(ptr::read_unaligned(address as *const u32)),
+    U16 => Val::U16(ptr::read_unaligned(address as *const u16)),
+    U8 => Val::U8(*address),
+    Bool => Val::Bool(*address != 0),
+  }
+}
+
+/// Reads a value of a concrete type from an address.
+///
+/// Pointers are not followed, except by strings and arrays, which are read along with
+/// their contents. Unions are read as raw bytes, as there is no way to tell which of
+/// their fields is in use, and reading the wrong one could follow a bad pointer.
+pub unsafe fn read_value(cs : &CodeStore, t : &Type, address : *const u8) -> Val {
+  match &t.content {
+    TypeContent::Prim(p) => read_prim(*p, address),
+    TypeContent::Ptr | TypeContent::Fun =>
+      Val::Ptr(ptr::read_unaligned(address as *const u64)),
+    TypeContent::Def(_, _) => {
+      let def = type_def(cs, t).unwrap();
+      if def.kind == TypeKind::Union {
+        let size = type_layout(cs, t).size;
+        let bytes = slice::from_raw_parts(address, size).iter().map(|&b| Val::U8(b)).collect();
+        return Val::Struct(def.name.clone(), vec![("bytes".into(), Val::Array(bytes))]);
+      }
+      let fields = field_layouts(cs, t);
+      // Strings and arrays both hold a pointer to their data, and a length
+      let data_and_length = || (
+        ptr::read_unaligned(address.add(fields[0].offset) as *const *const u8),
+        ptr::read_unaligned(address.add(fields[1].offset) as *const u64) as usize,
+      );
+      if is_intrinsic_type(cs, def, "string") {
+        let (data, length) = data_and_length();
+        if data.is_null() {
+          return Val::String(String::new());
+        }
+        let bytes = slice::from_raw_parts(data, length);
+        return Val::String(String::from_utf8_lossy(bytes).into_owned());
+      }
+      if is_intrinsic_type(cs, def, "array") {
+        let (data, length) = data_and_length();
+        if data.is_null() {
+          return Val::Array(vec![]);
+        }
+        let element_type = &t.children()[0];
+        let stride = type_layout(cs, element_type).size;
+        let elements =
+          (0..length).map(|i| read_value(cs, element_type, data.add(i * stride))).collect();
+        return Val::Array(elements);
+      }
+      let values =
+        fields.iter().map(|f| (f.name.clone(), read_value(cs, &f.t, address.add(f.offset))))
+        .collect();
+      Val::Struct(def.name.clone(), values)
+    }
+    TypeContent::Polytype(_) | TypeContent::Abstract(_) =>
+      panic!("can't read a value of unresolved type {}", t),
+  }
+}
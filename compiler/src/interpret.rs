@@ -1,29 +1,56 @@
 
 use crate::common::*;
-use crate::error::Error;
+use crate::error::{Error, error_raw, TextLocation};
 use crate::compiler::{Val, Compiler};
+use crate::node_graph::NodeGraph;
+use crate::project;
 
-use std::fs::File;
-use std::io::Read;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
 
-// TODO: fix this gross hack
-#[cfg(not(test))]
-const CODE_PATH : &'static str = "code/";
-#[cfg(test)]
-const CODE_PATH : &'static str = "../code/";
+/// Environment variable naming the directory of the core package
+pub static CORE_PACKAGE_VAR : &'static str = "CAULDRON_CORE";
+
+/// Where the core package is looked for if `CORE_PACKAGE_VAR` isn't set
+static DEFAULT_CORE_PACKAGE : &'static str = "code/core";
+
+/// The directory that the core package is loaded from. Unless `CORE_PACKAGE_VAR` is
+/// set, it is searched for in the working directory, the executable's directory, and
+/// their parents.
+pub fn core_package_dir() -> Result<PathBuf, Error> {
+  if let Some(dir) = env::var_os(CORE_PACKAGE_VAR) {
+    return Ok(PathBuf::from(dir));
+  }
+  let exe_dir = env::current_exe().ok().and_then(|p| p.parent().map(Path::to_path_buf));
+  let roots = env::current_dir().ok().into_iter().chain(exe_dir);
+  for root in roots {
+    for dir in root.ancestors() {
+      let package = dir.join(DEFAULT_CORE_PACKAGE);
+      if package.join(project::MANIFEST_FILE).is_file() {
+        return Ok(package);
+      }
+    }
+  }
+  let message = format!(
+    "could not find the core package in '{}' near the working directory or the executable. Set {} to its directory.",
+    DEFAULT_CORE_PACKAGE, CORE_PACKAGE_VAR);
+  Err(error_raw(TextLocation::zero(), message))
+}
 
 pub struct Interpreter {
   pub c : Box<Compiler>,
   imports : Vec<UnitId>,
+  graph : Option<NodeGraph>,
 }
 
 pub fn interpreter() -> Interpreter {
   let c = Compiler::new();
-  let mut i = Interpreter { c, imports: vec![] };
+  let mut i = Interpreter { c, imports: vec![], graph: None };
   
   // loading core modules
   if let Err(e) = i.load_core_modules() {
-    println!("Failed to load core modules.");
+    println!("Failed to load core modules:\n{}", i.c.display_error(&e));
   }
   
   return i;
@@ -39,20 +66,81 @@ impl Interpreter {
     Ok(self.load_module(code, Some(name))?.1)
   }
 
+  /// Runs a source file as a module named after its path
+  pub fn run_file(&mut self, path : &str) -> Result<Val, Error> {
+    let (unit_id, val) = self.c.load_file(path, &self.imports)?;
+    self.imports.push(unit_id);
+    Ok(val)
+  }
+
+  /// Loads a package directory and its dependencies. The modules loaded so far are
+  /// imported by every module in the package.
+  pub fn load_package(&mut self, path : &str) -> Result<(), Error> {
+    let units = project::load_package(&mut self.c, Path::new(path), &self.imports)?;
+    self.imports.extend(units);
+    Ok(())
+  }
+
+  /// Loads every node in a graph file, then calls their entry points.
+  /// Its nodes import the modules loaded so far.
+  pub fn load_graph(&mut self, path : &str) -> Result<(), Error> {
+    let graph = NodeGraph::from_file(&mut self.c, path)?;
+    graph.load(&mut self.c, &self.imports)?;
+    let result = graph.run_entry_points(&self.c);
+    self.graph = Some(graph);
+    result
+  }
+
+  /// The modules that evaluated code imports
+  pub fn imports(&self) -> &[UnitId] {
+    &self.imports
+  }
+
+  /// Removes a module along with every module that depends on it. Returns the removed modules.
+  pub fn unload(&mut self, unit_id : UnitId) -> Vec<UnitId> {
+    let removed = self.c.find_all_dependents(unit_id);
+    for &uid in removed.iter() {
+      self.c.code_store.remove_unit(uid);
+    }
+    self.imports.retain(|uid| !removed.contains(uid));
+    removed
+  }
+
   fn load_module(&mut self, code : &str, name : Option<&str>) -> Result<(UnitId, Val), Error> {
     let (unit_id, val) = self.c.load_module(code, name, &self.imports)?;
     self.imports.push(unit_id);
     Ok((unit_id, val))
   }
 
-  fn load_core_modules(&mut self) -> Result<(), Error> {
-    for module_name in &["prelude", "list", "compiler"] {
-      let path = format!("{}core/{}.code", CODE_PATH, module_name);
-      let mut f = File::open(&path).expect("failed to load prelude");
-      let mut code = String::new();
-      f.read_to_string(&mut code).unwrap();
-      self.load_module(&code, Some(&path))?;
+  /// Recompiles the module loaded from this file, and whatever depends on what changed.
+  /// Returns false if no module was loaded from the file.
+  pub fn reload_file(&mut self, path : &Path) -> Result<bool, Error> {
+    let names : Vec<RefStr> =
+      self.imports.iter().flat_map(|uid| self.c.code_store.names.get(uid)).cloned().collect();
+    let result = self.reload_file_internal(path);
+    // Reloaded units keep their names, so their new ids can be found by name
+    self.imports = names.iter().flat_map(|n| self.c.code_store.named_unit(n)).collect();
+    result
+  }
+
+  fn reload_file_internal(&mut self, path : &Path) -> Result<bool, Error> {
+    // Graph nodes are reloaded along with everything that depends on them
+    if let Some(graph) = &self.graph {
+      if let Some(node) = graph.node_for_path(path) {
+        let code = fs::read_to_string(path).map_err(|_|
+          error_raw(TextLocation::zero(), format!("failed to read '{}'", path.display())))?;
+        for node in graph.reload_node(&mut self.c, node, &code)? {
+          graph.run_entry_point(&self.c, node)?;
+        }
+        return Ok(true);
+      }
     }
+    self.c.reload_file(path)
+  }
+
+  fn load_core_modules(&mut self) -> Result<(), Error> {
+    let units = project::load_package(&mut self.c, &core_package_dir()?, &self.imports)?;
+    self.imports.extend(units);
     Ok(())
   }
 
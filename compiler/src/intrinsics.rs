@@ -104,6 +104,8 @@ fn add_type_def(cache : &StringCache, gen : &mut UIDGenerator, unit_id : UnitId,
       (reference, t)
     }).collect(),
     type_vars,
+    is_public: true,
+    private_fields: vec![],
   };
   t.type_defs.insert(type_def.name.clone(), type_def);
 }
@@ -124,6 +126,7 @@ fn create_symbol_def(
     type_tag: sig.into(),
     initialiser: SymbolInit::Intrinsic,
     type_vars,
+    is_public: true,
   }
 }
 
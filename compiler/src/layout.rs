@@ -0,0 +1,99 @@
+// Computes the memory layout of types. This must match the layout that
+// `llvm_codegen` produces, so that the runtime can walk values in memory.
+
+use crate::{common, code_store, types, structure};
+use common::*;
+use code_store::CodeStore;
+use types::{Type, TypeContent, PType, TypeDefinition};
+use structure::TypeKind;
+
+/// Size and alignment of a type, in bytes
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Layout {
+  pub size : usize,
+  pub align : usize,
+}
+
+#[derive(Clone, Debug)]
+pub struct FieldLayout {
+  pub name : RefStr,
+  pub offset : usize,
+  pub t : Type,
+}
+
+fn align_up(v : usize, align : usize) -> usize {
+  (v + align - 1) & !(align - 1)
+}
+
+fn prim_layout(p : PType) -> Layout {
+  use PType::*;
+  let size = match p {
+    Void => 0,
+    F64 | I64 | U64 => 8,
+    F32 | I32 | U32 => 4,
+    U16 => 2,
+    U8 | Bool => 1,
+  };
+  Layout { size, align: size.max(1) }
+}
+
+pub fn type_def<'l>(cs : &'l CodeStore, t : &Type) -> Option<&'l TypeDefinition> {
+  if let TypeContent::Def(name, unit_id) = &t.content {
+    return cs.types(*unit_id).find_type_def(name);
+  }
+  None
+}
+
+pub fn type_layout(cs : &CodeStore, t : &Type) -> Layout {
+  match &t.content {
+    TypeContent::Prim(p) => prim_layout(*p),
+    TypeContent::Fun | TypeContent::Ptr => Layout { size: 8, align: 8 },
+    TypeContent::Def(name, _) => {
+      let def = type_def(cs, t).unwrap_or_else(|| panic!("type `{}` not found", name));
+      def_layout(cs, def, t)
+    }
+    TypeContent::Polytype(_) | TypeContent::Abstract(_) =>
+      panic!("can't compute the layout of unresolved type {}", t),
+  }
+}
+
+/// Unions are packed structs in the generated code, so they are never padded
+fn def_layout(cs : &CodeStore, def : &TypeDefinition, t : &Type) -> Layout {
+  let fields = def.instanced_fields(t.children());
+  match def.kind {
+    TypeKind::Struct => {
+      let mut size = 0;
+      let mut align = 1;
+      for f in fields.iter() {
+        let l = type_layout(cs, f);
+        size = align_up(size, l.align) + l.size;
+        align = align.max(l.align);
+      }
+      Layout { size: align_up(size, align), align }
+    }
+    TypeKind::Union => {
+      let size = fields.iter().map(|f| type_layout(cs, f).size).max().unwrap_or(0);
+      Layout { size, align: 1 }
+    }
+  }
+}
+
+/// Returns the offset of every field in a struct or union type
+pub fn field_layouts(cs : &CodeStore, t : &Type) -> Vec<FieldLayout> {
+  let def = type_def(cs, t).unwrap_or_else(|| panic!("expected type definition, found {}", t));
+  let field_types = def.instanced_fields(t.children());
+  let mut offset = 0;
+  let mut layouts = vec![];
+  for ((r, _), ft) in def.fields.iter().zip(field_types.into_iter()) {
+    if let TypeKind::Struct = def.kind {
+      let l = type_layout(cs, &ft);
+      offset = align_up(offset, l.align);
+      layouts.push(FieldLayout { name: r.name.clone(), offset, t: ft });
+      offset += l.size;
+    }
+    else {
+      layouts.push(FieldLayout { name: r.name.clone(), offset: 0, t: ft });
+    }
+  }
+  layouts
+}
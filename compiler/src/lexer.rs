@@ -317,6 +317,7 @@ impl <'l> CStream<'l> {
       if c == '\\' {
         // slash pattern, e.g. \n for newline
         self.skip_char();
+        if !self.has_chars() { break }
         let c = self.peek();
         match c {
           '\\' => self.current_token.push('\\'),
@@ -345,6 +346,24 @@ impl <'l> CStream<'l> {
   }
 }
 
+/// Writes a string as a string literal, escaped so that it lexes back to the same string
+pub fn string_literal(s : &str) -> String {
+  let mut literal = String::with_capacity(s.len() + 2);
+  literal.push('"');
+  for c in s.chars() {
+    match c {
+      '\\' => literal.push_str("\\\\"),
+      '\n' => literal.push_str("\\n"),
+      '\t' => literal.push_str("\\t"),
+      '"' => literal.push_str("\\\""),
+      '\0' => literal.push_str("\\0"),
+      c => literal.push(c),
+    }
+  }
+  literal.push('"');
+  literal
+}
+
 pub fn lex(source : SourceId, code : &str, symbols : &StringCache) -> Result<Vec<Token>, Vec<Error>> {
 
   fn lex_with_errors(cs : &mut CStream) -> Result<(), Error> {
@@ -0,0 +1,58 @@
+// A live-programming compiler, which can be embedded in other programs.
+//
+// `interpreter()` is the simplest way in. Tools that need finer control can drive a
+// `Compiler` directly, query the `CodeStore` it maintains, and call compiled code
+// through the `embed` module.
+
+#[cfg(test)]
+#[macro_use] extern crate rusty_fork;
+
+pub mod common;
+pub mod error;
+pub mod lexer;
+pub mod parser;
+pub mod expr;
+pub mod watcher;
+pub mod structure;
+pub mod types;
+mod intrinsics;
+pub mod code_store;
+mod llvm_codegen;
+mod debug_info;
+mod llvm_compile;
+pub mod compiler;
+pub mod interpret;
+pub mod repl;
+mod repl_helper;
+mod graph;
+mod region;
+pub mod layout;
+mod trace;
+mod migrate;
+mod dependencies;
+mod indirection;
+mod event_log;
+pub mod snapshot;
+pub mod inspect;
+pub mod embed;
+pub mod guard;
+pub mod node_graph;
+pub mod project;
+pub mod c_interface;
+
+#[cfg(test)]
+mod test;
+
+pub use common::{UnitId, RefStr, StringCache};
+pub use error::Error;
+pub use code_store::CodeStore;
+pub use compiler::{Compiler, Val};
+pub use interpret::{Interpreter, interpreter};
+pub use embed::{ScriptType, ScriptValue, HostType, HostFunction, ScriptFunction, get_function};
+
+pub fn print_result(r : Result<Val, Error>) -> String {
+  match r {
+    Ok(v) => format!("{}", v),
+    Err(e) => format!( "{}", e.display()),
+  }
+}
@@ -6,14 +6,16 @@ use crate::error::{Error, error, error_raw, TextLocation};
 
 use crate::structure::{
   Node, NodeId, Nodes, Content, PrimitiveVal, TypeKind, ReferenceId,
-  LabelId, NodeValueType, VarScope, Reference };
+  LabelId, NodeValueType, VarScope, Reference, TOP_LEVEL_FUNCTION_NAME };
 use crate::types::{
   Type, PType, TypeDefinition, SymbolInit, SymbolId, TypeMapping,
   SymbolDefinition, TypeInfo, TypeContent, FunctionSignature };
 use crate::code_store::CodeStore;
-use crate::llvm_compile::SymbolLocation;
+use crate::llvm_compile::{SymbolLocation, CompiledSymbols, CallSites, call_site_table_name};
+use crate::debug_info::DebugInfo;
 
 use std::collections::HashMap;
+use std::ffi::CString;
 
 use inkwell::AddressSpace;
 use inkwell::basic_block::BasicBlock;
@@ -27,10 +29,13 @@ use inkwell::types::{
   FunctionType, IntType, FloatType };
 use inkwell::values::{
   BasicValueEnum, BasicValue, FloatValue, StructValue, IntValue,
-  FunctionValue, PointerValue, GlobalValue };
+  FunctionValue, PointerValue, GlobalValue, AsValueRef };
 use inkwell::{FloatPredicate, IntPredicate};
 use inkwell::targets::TargetData;
 
+use llvm_sys::core::*;
+use llvm_sys::prelude::{LLVMMetadataRef, LLVMBasicBlockRef, LLVMValueRef};
+
 // TODO: maybe add this macro to a utils lib?
 // macro_rules! unwrap_enum {
 //   ( $enum_name:ident, $variant_name:ident, $v:expr) => { 
@@ -54,6 +59,15 @@ enum MaybeVal {
 
 use MaybeVal::*;
 
+/// The name of the function that writes the result of a top-level function through a pointer
+pub fn result_writer_name(function_name : &str) -> String {
+  format!("{}.result", function_name)
+}
+
+/// An empty function generated after all the others in a module, which marks where
+/// the module's last function ends
+pub static END_MARKER_NAME : &'static str = "@end_marker";
+
 impl MaybeVal {
   fn unwrap(self) -> GenVal {
     match self { IsVal(gv) => gv, Void => panic!("expected value, found void.") }
@@ -121,9 +135,14 @@ pub struct Gen<'l> {
   /// Functions that need linking when the execution engine is created
   functions_to_link: &'l mut Vec<(FunctionValue, SymbolLocation)>,
 
+  /// The calls made by each function that has a call site table
+  call_sites: &'l mut Vec<CallSites>,
+
   struct_types: HashMap<RefStr, StructType>,
 
   pm : &'l PassManager<FunctionValue>,
+
+  debug_info : Option<DebugInfo>,
 }
 
 #[derive(Clone, Copy)]
@@ -158,12 +177,21 @@ pub struct GenFunction<'l, 'a> {
   // the llvm function being populated
   fn_val : FunctionValue,
 
+  /// The debug info scope of the function, if debug info is being generated
+  debug_scope : Option<LLVMMetadataRef>,
+
+  /// The location of the code that instructions are being generated for
+  debug_location : Option<TextLocation>,
+
   variables: HashMap<ReferenceId, PointerValue>,
 
   blocks: Vec<Block>,
 
   /// stack of labels in scopes and their state
   labels_in_scope: Vec<(LabelId, LabelState)>,
+
+  /// The block that follows each function call, and the location of the call
+  call_sites: Vec<(LLVMBasicBlockRef, TextLocation)>,
 }
 
 pub struct CompileInfo<'l> {
@@ -171,6 +199,7 @@ pub struct CompileInfo<'l> {
   t : &'l TypeInfo,
   nodes : &'l Nodes,
   mapping : &'l TypeMapping,
+  bounds_checked : bool,
 }
 
 impl <'l> CompileInfo<'l> {
@@ -183,7 +212,8 @@ impl <'l> CompileInfo<'l> {
   )
       -> Self 
   {
-    CompileInfo { code_store, t, nodes, mapping }
+    let bounds_checked = code_store.is_bounds_checked(t.unit_id);
+    CompileInfo { code_store, t, nodes, mapping, bounds_checked }
   }
 
   fn typed_node(&self, nid : NodeId) -> TypedNode {
@@ -261,7 +291,9 @@ impl <'l> Gen<'l> {
     target_data : &'l TargetData,
     globals_to_link: &'l mut Vec<(GlobalValue, SymbolLocation)>,
     functions_to_link: &'l mut Vec<(FunctionValue, SymbolLocation)>,
+    call_sites: &'l mut Vec<CallSites>,
     pm : &'l PassManager<FunctionValue>,
+    debug_info : bool,
   )
       -> Gen<'l>
   {
@@ -270,13 +302,19 @@ impl <'l> Gen<'l> {
       target_data,
       globals_to_link,
       functions_to_link,
+      call_sites,
       struct_types: HashMap::new(),
       pm,
+      debug_info: if debug_info { Some(DebugInfo::new()) } else { None },
     }
   }
 
-  /// Code-generates a module, returning a reference to the top-level function in the module
-  pub fn codegen_module(mut self, unit_group : &[UnitId], code_store : &CodeStore) -> Result<(), Error> {
+  /// Code-generates a module, returning a reference to the top-level function in the module.
+  /// Symbols that are already `compiled` are only declared, and linked against their existing code.
+  pub fn codegen_module(
+    mut self, unit_group : &[UnitId], code_store : &CodeStore, compiled : Option<&CompiledSymbols>)
+      -> Result<(), Error>
+  {
     let mut info = vec![];
     for &unit_id in unit_group {
       let nodes = code_store.nodes(unit_id);
@@ -286,11 +324,13 @@ impl <'l> Gen<'l> {
     }
 
     let mut functions_to_codegen = vec!();
+    let mut result_writers = vec!();
     // Declare all the globals and functions
     for info in info.iter() {
       for def in info.t.symbols.values() {
         if !def.is_polymorphic() {
           let t = self.to_basic_type(info, &def.type_tag).unwrap();
+          let compiled_loc = compiled.and_then(|c| c.location(def.id));
           match &def.initialiser {
             SymbolInit::CBind => {
               let symloc = SymbolLocation::CBind(def.name.clone());
@@ -304,6 +344,11 @@ impl <'l> Gen<'l> {
               }
             }
             SymbolInit::Expression(_node) => {
+              if let Some(symloc) = compiled_loc {
+                let gv = self.module.add_global(t, Some(AddressSpace::Generic), &def.name);
+                self.globals_to_link.push((gv, symloc));
+                continue;
+              }
               self.add_global(const_zero(t), false, &def.name);
               let aaa = (); // Do static initialisation where possible
               // let v = self.codegen_static(info.typed_node(node_id))?;
@@ -315,7 +360,16 @@ impl <'l> Gen<'l> {
                 self.codegen_prototype(
                   info, init.name_for_codegen.as_ref(), sig.return_type,
                   Some(&init.args), sig.args);
-              functions_to_codegen.push((f, init.args.as_slice(), init.body, info));
+              if let Some(symloc) = compiled_loc {
+                self.functions_to_link.push((f, symloc));
+                continue;
+              }
+              functions_to_codegen.push((f, def, init, info));
+              // Results that aren't primitives are read from memory by the compiler
+              let is_prim = if let TypeContent::Prim(_) = sig.return_type.content { true } else { false };
+              if def.name.as_ref() == TOP_LEVEL_FUNCTION_NAME && !is_prim {
+                result_writers.push((f, result_writer_name(&init.name_for_codegen)));
+              }
             }
             SymbolInit::Intrinsic => (),
           }
@@ -324,13 +378,74 @@ impl <'l> Gen<'l> {
     }
 
     // codegen the functions
-    for (p, args, body, info) in functions_to_codegen {
-      self.codegen_function(p, info.typed_node(body), args)?;
+    for (p, def, init, info) in functions_to_codegen {
+      self.codegen_function(p, def, info.typed_node(init.body), &init.args)?;
+    }
+    for (f, name) in result_writers {
+      self.codegen_result_writer(f, &name);
+    }
+    self.codegen_end_marker();
+    if let Some(di) = &self.debug_info {
+      di.finalize();
     }
 
     Ok(())
   }
 
+  fn codegen_end_marker(&mut self) {
+    let fn_type = self.context.void_type().fn_type(&[], false);
+    let marker = self.module.add_function(END_MARKER_NAME, fn_type, None);
+    let builder = self.context.create_builder();
+    let entry = self.context.append_basic_block(&marker, "entry");
+    builder.position_at_end(&entry);
+    builder.build_return(None);
+  }
+
+  /// Code-generates a trampoline for each function, which calls whatever function its
+  /// slot points to. Trampolines have the same signatures as the functions they stand in for.
+  /// `trampolines` pairs each function with the names of its trampoline and its slot.
+  pub fn codegen_trampolines(mut self, trampolines : &[(SymbolId, String, String)], code_store : &CodeStore) {
+    for (symbol_id, trampoline_name, slot_name) in trampolines {
+      let def = code_store.symbol_def(*symbol_id);
+      let info = CompileInfo::new(
+        code_store, code_store.types(def.unit_id),
+        code_store.nodes(def.unit_id), code_store.type_mapping(def.unit_id));
+      let sig = def.type_tag.sig().unwrap();
+      let f = self.codegen_prototype(&info, trampoline_name, sig.return_type, None, sig.args);
+      let fn_ptr_type = f.get_type().ptr_type(AddressSpace::Generic);
+      let slot = self.module.add_global(fn_ptr_type, Some(AddressSpace::Generic), slot_name);
+      slot.set_initializer(&fn_ptr_type.const_null());
+      let builder = self.context.create_builder();
+      let entry = self.context.append_basic_block(&f, "entry");
+      builder.position_at_end(&entry);
+      let target = builder.build_load(slot.as_pointer_value(), "target").into_pointer_value();
+      let args : Vec<BasicValueEnum> = f.get_param_iter().collect();
+      let call = builder.build_call(target, args.as_slice(), "call");
+      match call.try_as_basic_value().left() {
+        Some(v) => builder.build_return(Some(&v)),
+        None => builder.build_return(None),
+      };
+    }
+  }
+
+  /// Code-generates a function that calls a function with no arguments, and stores its
+  /// result through the pointer it is passed. This means the result can be read without
+  /// knowing how values of its type are returned.
+  fn codegen_result_writer(&mut self, f : FunctionValue, name : &str) {
+    let return_type = f.get_type().get_return_type().unwrap();
+    let out_type = return_type.ptr_type(AddressSpace::Generic);
+    let fn_type = self.context.void_type().fn_type(&[out_type.into()], false);
+    let writer = self.module.add_function(name, fn_type, None);
+    let builder = self.context.create_builder();
+    let entry = self.context.append_basic_block(&writer, "entry");
+    builder.position_at_end(&entry);
+    let call = builder.build_call(f, &[], "result");
+    let result = call.try_as_basic_value().left().unwrap();
+    let out = writer.get_first_param().unwrap().into_pointer_value();
+    builder.build_store(out, result);
+    builder.build_return(None);
+  }
+
   fn codegen_prototype(
     &mut self,
     info : &CompileInfo,
@@ -366,26 +481,38 @@ impl <'l> Gen<'l> {
   fn codegen_function(
     &mut self,
     prototype_handle : FunctionValue,
+    def : &SymbolDefinition,
     body : TypedNode,
     args : &[Reference])
       -> Result<FunctionValue, Error>
   {
     // this function is here because Rust doesn't have a proper try/catch yet
-    fn generate(body : TypedNode, args : &[Reference], genf : &mut GenFunction)
+    fn generate(def : &SymbolDefinition, body : TypedNode, args : &[Reference], genf : &mut GenFunction)
       -> Result<(), Error>
     {
       let function = genf.fn_val;
+      let sig = def.type_tag.sig().unwrap();
+      if let Some(di) = &mut genf.gen.debug_info {
+        let scope = di.function(
+          body.info.code_store, body.info.t.unit_id, function,
+          &def.name, body.node.loc, sig.args, sig.return_type);
+        genf.debug_scope = Some(scope);
+      }
+      // Anything not generated for a particular node belongs to the function as a whole
+      genf.set_debug_location(body.node.loc);
+
       let entry = genf.gen.context.append_basic_block(&function, "entry");
 
       genf.builder.position_at_end(&entry);
 
       // set function parameters
-      for (arg_value, arg_symbol) in function.get_param_iter().zip(args) {
-        genf.init_local_var(arg_symbol.id, &arg_symbol.name, arg_value);
+      for (i, (arg_value, arg_symbol)) in function.get_param_iter().zip(args).enumerate() {
+        genf.init_local_var(body.info, arg_symbol, &sig.args[i], arg_value, Some(i as u32 + 1));
       }
 
       // compile body and emit return
       genf.codegen_return(Some(body))?;
+      genf.codegen_call_site_table();
 
       // return the whole thing after verification and optimization
       if function.verify(true) {
@@ -403,7 +530,7 @@ impl <'l> Gen<'l> {
     let builder = self.context.create_builder();
     let mut gen_function = GenFunction::new(self, builder, prototype_handle);
 
-    match generate(body, args, &mut gen_function) {
+    match generate(def, body, args, &mut gen_function) {
       Ok(()) => Ok(prototype_handle),
       Err(e) => {
         // TODO: is this cleanup still necessary? The functions are part of a module in the version of
@@ -742,15 +869,66 @@ fn integer_binary_ops(
   }
 }
 
-fn get_index_data_ptr(gf : &mut GenFunction, container : TypedNode, index : TypedNode) -> Result<PointerValue, Error> {
+/// Emits a branch to a call to the `index_out_of_bounds` host function if the
+/// index is not within the bounds of the array.
+fn codegen_bounds_check(
+  gf : &mut GenFunction, node : TypedNode, array : StructValue, index : TypedNode, index_val : IntValue)
+{
+  let i64_type = gf.gen.context.i64_type();
+  let length = gf.builder.build_extract_value(array, 1, "array_length")
+    .unwrap().into_int_value();
+  let index_val = {
+    if index_val.get_type().get_bit_width() >= 64 { index_val }
+    else if index.type_tag().signed_int() {
+      gf.builder.build_int_s_extend(index_val, i64_type, "index_ext")
+    }
+    else {
+      gf.builder.build_int_z_extend(index_val, i64_type, "index_ext")
+    }
+  };
+  // An unsigned comparison also catches negative indices
+  let in_bounds = gf.builder.build_int_compare(IntPredicate::ULT, index_val, length, "in_bounds");
+  let f = gf.fn_val;
+  let fail_block = gf.gen.context.append_basic_block(&f, "out_of_bounds");
+  let ok_block = gf.gen.context.append_basic_block(&f, "index_ok");
+  gf.builder.build_conditional_branch(in_bounds, &ok_block, &fail_block);
+  gf.builder.position_at_end(&fail_block);
+  let message = {
+    let loc : TextLocation = node.into();
+    let unit_name = node.info.code_store.names.get(&loc.source)
+      .map(|n| n.as_ref()).unwrap_or("unknown");
+    format!("array index out of bounds in module '{}' {}", unit_name, loc)
+  };
+  let byte = gf.gen.context.i8_type();
+  let bytes : Vec<IntValue> =
+    message.as_bytes().iter().map(|v| byte.const_int(*v as u64, false)).collect();
+  let const_array : BasicValueEnum = byte.const_array(bytes.as_slice()).into();
+  let message_ptr = gf.gen.add_global(const_array, true, "bounds_message");
+  let message_ptr = gf.builder.build_pointer_cast(
+    message_ptr, byte.ptr_type(AddressSpace::Generic), "bounds_message_pointer");
+  let message_length = i64_type.const_int(bytes.len() as u64, false);
+  let fail_function = gf.get_linked_index_out_of_bounds_reference();
+  gf.build_function_value_call(
+    fail_function,
+    &[message_ptr.into(), message_length.into(), index_val.into(), length.into()],
+    "");
+  gf.builder.build_unreachable();
+  gf.builder.position_at_end(&ok_block);
+}
+
+fn codegen_element_pointer(
+  gf : &mut GenFunction, node : TypedNode, container : TypedNode, index : TypedNode)
+    -> Result<PointerValue, Error>
+{
   if index.type_tag().int() {
     match &container.type_tag().content {
       TypeContent::Ptr => {
-        return gf.codegen_pointer(container);
+        let ptr = gf.codegen_pointer(container)?;
+        let index = gf.codegen_int(index)?;
+        return Ok(unsafe { gf.builder.build_gep(ptr, &[index], "element_ptr") });
       }
       TypeContent::Def(name, _)=> {
         if name.as_ref() == "array" {
-          // TODO: add bounds checks?
           let array = gf.codegen_struct(container)?;
           let ptr = gf.builder.build_extract_value(array, 0, "array_pointer")
             .unwrap().into_pointer_value();
@@ -760,7 +938,11 @@ fn get_index_data_ptr(gf : &mut GenFunction, container : TypedNode, index : Type
             gf.gen.pointer_to_type(element_type)
           };
           let ptr = gf.builder.build_pointer_cast(ptr, corrected_type, "field cast");
-          return Ok(ptr);
+          let index_val = gf.codegen_int(index)?;
+          if node.info.bounds_checked {
+            codegen_bounds_check(gf, node, array, index, index_val);
+          }
+          return Ok(unsafe { gf.builder.build_gep(ptr, &[index_val], "element_ptr") });
         }
       }
       _ => ()
@@ -770,24 +952,20 @@ fn get_index_data_ptr(gf : &mut GenFunction, container : TypedNode, index : Type
 }
 
 fn codegen_set_index(
-  gf : &mut GenFunction, container : TypedNode, index : TypedNode, new_value : TypedNode)
+  gf : &mut GenFunction, node : TypedNode, container : TypedNode, index : TypedNode, new_value : TypedNode)
     -> Result<MaybeVal, Error>
 {
-  let ptr = get_index_data_ptr(gf, container, index)?;
-  let index = gf.codegen_int(index)?;
-  let element_ptr = unsafe { gf.builder.build_gep(ptr, &[index], "element_ptr") };
+  let element_ptr = codegen_element_pointer(gf, node, container, index)?;
   let new_value = gf.codegen_value(new_value)?;
   gf.builder.build_store(element_ptr, new_value);
   return Ok(MaybeVal::Void);
 }
 
 fn codegen_index(
-  gf : &mut GenFunction, container : TypedNode, index : TypedNode)
+  gf : &mut GenFunction, node : TypedNode, container : TypedNode, index : TypedNode)
     -> Result<GenVal, Error>
 {
-  let ptr = get_index_data_ptr(gf, container, index)?;
-  let index = gf.codegen_int(index)?;
-  let element_ptr = unsafe { gf.builder.build_gep(ptr, &[index], "element_ptr") };
+  let element_ptr = codegen_element_pointer(gf, node, container, index)?;
   return Ok(reg(element_ptr.into()));
 }
 
@@ -796,7 +974,7 @@ fn codegen_binary_intrinsic_call(gf : &mut GenFunction, node : TypedNode, name :
 {
   let (a, b) = (node.get(a), node.get(b));
   if name == "Index" {
-    return codegen_index(gf, a, b);
+    return codegen_index(gf, node, a, b);
   }
   let (ta, tb) = (a.type_tag(), b.type_tag());
   if ta == tb {
@@ -888,7 +1066,7 @@ fn codegen_intrinsic_call(gf : &mut GenFunction, node : TypedNode, name : &str,
   if let [a, b, c] = args {
     let (a, b, c) = (node.get(*a), node.get(*b), node.get(*c));
     if name == "SetIndex" {
-      return codegen_set_index(gf, a, b, c);
+      return codegen_set_index(gf, node, a, b, c);
     }
     panic!("COMPILER BUG: encountered unrecognised intrinsic, {}({}, {}, {}).",
       name, a.type_tag(), b.type_tag(), c.type_tag());
@@ -910,7 +1088,9 @@ impl <'l, 'a> GenFunction<'l, 'a> {
 
   pub fn new(gen: &'l mut Gen<'a>, builder : Builder, fn_val : FunctionValue) -> GenFunction<'l, 'a> {
     let variables = HashMap::new();
-    GenFunction{ gen, fn_val, builder, variables, blocks: vec![Block::new()], labels_in_scope: vec![] }
+    GenFunction{
+      gen, fn_val, builder, debug_scope: None, debug_location: None, variables,
+      blocks: vec![Block::new()], labels_in_scope: vec![], call_sites: vec![] }
   }
 
   fn create_entry_block_alloca(&self, t : BasicTypeEnum, name : &str) -> PointerValue {
@@ -926,10 +1106,28 @@ impl <'l, 'a> GenFunction<'l, 'a> {
     pointer
   }
 
-  fn init_local_var(&mut self, var_id : ReferenceId, name: &str, value : BasicValueEnum) {
-    let pointer = self.create_entry_block_alloca(value.get_type(), name);
+  /// Stores a value in a new local variable. Argument numbers start at 1, and are
+  /// `None` for variables that aren't arguments.
+  fn init_local_var(
+    &mut self, info : &CompileInfo, var : &Reference, t : &Type,
+    value : BasicValueEnum, arg_number : Option<u32>)
+  {
+    let pointer = self.create_entry_block_alloca(value.get_type(), &var.name);
     self.builder.build_store(pointer, value);
-    self.add_var_pointer_to_scope(var_id, pointer);
+    if let (Some(di), Some(scope)) = (&mut self.gen.debug_info, self.debug_scope) {
+      di.variable(
+        info.code_store, info.t.unit_id, scope, pointer, &var.name, t, var.loc, arg_number);
+    }
+    self.add_var_pointer_to_scope(var.id, pointer);
+  }
+
+  /// Gives the instructions generated from now on a location in the debug info, and
+  /// returns the location they were given before
+  fn set_debug_location(&mut self, loc : TextLocation) -> Option<TextLocation> {
+    if let (Some(di), Some(scope)) = (&self.gen.debug_info, self.debug_scope) {
+      di.set_location(&self.builder, scope, loc);
+    }
+    self.debug_location.replace(loc)
   }
 
   fn init_global_var(&mut self, name : &str, value : BasicValueEnum) {
@@ -1287,6 +1485,21 @@ impl <'l, 'a> GenFunction<'l, 'a> {
     }
   }
 
+  /// Declares the host function that reports failed bounds checks
+  fn get_linked_index_out_of_bounds_reference(&mut self) -> FunctionValue {
+    let name = "index_out_of_bounds";
+    if let Some(f) = self.gen.module.get_function(name) {
+      return f;
+    }
+    let i64_type = self.gen.context.i64_type();
+    let byte_ptr = self.gen.context.i8_type().ptr_type(AddressSpace::Generic);
+    let fn_type = self.gen.context.void_type().fn_type(
+      &[byte_ptr.into(), i64_type.into(), i64_type.into(), i64_type.into()], false);
+    let f = self.gen.module.add_function(name, fn_type, None);
+    self.gen.functions_to_link.push((f, SymbolLocation::CBind(name.into())));
+    f
+  }
+
   fn build_function_pointer_call(&mut self, f : PointerValue, args : &[BasicValueEnum], name : &str) -> MaybeVal {
     let call = self.builder.build_call(f, args, name);
     let r = call.try_as_basic_value().left();
@@ -1322,7 +1535,48 @@ impl <'l, 'a> GenFunction<'l, 'a> {
       let v = self.codegen_value(a)?;
       arg_vals.push(v);
     }
-    Ok(self.build_function_pointer_call(function_pointer, arg_vals.as_slice(), "return_val"))
+    let v = self.build_function_pointer_call(function_pointer, arg_vals.as_slice(), "return_val");
+    self.mark_call_site(node.node.loc);
+    Ok(v)
+  }
+
+  /// Continues in a new block after a function call, so that the call's return address
+  /// can be found again from the block's address
+  fn mark_call_site(&mut self, loc : TextLocation) {
+    let call = self.builder.get_insert_block().unwrap().get_last_instruction().unwrap();
+    let f = self.fn_val;
+    let after = self.gen.context.append_basic_block(&f, "after_call");
+    unsafe {
+      let after_ref = LLVMGetLastBasicBlock(f.as_value_ref());
+      // The call falls through into the block, so they stay next to each other
+      LLVMMoveBasicBlockAfter(after_ref, LLVMGetInstructionParent(call.as_value_ref()));
+      self.call_sites.push((after_ref, loc));
+    }
+    self.builder.build_unconditional_branch(&after);
+    self.builder.position_at_end(&after);
+  }
+
+  /// Stores the address of the block after each call in a constant global. Taking
+  /// the addresses also stops the blocks from being merged away.
+  fn codegen_call_site_table(&mut self) {
+    if self.call_sites.is_empty() {
+      return;
+    }
+    let function = self.fn_val.get_name().to_str().unwrap().to_string();
+    let name = CString::new(call_site_table_name(&function)).unwrap();
+    unsafe {
+      let f = self.fn_val.as_value_ref();
+      let module = LLVMGetGlobalParent(f);
+      let i8_ptr = LLVMPointerType(LLVMInt8TypeInContext(LLVMGetModuleContext(module)), 0);
+      let mut addresses : Vec<LLVMValueRef> =
+        self.call_sites.iter().map(|&(block, _)| LLVMBlockAddress(f, block)).collect();
+      let table = LLVMConstArray(i8_ptr, addresses.as_mut_ptr(), addresses.len() as u32);
+      let global = LLVMAddGlobal(module, LLVMTypeOf(table), name.as_ptr());
+      LLVMSetInitializer(global, table);
+      LLVMSetGlobalConstant(global, 1);
+    }
+    let locations = self.call_sites.iter().map(|&(_, loc)| loc).collect();
+    self.gen.call_sites.push(CallSites { function, locations });
   }
 
   fn get_linked_drop_reference(&mut self, _info : &CompileInfo, _t : &Type) -> Option<FunctionValue> {
@@ -1393,8 +1647,14 @@ impl <'l, 'a> GenFunction<'l, 'a> {
 
 
   fn codegen_expression(&mut self, node : TypedNode) -> Result<MaybeVal, Error> {
+    let outer = self.set_debug_location(node.node.loc);
     let v = self.codegen_without_drop_value_registration(node)?;
-    return self.codegen_drop_value_registration(node, v);
+    let v = self.codegen_drop_value_registration(node, v)?;
+    // The rest of the parent's instructions belong to the parent
+    if let Some(loc) = outer {
+      self.set_debug_location(loc);
+    }
+    Ok(v)
   }
 
   fn codegen_without_drop_value_registration(&mut self, node : TypedNode) -> Result<MaybeVal, Error> {
@@ -1554,6 +1814,9 @@ impl <'l, 'a> GenFunction<'l, 'a> {
       Content::TypeAlias { .. } => {
         return Ok(Void);
       }
+      Content::Import { .. } => {
+        return Ok(Void);
+      }
       Content::TypeConstructor{ name:_, field_values } => {
         // TODO: log values that need to be dropped
         let a : Result<Vec<BasicValueEnum>, Error> =
@@ -1711,7 +1974,7 @@ impl <'l, 'a> GenFunction<'l, 'a> {
         match var_scope {
           VarScope::Local => {
             let v = self.codegen_value(value)?;
-            self.init_local_var(name.id, &name.name, v);
+            self.init_local_var(info, name, value.type_tag(), v, None);
           }
           VarScope::Global(_) => {
             let aaa = (); // THIS SHOULDN'T HAPPEN FOR CONST GLOBALS
@@ -4,7 +4,7 @@ use crate::{
 };
 
 use common::*;
-use error::Error;
+use error::{Error, TextLocation};
 use c_interface::CSymbols;
 use types::{SymbolId, SymbolInit};
 use code_store::{CodeStore, CodegenId};
@@ -17,10 +17,30 @@ use inkwell::OptimizationLevel;
 use inkwell::execution_engine::ExecutionEngine;
 use inkwell::module::Module;
 
+use std::collections::{HashMap, HashSet};
+
 pub enum SymbolLocation {
   CBind(RefStr),
   Function(UnitId, SymbolId),
   Global(UnitId, SymbolId),
+
+  /// A symbol in code that was compiled earlier, found by the name it was compiled under
+  Compiled(CodegenId, RefStr),
+}
+
+/// The symbols of an updated unit that are linked against the code they were already
+/// compiled into, rather than being compiled again
+pub struct CompiledSymbols {
+  pub codegen_id : CodegenId,
+
+  /// The name that each symbol was compiled under, by its id in the new version
+  pub names : HashMap<SymbolId, RefStr>,
+}
+
+impl CompiledSymbols {
+  pub fn location(&self, symbol_id : SymbolId) -> Option<SymbolLocation> {
+    self.names.get(&symbol_id).map(|name| SymbolLocation::Compiled(self.codegen_id, name.clone()))
+  }
 }
 
 pub struct LlvmUnit {
@@ -29,6 +49,39 @@ pub struct LlvmUnit {
   pub llvm_module : Module,
   pub globals_to_link : Vec<(GlobalValue, SymbolLocation)>,
   pub functions_to_link : Vec<(FunctionValue, SymbolLocation)>,
+
+  /// The calls made by each function, in the order of its call site table
+  pub call_sites : Vec<CallSites>,
+
+  /// The machine code of each function, once the unit is linked
+  pub function_ranges : Vec<FunctionRange>,
+
+  /// The units whose code or globals this one was linked against directly,
+  /// rather than through a trampoline
+  pub links_to : HashSet<CodegenId>,
+}
+
+/// The addresses of a compiled function's machine code
+pub struct FunctionRange {
+  pub start : usize,
+  pub end : usize,
+  pub symbol : SymbolId,
+
+  /// The address that each call in the function returns to, and the location of the
+  /// call, sorted by address
+  pub call_sites : Vec<(usize, TextLocation)>,
+}
+
+/// The locations of the calls made by a function. The function's call site table holds
+/// the address that each call returns to, in the same order.
+pub struct CallSites {
+  pub function : String,
+  pub locations : Vec<TextLocation>,
+}
+
+/// The name of the global that holds a function's call site table
+pub fn call_site_table_name(function_name : &str) -> String {
+  format!("call_sites.{}", function_name)
 }
 
 pub fn execute_function<T>(function_name : &str, llvm_unit : &LlvmUnit) -> T {
@@ -54,6 +107,8 @@ impl LlvmCompiler {
     codegen_id : CodegenId,
     unit_group : &[UnitId],
     code_store : &CodeStore,
+    compiled : Option<&CompiledSymbols>,
+    debug_info : bool,
   ) -> Result<LlvmUnit, Error>
   {
     let name = code_store.name(unit_group[0]);
@@ -78,20 +133,57 @@ impl LlvmCompiler {
 
     let mut globals_to_link = vec![];
     let mut functions_to_link = vec![];
+    let mut call_sites = vec![];
     {
       let gen = Gen::new(
         &self.context, &mut llvm_module, &mut ee.get_target_data(),
-        &mut globals_to_link, &mut functions_to_link, &pm);
-      gen.codegen_module(unit_group, code_store)?
+        &mut globals_to_link, &mut functions_to_link, &mut call_sites, &pm, debug_info);
+      gen.codegen_module(unit_group, code_store, compiled)?
     };
 
     if compiler::DEBUG_PRINTING_IR {
       println!("{}", llvm_module.print_to_string());
     }
 
-    let lu = LlvmUnit { codegen_id, ee, llvm_module, globals_to_link, functions_to_link };
+    let lu = LlvmUnit {
+      codegen_id, ee, llvm_module, globals_to_link, functions_to_link, call_sites,
+      function_ranges: vec![], links_to: HashSet::new(),
+    };
     Ok(lu)
   }
+
+  /// Compiles trampolines for some functions into a unit of their own
+  pub fn compile_trampolines(
+    &self,
+    codegen_id : CodegenId,
+    trampolines : &[(SymbolId, String, String)],
+    code_store : &CodeStore,
+  ) -> LlvmUnit
+  {
+    let mut llvm_module = self.context.create_module("trampolines");
+    let ee =
+      llvm_module.create_jit_execution_engine(OptimizationLevel::None)
+      .expect("could not create execution engine");
+    let pm = PassManager::create(&llvm_module);
+    pm.initialize();
+    let mut globals_to_link = vec![];
+    let mut functions_to_link = vec![];
+    let mut call_sites = vec![];
+    {
+      let gen = Gen::new(
+        &self.context, &mut llvm_module, &mut ee.get_target_data(),
+        &mut globals_to_link, &mut functions_to_link, &mut call_sites, &pm, false);
+      gen.codegen_trampolines(trampolines, code_store);
+    }
+    if compiler::DEBUG_PRINTING_IR {
+      println!("{}", llvm_module.print_to_string());
+    }
+    ee.run_static_constructors();
+    LlvmUnit {
+      codegen_id, ee, llvm_module, globals_to_link, functions_to_link, call_sites,
+      function_ranges: vec![], links_to: HashSet::new(),
+    }
+  }
 }
 
 fn find_symbol_address(code_store : &CodeStore, c_symbols : &CSymbols, loc : &SymbolLocation) -> usize {
@@ -106,6 +198,9 @@ fn find_symbol_address(code_store : &CodeStore, c_symbols : &CSymbols, loc : &Sy
     }
     SymbolLocation::Function(unit_id, symbol_id) => {
       let def = code_store.types(*unit_id).symbols.get(&symbol_id).unwrap();
+      if let Some(address) = code_store.indirection.trampoline(code_store, def) {
+        return address;
+      }
       let init = match &def.initialiser {
         SymbolInit::Function(init) => init, _ => panic!("expected function initialiser") 
       };
@@ -123,14 +218,23 @@ fn find_symbol_address(code_store : &CodeStore, c_symbols : &CSymbols, loc : &Sy
           .expect("global pointer was null") as usize
       }
     }
+    SymbolLocation::Compiled(codegen_id, name) => {
+      let lu = code_store.llvm_units.get(codegen_id).unwrap();
+      unsafe {
+        lu.ee.get_global_address(name)
+          .expect("symbol pointer was null") as usize
+      }
+    }
   }
 }
 
+/// Links a unit against the code it refers to. Returns the units that it was
+/// linked against directly, which have to be kept alive as long as it is.
 pub fn link_unit(
   codegen_id : CodegenId,
   code_store : &CodeStore,
   c_symbols : &CSymbols,
-)
+) -> HashSet<CodegenId>
 {
   let lu = code_store.llvm_units.get(&codegen_id).unwrap();
   // Link globals
@@ -145,4 +249,77 @@ pub fn link_unit(
   }
   // Finalize unit
   lu.ee.run_static_constructors();
+  let locs = lu.globals_to_link.iter().map(|(_, loc)| loc)
+    .chain(lu.functions_to_link.iter().map(|(_, loc)| loc));
+  locs.filter_map(|loc| linked_unit(code_store, loc))
+    .filter(|&id| id != codegen_id)
+    .collect()
+}
+
+/// The unit that a symbol was linked against, unless it was linked through a trampoline
+fn linked_unit(code_store : &CodeStore, loc : &SymbolLocation) -> Option<CodegenId> {
+  match loc {
+    SymbolLocation::CBind(_) => None,
+    SymbolLocation::Function(unit_id, symbol_id) => {
+      let def = code_store.types(*unit_id).symbols.get(&symbol_id).unwrap();
+      if code_store.indirection.trampoline(code_store, def).is_some() {
+        return None;
+      }
+      code_store.codegen_mapping.get(unit_id).cloned()
+    }
+    SymbolLocation::Global(unit_id, _) => code_store.codegen_mapping.get(unit_id).cloned(),
+    SymbolLocation::Compiled(codegen_id, _) => Some(*codegen_id),
+  }
+}
+
+/// Finds where each function of a linked unit group was placed in memory. Functions
+/// are laid out in the order they were generated, so each one ends where the next
+/// one begins, and the last one ends at the end marker. The return addresses of
+/// each function's calls are read from its call site table.
+pub fn function_ranges(lu : &LlvmUnit, unit_group : &[UnitId], code_store : &CodeStore)
+  -> Vec<FunctionRange>
+{
+  let mut symbols = HashMap::new();
+  for &uid in unit_group {
+    for def in code_store.types(uid).symbols.values() {
+      if let SymbolInit::Function(init) = &def.initialiser {
+        symbols.insert(init.name_for_codegen.as_ref(), def.id);
+      }
+    }
+  }
+  let mut starts = vec![];
+  let mut next = lu.llvm_module.get_first_function();
+  while let Some(f) = next {
+    if f.count_basic_blocks() > 0 {
+      let name = f.get_name().to_str().unwrap();
+      if let Some(address) = unsafe { lu.ee.get_function_address(name) } {
+        starts.push((address as usize, symbols.get(name).cloned(), name.to_string()));
+      }
+    }
+    next = f.get_next_function();
+  }
+  starts.sort_by_key(|(address, _, _)| *address);
+  starts.windows(2).flat_map(|w| {
+    let (start, symbol, name) = &w[0];
+    symbol.map(|symbol| FunctionRange {
+      start: *start, end: w[1].0, symbol, call_sites: call_sites(lu, name)
+    })
+  })
+  .collect()
+}
+
+/// Pairs the return addresses in a function's call site table with the locations of
+/// the calls
+fn call_sites(lu : &LlvmUnit, function_name : &str) -> Vec<(usize, TextLocation)> {
+  let calls = match lu.call_sites.iter().find(|c| c.function == function_name) {
+    Some(calls) => calls,
+    None => return vec![],
+  };
+  let table = unsafe { lu.ee.get_global_address(&call_site_table_name(function_name)) }
+    .expect("call site table was null") as *const usize;
+  let addresses = unsafe { std::slice::from_raw_parts(table, calls.locations.len()) };
+  let mut call_sites : Vec<_> =
+    addresses.iter().cloned().zip(calls.locations.iter().cloned()).collect();
+  call_sites.sort_by_key(|(address, _)| *address);
+  call_sites
 }
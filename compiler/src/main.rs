@@ -1,58 +1,15 @@
 
-//#![allow(dead_code)]
-
-#[cfg(test)]
-#[macro_use] extern crate rusty_fork;
-
-mod common;
-mod error;
-mod lexer;
-mod parser;
-mod expr;
-mod watcher;
-mod structure;
-mod types;
-mod intrinsics;
-mod code_store;
-mod llvm_codegen;
-mod llvm_compile;
-mod compiler;
-mod interpret;
-mod repl;
-mod graph;
-pub mod c_interface;
-
-#[cfg(test)]
-mod test;
-
-use std::fs::File;
-use std::io::Read;
-use std::path::PathBuf;
 use std::env;
+use std::path::Path;
 
-use crate::interpret::interpreter;
-use crate::compiler::Val;
-use crate::error::Error;
-
-pub fn print_result(r : Result<Val, Error>) -> String {
-  match r {
-    Ok(v) => format!("{:?}", v),
-    Err(e) => format!( "{}", e.display()),
-  }
-}
-
-fn load(path : &str) -> String {
-  let path = PathBuf::from(path);
-  let mut f = File::open(path).expect("file not found");
-  let mut code = String::new();
-  f.read_to_string(&mut code).unwrap();
-  code
-}
+use cauldron::{watcher, repl, interpreter, print_result, Val};
 
+/// Runs a source file, or loads a package if given a package directory
 fn load_and_run(path : &str) {
-  let code = load(path);
   let mut i = interpreter();
-  let result = i.run_module(&code, path);
+  let result =
+    if Path::new(path).is_dir() { i.load_package(path).map(|_| Val::Void) }
+    else { i.run_file(path) };
   println!("{}", print_result(result));
 }
 
@@ -64,6 +21,9 @@ fn main(){
       watcher::watch(path.as_ref())
     }
     ["watch"] => watcher::watch("code/scratchpad.code"),
+    ["hotload", path] => watcher::hotload(path, None),
+    ["hotload", path, "--watch-requests", address] => watcher::hotload(path, Some(address)),
+    ["graph", path] => watcher::hotload_graph(path),
     ["repl"] => repl::run_repl(),
     ["run", path] => {
       load_and_run(path)
@@ -76,4 +36,4 @@ fn main(){
       println!("unrecognised arguments {:?}", args);
     }
   }
-}
\ No newline at end of file
+}
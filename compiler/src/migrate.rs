@@ -0,0 +1,240 @@
+// Carries the global state of a module over to a newly loaded version of it,
+// so that hot reloading doesn't throw away the program's state.
+
+use crate::{common, code_store, types, layout, structure};
+use common::*;
+use code_store::CodeStore;
+use types::{Type, TypeContent, SymbolInit, SymbolDefinition};
+use layout::{type_layout, field_layouts, type_def};
+use structure::TypeKind;
+
+use std::fmt;
+use std::ptr;
+
+#[derive(Clone, Debug)]
+pub enum FieldChange {
+  Added(RefStr),
+  Removed(RefStr),
+  Retyped(RefStr, Type, Type),
+}
+
+/// The changes made to a type definition between two versions of a unit
+#[derive(Clone, Debug)]
+pub struct TypeDiff {
+  pub name : RefStr,
+  pub changes : Vec<FieldChange>,
+}
+
+impl fmt::Display for TypeDiff {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "type '{}' changed:", self.name)?;
+    for c in self.changes.iter() {
+      match c {
+        FieldChange::Added(n) => write!(f, " added '{}';", n)?,
+        FieldChange::Removed(n) => write!(f, " removed '{}';", n)?,
+        FieldChange::Retyped(n, a, b) => write!(f, " '{}' changed from {} to {};", n, a, b)?,
+      }
+    }
+    Ok(())
+  }
+}
+
+#[derive(Default, Debug)]
+pub struct MigrationReport {
+  pub type_diffs : Vec<TypeDiff>,
+
+  /// Globals whose values were carried over
+  pub migrated : Vec<RefStr>,
+
+  /// Globals that exist in both versions, but couldn't be carried over
+  pub skipped : Vec<RefStr>,
+}
+
+/// Compares the type definitions that two versions of a unit have in common
+pub fn diff_types(cs : &CodeStore, old_unit : UnitId, new_unit : UnitId) -> Vec<TypeDiff> {
+  let (old, new) = (cs.types(old_unit), cs.types(new_unit));
+  let v = Versions { cs, old_unit, new_unit };
+  let mut diffs = vec![];
+  for (name, new_def) in new.type_defs.iter() {
+    if let Some(old_def) = old.type_defs.get(name) {
+      let mut changes = vec![];
+      for (r, old_t) in old_def.fields.iter() {
+        match new_def.fields.iter().find(|(nr, _)| nr.name == r.name) {
+          Some((_, new_t)) => {
+            if !v.same_type(old_t, new_t) {
+              changes.push(FieldChange::Retyped(r.name.clone(), old_t.clone(), new_t.clone()));
+            }
+          }
+          None => changes.push(FieldChange::Removed(r.name.clone())),
+        }
+      }
+      for (r, _) in new_def.fields.iter() {
+        if !old_def.fields.iter().any(|(or, _)| or.name == r.name) {
+          changes.push(FieldChange::Added(r.name.clone()));
+        }
+      }
+      if changes.len() > 0 {
+        diffs.push(TypeDiff { name: name.clone(), changes });
+      }
+    }
+  }
+  diffs
+}
+
+/// Copies the value of every global in the old unit into the global with the same
+/// name in the new unit. Structs whose layout changed are migrated field-by-name.
+///
+/// A migration hook can be defined in the new unit to handle renamed or retyped fields:
+/// `fun migrate(old : ptr(old_layout), new : ptr(T))`. It is called after the automatic
+/// migration of each value of type `T` whose layout changed. `old_layout` should be a
+/// struct declared with the fields of the previous version of `T`.
+///
+/// Values behind pointers are not migrated, so a global is skipped if it can reach a
+/// pointer to a type whose layout changed. A global is also skipped if any of its fields
+/// that kept their type can't be migrated. Fields whose type changed are left to the hook.
+pub fn migrate_globals(cs : &CodeStore, old_unit : UnitId, new_unit : UnitId) -> MigrationReport {
+  let v = Versions { cs, old_unit, new_unit };
+  let mut report = MigrationReport::default();
+  report.type_diffs = diff_types(cs, old_unit, new_unit);
+  for new_def in cs.types(new_unit).symbols.values() {
+    if let SymbolInit::Expression(_) = new_def.initialiser {
+      let old_def = cs.types(old_unit).symbols.values().find(|def| {
+        def.name == new_def.name && if let SymbolInit::Expression(_) = def.initialiser { true } else { false }
+      });
+      if let Some(old_def) = old_def {
+        let old_ptr = v.global_address(old_unit, old_def);
+        let new_ptr = v.global_address(new_unit, new_def);
+        let migrated = match (old_ptr, new_ptr) {
+          (Some(old_ptr), Some(new_ptr)) => {
+            // Migrated into a copy, so that a value that fails part way is left untouched
+            let size = type_layout(cs, &new_def.type_tag).size;
+            let mut value = unsafe { std::slice::from_raw_parts(new_ptr, size) }.to_vec();
+            let migrated =
+              v.migrate_value(old_ptr, &old_def.type_tag, value.as_mut_ptr(), &new_def.type_tag);
+            if migrated {
+              unsafe { ptr::copy_nonoverlapping(value.as_ptr(), new_ptr, size) };
+            }
+            migrated
+          }
+          _ => false,
+        };
+        if migrated {
+          report.migrated.push(new_def.name.clone());
+        }
+        else {
+          report.skipped.push(new_def.name.clone());
+        }
+      }
+    }
+  }
+  report
+}
+
+/// Two loaded versions of the same unit
+struct Versions<'l> {
+  cs : &'l CodeStore,
+  old_unit : UnitId,
+  new_unit : UnitId,
+}
+
+impl <'l> Versions<'l> {
+
+  fn global_address(&self, unit_id : UnitId, def : &SymbolDefinition) -> Option<*mut u8> {
+    let lu = self.cs.llvm_unit(unit_id);
+    unsafe { lu.ee.get_global_address(&def.name) }.map(|a| a as *mut u8)
+  }
+
+  /// Types defined in the old unit are considered the same as types of the same
+  /// name in the new unit. This says nothing about whether their layouts match.
+  fn same_type(&self, a : &Type, b : &Type) -> bool {
+    let content_matches = match (&a.content, &b.content) {
+      (TypeContent::Def(n1, u1), TypeContent::Def(n2, u2)) => {
+        n1 == n2 && (u1 == u2 || (*u1 == self.old_unit && *u2 == self.new_unit))
+      }
+      (x, y) => x == y,
+    };
+    content_matches && a.children.len() == b.children.len()
+      && a.children.iter().zip(b.children.iter()).all(|(a, b)| self.same_type(a, b))
+  }
+
+  /// Returns true if values of the two types have exactly the same representation,
+  /// including the values that they point to
+  fn same_layout(&self, a : &Type, b : &Type) -> bool {
+    self.same_layout_assuming(a, b, &mut vec![])
+  }
+
+  /// `assumed` holds the pairs of types being compared further up, which are assumed
+  /// to match, so that recursive types can be compared
+  fn same_layout_assuming(&self, a : &Type, b : &Type, assumed : &mut Vec<(Type, Type)>) -> bool {
+    if !self.same_type(a, b) {
+      return false;
+    }
+    match &a.content {
+      TypeContent::Def(_, _) => {
+        if assumed.iter().any(|(x, y)| x == a && y == b) {
+          return true;
+        }
+        assumed.push((a.clone(), b.clone()));
+        let (da, db) = (type_def(self.cs, a).unwrap(), type_def(self.cs, b).unwrap());
+        let same_kind = match (&da.kind, &db.kind) {
+          (TypeKind::Struct, TypeKind::Struct) | (TypeKind::Union, TypeKind::Union) => true,
+          _ => false,
+        };
+        let (fa, fb) = (field_layouts(self.cs, a), field_layouts(self.cs, b));
+        same_kind && fa.len() == fb.len() &&
+          fa.iter().zip(fb.iter()).all(|(fa, fb)| {
+            fa.name == fb.name && self.same_layout_assuming(&fa.t, &fb.t, assumed)
+          })
+      }
+      // Pointers are copied as they are, so what they point to must not have changed
+      TypeContent::Ptr => self.same_layout_assuming(a.ptr().unwrap(), b.ptr().unwrap(), assumed),
+      _ => true,
+    }
+  }
+
+  fn migrate_value(&self, old_ptr : *mut u8, old_t : &Type, new_ptr : *mut u8, new_t : &Type) -> bool {
+    if self.same_layout(old_t, new_t) {
+      let size = type_layout(self.cs, new_t).size;
+      unsafe { ptr::copy_nonoverlapping(old_ptr, new_ptr, size) };
+      return true;
+    }
+    if !self.same_type(old_t, new_t) {
+      return false;
+    }
+    let is_struct = |t| {
+      type_def(self.cs, t).map(|def| if let TypeKind::Struct = def.kind { true } else { false })
+    };
+    if is_struct(old_t) != Some(true) || is_struct(new_t) != Some(true) {
+      return false;
+    }
+    let old_fields = field_layouts(self.cs, old_t);
+    for nf in field_layouts(self.cs, new_t) {
+      if let Some(of) = old_fields.iter().find(|of| of.name == nf.name) {
+        // Fields whose type changed are left to the migration hook
+        if self.same_type(&of.t, &nf.t) {
+          let (old_field, new_field) = unsafe { (old_ptr.add(of.offset), new_ptr.add(nf.offset)) };
+          if !self.migrate_value(old_field, &of.t, new_field, &nf.t) {
+            return false;
+          }
+        }
+      }
+    }
+    self.call_migration_hook(old_ptr, new_ptr, new_t);
+    true
+  }
+
+  fn call_migration_hook(&self, old_ptr : *mut u8, new_ptr : *mut u8, new_t : &Type) {
+    let hook_type = new_t.clone().ptr_to();
+    let hook = self.cs.types(self.new_unit).symbols.values().find(|def| {
+      def.name.as_ref() == "migrate" &&
+        def.type_tag.sig().map(|sig| sig.args.len() == 2 && sig.args[1] == hook_type).unwrap_or(false)
+    });
+    if let Some(SymbolInit::Function(init)) = hook.map(|def| &def.initialiser) {
+      let lu = self.cs.llvm_unit(self.new_unit);
+      if let Some(address) = unsafe { lu.ee.get_function_address(&init.name_for_codegen) } {
+        let f : extern "C" fn(*mut u8, *mut u8) = unsafe { std::mem::transmute(address) };
+        f(old_ptr, new_ptr);
+      }
+    }
+  }
+}
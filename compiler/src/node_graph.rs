@@ -0,0 +1,329 @@
+// A reactive graph of modules. Each node is a unit loaded from a source file, which
+// imports the nodes named as its inputs. When a node's source changes, it is reloaded
+// along with the nodes downstream of it, and nothing else is evaluated again.
+//
+// Graphs are described in the language's own syntax, one node per line:
+//
+//   node(name: "input", source: "input.code", outputs: ["poll_input"])
+//   node(name: "game", source: "game.code", inputs: ["input"], entry: "start")
+//
+// Graph files can be written back out in the same format, so that tools can edit them.
+
+use crate::{common, error, lexer, parser, expr, compiler, graph};
+use common::*;
+use error::{Error, error, error_raw};
+use expr::{Expr, ExprContent};
+use lexer::string_literal;
+use compiler::Compiler;
+use graph::{DirectedGraph, get_strongly_connected_components, valid_topological_ordering};
+
+use std::collections::HashSet;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+#[derive(Clone, Debug)]
+pub struct NodeDefinition {
+  pub name : RefStr,
+
+  /// Path of the node's source file, relative to the graph file
+  pub source : PathBuf,
+
+  /// Names of the nodes that this node imports
+  pub inputs : Vec<RefStr>,
+
+  /// Names of the symbols that this node must define for its dependents
+  pub outputs : Vec<RefStr>,
+
+  /// A function with no arguments, called whenever the node has been loaded
+  pub entry : Option<RefStr>,
+
+  pub loc : TextLocation,
+}
+
+pub struct NodeGraph {
+  pub nodes : Vec<NodeDefinition>,
+
+  /// The directory that node source paths are relative to
+  root : PathBuf,
+
+  /// Edges point from each node to its inputs
+  graph : DirectedGraph,
+
+  /// Every node comes after its inputs
+  order : Vec<usize>,
+}
+
+/// Parses a description written as a sequence of calls with named arguments,
+/// such as the node definitions in a graph file
+pub fn parse_description(c : &mut Compiler, code : &str) -> Result<Expr, Error> {
+  let source = create_unit(c.gen.next());
+  let tokens =
+    lexer::lex(source, code, &c.cache)
+    .map_err(|mut es| es.remove(0))?;
+  parser::parse(source, tokens, &c.cache)
+}
+
+/// Returns the named arguments of a call such as `node(name: "a")`
+pub fn named_arguments<'l>(e : &'l Expr, function : &str) -> Result<Vec<(&'l str, &'l Expr)>, Error> {
+  let args = match e.try_construct() {
+    Some(("call", args)) if args.len() > 0 && args[0].try_symbol() == Some(function) => &args[1..],
+    _ => return error(e, format!("expected a {}, such as {}(name: \"a\")", function, function)),
+  };
+  args.iter().map(|arg| match arg.try_construct() {
+    Some((":", [field, value])) => Ok((field.unwrap_symbol()?, value)),
+    _ => error(arg, format!("expected a {} field, such as name: \"a\"", function)),
+  }).collect()
+}
+
+/// Parses a graph description into node definitions
+pub fn parse_graph(c : &mut Compiler, code : &str) -> Result<Vec<NodeDefinition>, Error> {
+  let expr = parse_description(c, code)?;
+  expr.children().iter().map(|e| node_definition(c, e)).collect()
+}
+
+fn node_definition(c : &Compiler, e : &Expr) -> Result<NodeDefinition, Error> {
+  let (mut name, mut source, mut entry) = (None, None, None);
+  let (mut inputs, mut outputs) = (vec![], vec![]);
+  for (field, value) in named_arguments(e, "node")? {
+    match field {
+      "name" => name = Some(c.cache.get(literal_string(value)?)),
+      "source" => source = Some(PathBuf::from(literal_string(value)?)),
+      "inputs" => inputs = string_array(c, value)?,
+      "outputs" => outputs = string_array(c, value)?,
+      "entry" => entry = Some(c.cache.get(literal_string(value)?)),
+      f => return error(value, format!("unknown node field '{}'", f)),
+    }
+  }
+  let name = name.ok_or_else(|| error_raw(e, "node has no name"))?;
+  let source = source.ok_or_else(|| error_raw(e, format!("node '{}' has no source", name)))?;
+  Ok(NodeDefinition { name, source, inputs, outputs, entry, loc: e.loc })
+}
+
+/// Writes node definitions in the format that `parse_graph` reads
+pub fn graph_to_string(nodes : &[NodeDefinition]) -> String {
+  nodes.iter().map(|n| format!("{}\n", n)).collect()
+}
+
+impl fmt::Display for NodeDefinition {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    fn string_array(strings : &[RefStr]) -> String {
+      let strings : Vec<String> = strings.iter().map(|s| string_literal(s)).collect();
+      format!("[{}]", strings.join(", "))
+    }
+    write!(f, "node(name: {}, source: {}",
+      string_literal(&self.name), string_literal(&self.source.to_string_lossy()))?;
+    if self.inputs.len() > 0 {
+      write!(f, ", inputs: {}", string_array(&self.inputs))?;
+    }
+    if self.outputs.len() > 0 {
+      write!(f, ", outputs: {}", string_array(&self.outputs))?;
+    }
+    if let Some(entry) = &self.entry {
+      write!(f, ", entry: {}", string_literal(entry))?;
+    }
+    write!(f, ")")
+  }
+}
+
+pub fn literal_string(e : &Expr) -> Result<&str, Error> {
+  match &e.content {
+    ExprContent::LiteralString(s) => Ok(s.as_str()),
+    _ => error(e, "expected a string"),
+  }
+}
+
+pub fn string_array(c : &Compiler, e : &Expr) -> Result<Vec<RefStr>, Error> {
+  match e.try_construct() {
+    Some(("array", elements)) =>
+      elements.iter().map(|e| Ok(c.cache.get(literal_string(e)?))).collect(),
+    _ => error(e, "expected an array of strings"),
+  }
+}
+
+impl NodeGraph {
+
+  /// Checks that every input refers to a node, and that there are no cycles
+  pub fn new(nodes : Vec<NodeDefinition>, root : &Path) -> Result<NodeGraph, Error> {
+    let mut graph : DirectedGraph = Default::default();
+    for (i, n) in nodes.iter().enumerate() {
+      if nodes[..i].iter().any(|m| m.name == n.name) {
+        return error(n.loc, format!("node '{}' is defined more than once", n.name));
+      }
+      let mut edges = vec![];
+      for input in n.inputs.iter() {
+        match nodes.iter().position(|m| &m.name == input) {
+          Some(j) => edges.push(j),
+          None => return error(n.loc, format!("node '{}' has unknown input '{}'", n.name, input)),
+        }
+      }
+      graph.vertex_edges.push(edges);
+    }
+    for scc in get_strongly_connected_components(&graph) {
+      let i = scc[0];
+      if scc.len() > 1 || graph.edges(i).contains(&i) {
+        let names : Vec<&str> = scc.iter().map(|&i| nodes[i].name.as_ref()).collect();
+        return error(nodes[i].loc, format!("nodes form a cycle: {}", names.join(", ")));
+      }
+    }
+    let order = valid_topological_ordering(&graph).unwrap();
+    Ok(NodeGraph { nodes, root: root.to_path_buf(), graph, order })
+  }
+
+  /// Parses a graph file. Node sources are relative to the directory containing it.
+  pub fn from_file(c : &mut Compiler, path : &str) -> Result<NodeGraph, Error> {
+    let code = fs::read_to_string(path).map_err(|_|
+      error_raw(TextLocation::zero(), format!("file '{}' not found", path)))?;
+    let nodes = parse_graph(c, &code)?;
+    let root = Path::new(path).parent().unwrap_or(Path::new(""));
+    NodeGraph::new(nodes, root)
+  }
+
+  /// Writes the graph out in the format it was read from
+  pub fn save(&self, path : &Path) -> std::io::Result<()> {
+    fs::write(path, graph_to_string(&self.nodes))
+  }
+
+  pub fn node(&self, name : &str) -> Option<usize> {
+    self.nodes.iter().position(|n| n.name.as_ref() == name)
+  }
+
+  /// Units are named after their nodes, so that they can be found again after reloading
+  pub fn unit(&self, c : &Compiler, node : usize) -> Option<UnitId> {
+    c.code_store.named_unit(&self.nodes[node].name)
+  }
+
+  pub fn source_path(&self, node : usize) -> PathBuf {
+    self.root.join(&self.nodes[node].source)
+  }
+
+  /// Returns the node whose source is in this file, if any
+  pub fn node_for_path(&self, path : &Path) -> Option<usize> {
+    let path = fs::canonicalize(path).ok()?;
+    (0..self.nodes.len()).find(|&i| fs::canonicalize(self.source_path(i)).ok() == Some(path.clone()))
+  }
+
+  /// A node and every node that depends on it, directly or indirectly, with each
+  /// node after its inputs
+  pub fn downstream(&self, node : usize) -> Vec<usize> {
+    let mut affected = HashSet::new();
+    affected.insert(node);
+    for &i in self.order.iter() {
+      if self.graph.edges(i).iter().any(|j| affected.contains(j)) {
+        affected.insert(i);
+      }
+    }
+    self.order.iter().cloned().filter(|i| affected.contains(i)).collect()
+  }
+
+  /// Loads every node, after its inputs. Nodes also import `imports`.
+  /// If any node fails to load, none of them are left loaded.
+  pub fn load(&self, c : &mut Compiler, imports : &[UnitId]) -> Result<(), Error> {
+    let mut loaded = vec![];
+    for &i in self.order.iter() {
+      let path = self.source_path(i);
+      let result = fs::read_to_string(&path)
+        .map_err(|_| error_raw(self.nodes[i].loc, format!("file '{}' not found", path.display())))
+        .and_then(|code| {
+          let imports : Vec<UnitId> = imports.iter().cloned()
+            .chain(self.graph.edges(i).iter().map(|&j| self.unit(c, j).unwrap())).collect();
+          self.load_node(c, i, &code, &imports)
+        });
+      match result {
+        Ok(uid) => {
+          self.name_unit(c, i, uid);
+          loaded.push(uid);
+        }
+        Err(e) => {
+          for uid in loaded.into_iter().rev() {
+            c.code_store.remove_unit(uid);
+          }
+          return Err(e);
+        }
+      }
+    }
+    Ok(())
+  }
+
+  /// Reloads a node from new source code, and evaluates everything that depends on it
+  /// again from scratch. That includes the nodes downstream of it, as well as any other
+  /// unit importing it. The replacements are all loaded before anything is swapped, so
+  /// if any of them fails, the old versions are left running. Returns the nodes that
+  /// were reloaded, with each node after its inputs.
+  pub fn reload_node(&self, c : &mut Compiler, node : usize, code : &str)
+    -> Result<Vec<usize>, Error>
+  {
+    let uid = self.unit(c, node).unwrap();
+    let units : Vec<Option<UnitId>> = (0..self.nodes.len()).map(|i| self.unit(c, i)).collect();
+    let remapped = c.reload_module_checked(uid, code, |c, remapped| {
+      for (i, old_uid) in units.iter().enumerate() {
+        if let Some(new_uid) = old_uid.and_then(|u| remapped.get(&u)) {
+          self.check_outputs(c, i, *new_uid)?;
+        }
+      }
+      Ok(())
+    })?;
+    let reloaded = self.order.iter().cloned().filter(|&i| {
+      units[i].map(|u| remapped.contains_key(&u)).unwrap_or(false)
+    }).collect();
+    Ok(reloaded)
+  }
+
+  /// Calls the entry point of each node that has one, with each node after its inputs
+  pub fn run_entry_points(&self, c : &Compiler) -> Result<(), Error> {
+    for &i in self.order.iter() {
+      self.run_entry_point(c, i)?;
+    }
+    Ok(())
+  }
+
+  /// Calls a node's entry point, if it has one
+  pub fn run_entry_point(&self, c : &Compiler, node : usize) -> Result<(), Error> {
+    let n = &self.nodes[node];
+    let entry = if let Some(entry) = &n.entry { entry } else { return Ok(()) };
+    let uid = self.unit(c, node).unwrap();
+    let cs = &c.code_store;
+    let def = cs.types(uid).symbols.values().find(|def| {
+      &def.name == entry && def.type_tag.sig().map(|sig| sig.args.len() == 0).unwrap_or(false)
+    });
+    let address = def.and_then(|def| cs.function_address(def));
+    match address {
+      Some(address) => {
+        let f : extern "C" fn() = unsafe { std::mem::transmute(address) };
+        c.call_guarded(uid, entry, || f())
+      }
+      None => error(n.loc, format!("node '{}' has no entry point '{}' without arguments", n.name, entry)),
+    }
+  }
+
+  /// Gives a newly loaded unit its node's name and source path
+  fn name_unit(&self, c : &mut Compiler, node : usize, uid : UnitId) {
+    let temporary_name = c.code_store.name(uid);
+    c.code_store.indirection.rename_unit(&temporary_name, &self.nodes[node].name);
+    c.code_store.names.insert(uid, self.nodes[node].name.clone());
+    c.register_source_path(uid, &self.source_path(node));
+  }
+
+  /// Loads a node without a name, and checks that it defines its outputs
+  fn load_node(&self, c : &mut Compiler, node : usize, code : &str, imports : &[UnitId])
+    -> Result<UnitId, Error>
+  {
+    let (uid, _) = c.load_module(code, None, imports)?;
+    if let Err(e) = self.check_outputs(c, node, uid) {
+      c.code_store.remove_unit(uid);
+      return Err(e);
+    }
+    Ok(uid)
+  }
+
+  /// Checks that a node's unit defines its outputs
+  fn check_outputs(&self, c : &Compiler, node : usize, uid : UnitId) -> Result<(), Error> {
+    let n = &self.nodes[node];
+    for output in n.outputs.iter() {
+      if !c.code_store.types(uid).symbols.values().any(|def| &def.name == output) {
+        return error(n.loc, format!("node '{}' does not define its output '{}'", n.name, output));
+      }
+    }
+    Ok(())
+  }
+}
@@ -72,7 +72,8 @@ fn parse_config() -> ParseConfig {
   c.separator(";");
   c.separator(",");
   c.prefix(&["#keyword"]);
-  c.infix(&["=", "+=", "in"]);
+  c.infix(&["=", "+="]);
+  c.infix(&["in"]);
   c.infix(&[":"]);
   c.infix(&["as"]);
   c.infix(&["&&", "||"]);
@@ -548,6 +549,20 @@ fn try_parse_keyword_term(ps : &mut ParseState) -> Result<Option<Expr>, Error> {
       let definition = pratt_parse(ps, kp)?;
       ps.add_list("type", vec![definition], start)
     }
+    "pub" => {
+      ps.pop_type(TokenType::Symbol)?;
+      let definition = pratt_parse(ps, kp)?;
+      ps.add_list("pub", vec![definition], start)
+    }
+    "private" => {
+      ps.pop_type(TokenType::Symbol)?;
+      ps.add_list("private", vec![], start)
+    }
+    "import" => {
+      ps.pop_type(TokenType::Symbol)?;
+      let path = pratt_parse(ps, kp)?;
+      ps.add_list("import", vec![path], start)
+    }
     "return" => {
       let start = ps.peek_marker();
       ps.expect("return")?;
@@ -0,0 +1,127 @@
+// Packages of modules, described by a manifest in the package's directory, so that
+// shared libraries can be loaded by name instead of by path. A manifest looks like:
+//
+//   package(name: "game", root: "src", dependencies: ["../sdl2"], modules: ["window", "main"])
+//
+// Module `window` is loaded from `src/window.code`, as a unit named `game/window`.
+
+use crate::{common, error, compiler, node_graph};
+use common::*;
+use error::{Error, error, error_raw};
+use compiler::Compiler;
+use node_graph::{parse_description, named_arguments, literal_string, string_array};
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+pub static MANIFEST_FILE : &'static str = "package.code";
+
+#[derive(Clone, Debug)]
+pub struct Manifest {
+  pub name : RefStr,
+
+  /// The directory containing the manifest
+  pub dir : PathBuf,
+
+  /// The directory that module paths are relative to, relative to `dir`
+  pub root : PathBuf,
+
+  /// Directories of the packages that this package imports, relative to `dir`
+  pub dependencies : Vec<PathBuf>,
+
+  /// Module paths without the file extension, in the order they are loaded.
+  /// Each module imports the modules before it, and those of its dependencies.
+  pub modules : Vec<RefStr>,
+}
+
+impl Manifest {
+
+  /// Reads the manifest in a package directory
+  pub fn from_dir(c : &mut Compiler, dir : &Path) -> Result<Manifest, Error> {
+    let path = dir.join(MANIFEST_FILE);
+    let code = fs::read_to_string(&path).map_err(|_|
+      error_raw(TextLocation::zero(), format!("file '{}' not found", path.display())))?;
+    let expr = parse_description(c, &code)?;
+    let e = match expr.children() {
+      [e] => e,
+      _ => return error(&expr, "expected a single package"),
+    };
+    let (mut name, mut root) = (None, PathBuf::from("."));
+    let (mut dependencies, mut modules) = (vec![], vec![]);
+    for (field, value) in named_arguments(e, "package")? {
+      match field {
+        "name" => name = Some(c.cache.get(literal_string(value)?)),
+        "root" => root = PathBuf::from(literal_string(value)?),
+        "dependencies" =>
+          dependencies = string_array(c, value)?.iter().map(|d| PathBuf::from(d.as_ref())).collect(),
+        "modules" => modules = string_array(c, value)?,
+        f => return error(value, format!("unknown package field '{}'", f)),
+      }
+    }
+    let name = name.ok_or_else(|| error_raw(e, "package has no name"))?;
+    Ok(Manifest { name, dir: dir.to_path_buf(), root, dependencies, modules })
+  }
+
+  pub fn module_path(&self, module : &str) -> PathBuf {
+    self.dir.join(&self.root).join(format!("{}.code", module))
+  }
+
+  /// The name of the unit that a module is loaded as
+  pub fn unit_name(&self, module : &str) -> String {
+    format!("{}/{}", self.name, module)
+  }
+}
+
+/// Loads a package and the packages it depends on. Packages that are already loaded
+/// are not loaded again. Every module also imports `imports`. Returns the package's
+/// modules, in load order.
+pub fn load_package(c : &mut Compiler, dir : &Path, imports : &[UnitId])
+  -> Result<Vec<UnitId>, Error>
+{
+  load_package_internal(c, dir, imports, &mut vec![])
+}
+
+fn load_package_internal(
+  c : &mut Compiler, dir : &Path, imports : &[UnitId], loading : &mut Vec<RefStr>)
+    -> Result<Vec<UnitId>, Error>
+{
+  let manifest = Manifest::from_dir(c, dir)?;
+  if loading.contains(&manifest.name) {
+    return error(TextLocation::zero(),
+      format!("package '{}' depends on itself", manifest.name));
+  }
+  let loaded : Option<Vec<UnitId>> =
+    manifest.modules.iter().map(|m| c.code_store.named_unit(&manifest.unit_name(m))).collect();
+  if let Some(units) = loaded {
+    return Ok(units);
+  }
+  loading.push(manifest.name.clone());
+  let mut module_imports = imports.to_vec();
+  for d in manifest.dependencies.iter() {
+    let units = load_package_internal(c, &manifest.dir.join(d), imports, loading)?;
+    module_imports.extend(units);
+  }
+  loading.pop();
+  let mut units = vec![];
+  for m in manifest.modules.iter() {
+    let path = manifest.module_path(m);
+    let result = fs::read_to_string(&path)
+      .map_err(|_| error_raw(TextLocation::zero(), format!("file '{}' not found", path.display())))
+      .and_then(|code| c.load_module(&code, Some(&manifest.unit_name(m)), &module_imports));
+    match result {
+      Ok((uid, _)) => {
+        c.register_source_path(uid, &path);
+        module_imports.push(uid);
+        units.push(uid);
+      }
+      Err(e) => {
+        // Unload the modules loaded so far, so that loading the package can be retried
+        for uid in units.into_iter().rev() {
+          c.code_store.remove_unit(uid);
+        }
+        return Err(e);
+      }
+    }
+  }
+  Ok(units)
+}
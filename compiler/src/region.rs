@@ -0,0 +1,108 @@
+// Paged bump allocator backing the language's region type
+
+use std::alloc::{alloc_zeroed, dealloc, Layout};
+
+pub static DEFAULT_PAGE_SIZE : usize = 4096;
+
+/// Alignment of every page. Covers the alignment of all primitive types.
+static PAGE_ALIGNMENT : usize = 16;
+
+struct Page {
+  mem : *mut u8,
+  layout : Layout,
+  next : usize,
+}
+
+impl Page {
+  fn new(size : usize, align : usize) -> Page {
+    let layout = Layout::from_size_align(size, align.max(PAGE_ALIGNMENT)).unwrap();
+    let mem = unsafe { alloc_zeroed(layout) };
+    if mem.is_null() {
+      panic!("region failed to allocate a page of {} bytes", size);
+    }
+    Page { mem, layout, next: 0 }
+  }
+
+  /// Returns a pointer to the allocation, or None if the page is full
+  fn alloc(&mut self, bytes : usize, align : usize) -> Option<*mut u8> {
+    let start = self.mem as usize;
+    let offset = align_up(start + self.next, align) - start;
+    if offset + bytes > self.layout.size() {
+      return None;
+    }
+    self.next = offset + bytes;
+    Some(unsafe { self.mem.add(offset) })
+  }
+
+  fn contains(&self, p : *const u8) -> bool {
+    let (start, p) = (self.mem as usize, p as usize);
+    p >= start && p < start + self.next
+  }
+}
+
+fn align_up(v : usize, align : usize) -> usize {
+  (v + align - 1) & !(align - 1)
+}
+
+/// A region of memory that objects are bump-allocated into. Every object
+/// in the region is freed at once when the region is dropped.
+pub struct Region {
+  pages : Vec<Page>,
+  page_size : usize,
+}
+
+impl Region {
+  pub fn new() -> Region {
+    Region::with_page_size(DEFAULT_PAGE_SIZE)
+  }
+
+  pub fn with_page_size(page_size : usize) -> Region {
+    if page_size == 0 {
+      panic!("region page size must be greater than zero");
+    }
+    Region { pages: vec![], page_size }
+  }
+
+  /// Allocates zeroed memory. `align` must be a power of two.
+  pub fn alloc(&mut self, bytes : usize, align : usize) -> *mut u8 {
+    if !align.is_power_of_two() {
+      panic!("region allocation with invalid alignment {}", align);
+    }
+    if let Some(page) = self.pages.last_mut() {
+      if let Some(p) = page.alloc(bytes, align) {
+        return p;
+      }
+    }
+    // Oversized allocations get a page of their own
+    let mut page = Page::new(self.page_size.max(bytes), align);
+    let p = page.alloc(bytes, align).unwrap();
+    self.pages.push(page);
+    p
+  }
+
+  /// Takes ownership of everything allocated in another region
+  pub fn merge(&mut self, mut other : Region) {
+    // Keep the current page last, so that it continues to be bumped
+    let current = self.pages.pop();
+    self.pages.extend(other.pages.drain(..));
+    self.pages.extend(current);
+  }
+
+  /// Returns true if the pointer refers to memory allocated in this region
+  pub fn contains(&self, p : *const u8) -> bool {
+    self.pages.iter().any(|page| page.contains(p))
+  }
+
+  /// The number of bytes handed out by this region, including alignment padding
+  pub fn bytes_used(&self) -> usize {
+    self.pages.iter().map(|p| p.next).sum()
+  }
+}
+
+impl Drop for Region {
+  fn drop(&mut self) {
+    for page in self.pages.iter() {
+      unsafe { dealloc(page.mem, page.layout) };
+    }
+  }
+}
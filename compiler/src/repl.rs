@@ -1,61 +1,168 @@
 
 use crate::interpret::{interpreter, Interpreter};
-use crate::error::{Error, ErrorContent};
+use crate::error::{Error, TextLocation, error};
 use crate::compiler::Val;
-use crate::parser::EXPECTED_TOKEN_ERROR;
+use crate::common::*;
+use crate::structure::TOP_LEVEL_FUNCTION_NAME;
+use crate::repl_helper::{ReplHelper, Completions, is_incomplete};
 
 use rustyline::Editor;
+use itertools::Itertools;
 
-pub enum ReplResult {
-  Complete(Val),
-  Incomplete,
-  Failed(Error),
+use std::cell::RefCell;
+use std::env;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+static HISTORY_FILE : &'static str = ".cauldron_history";
+
+static HELP : &'static str = "\
+:type <expr>     show the type of an expression without running it
+:ir <function>   show the LLVM IR of a function
+:units           list the loaded modules
+:symbols [unit]  list the symbols of a module, or of those imported by the repl
+:load <path>     run or reload a source file, or load a package directory
+:reload          recompile the modules whose files have changed
+:unload <unit>   remove a module and the modules that depend on it
+:help            show this message";
+
+fn find_unit(i : &Interpreter, name : &str) -> Result<UnitId, Error> {
+  match i.c.code_store.named_unit(name) {
+    Some(uid) => Ok(uid),
+    None => error(TextLocation::zero(), format!("no module named '{}'", name)),
+  }
+}
+
+fn list_symbols(i : &Interpreter, units : &[UnitId]) -> String {
+  let cs = &i.c.code_store;
+  units.iter().flat_map(|&uid| cs.types.get(&uid))
+    .flat_map(|types| types.symbols.values())
+    .filter(|def| def.name.as_ref() != TOP_LEVEL_FUNCTION_NAME)
+    .map(|def| format!("{} : {}", def.name, def.type_tag))
+    .sorted().join("\n")
 }
 
-use ReplResult::*;
+fn function_ir(i : &Interpreter, name : &str) -> Result<String, Error> {
+  let cs = &i.c.code_store;
+  let ir =
+    cs.types.values().flat_map(|types| types.symbols.values())
+    .filter(|def| def.name.as_ref() == name && cs.codegen_mapping.contains_key(&def.unit_id))
+    .flat_map(|def| {
+      let f = def.codegen_name()?;
+      cs.llvm_unit(def.unit_id).llvm_module.get_function(f)
+    })
+    .map(|f| f.print_to_string().to_string())
+    .join("\n");
+  if ir.is_empty() {
+    return error(TextLocation::zero(), format!("no compiled function named '{}'", name));
+  }
+  Ok(ir)
+}
 
-fn repl_eval(i : &mut Interpreter, code : &str) -> ReplResult {
-  match i.eval(code) {
-    Ok(e) => {
-      return Complete(e);
+/// Runs a command that starts with ':', and returns what it printed
+pub fn meta_command(i : &mut Interpreter, command : &str) -> Result<String, Error> {
+  let command = command.trim();
+  let (name, arg) = match command.find(char::is_whitespace) {
+    Some(n) => (&command[..n], command[n..].trim()),
+    None => (command, ""),
+  };
+  match (name, arg) {
+    (":type", code) => {
+      let imports = i.imports().to_vec();
+      let t = i.c.expression_type(code, &imports)?;
+      Ok(format!("{}", t))
+    }
+    (":ir", function) => function_ir(i, function),
+    (":units", "") => {
+      let cs = &i.c.code_store;
+      let units =
+        cs.names.iter().map(|(&uid, name)| match cs.source_path(uid) {
+          Some(path) => format!("{} ({})", name, path.display()),
+          None => format!("{}", name),
+        })
+        .sorted().join("\n");
+      Ok(units)
+    }
+    (":symbols", "") => {
+      let imports = i.imports().to_vec();
+      Ok(list_symbols(i, &imports))
+    }
+    (":symbols", unit) => {
+      let uid = find_unit(i, unit)?;
+      Ok(list_symbols(i, &[uid]))
+    }
+    (":load", path) => {
+      if Path::new(path).is_dir() {
+        i.load_package(path)?;
+        Ok(format!("{}", Val::Void))
+      }
+      else if i.c.code_store.unit_for_path(Path::new(path)).is_some() {
+        i.reload_file(Path::new(path))?;
+        Ok(format!("reloaded {}", path))
+      }
+      else {
+        Ok(format!("{}", i.run_file(path)?))
+      }
     }
-    Err(e) => {
-      if let ErrorContent::Message(m) = &e.message {
-        if m.as_str() == EXPECTED_TOKEN_ERROR {
-          return Incomplete;
+    (":reload", "") => {
+      let mut reloaded = 0;
+      for path in i.c.code_store.changed_source_files() {
+        if i.reload_file(&path)? {
+          reloaded += 1;
         }
       }
-      return Failed(e);
+      Ok(format!("reloaded {} modules", reloaded))
     }
+    (":unload", unit) => {
+      let uid = find_unit(i, unit)?;
+      let names : Vec<_> =
+        i.unload(uid).into_iter().map(|uid| i.c.code_store.name(uid).to_string()).collect();
+      Ok(format!("unloaded {}", names.into_iter().sorted().join(", ")))
+    }
+    (":help", "") => Ok(HELP.into()),
+    _ => error(TextLocation::zero(), format!("unknown command '{}', try :help", command)),
   }
 }
 
+/// History is kept in the home directory, so that it is shared between sessions
+fn history_path() -> PathBuf {
+  let home = env::var_os("HOME").map(PathBuf::from).unwrap_or_else(|| PathBuf::from("."));
+  home.join(HISTORY_FILE)
+}
+
 pub fn run_repl() {
-  let mut rl = Editor::<()>::new();
   let mut i = interpreter();
+  let completions = Rc::new(RefCell::new(Completions::from_interpreter(&i)));
+  let mut rl = Editor::<ReplHelper>::new();
+  rl.set_helper(Some(ReplHelper::new(completions.clone())));
+  let history = history_path();
+  let _ = rl.load_history(&history);
 
-  loop {
-    let mut input_line = rl.readline("repl> ").unwrap();
-
-    loop {
-      match repl_eval(&mut i, input_line.as_str()) {
-        Complete(val) => {
-          rl.add_history_entry(input_line);
-          println!("{:?}", val);
-          break;
-        }
-        Incomplete => {
-          // get more tokens
-          let next_line = rl.readline(". ").unwrap();
-          input_line.push_str("\n");
-          input_line.push_str(next_line.as_str());
-        }
-        Failed(e) => {
-          rl.add_history_entry(input_line);
-          println!("Error occured: {}", e.display());
-          break;
+  while let Ok(mut input_line) = rl.readline("repl> ") {
+    if input_line.trim_start().starts_with(":") {
+      match meta_command(&mut i, &input_line) {
+        Ok(s) => println!("{}", s),
+        Err(e) => println!("Error occured: {}", e.display()),
+      }
+    }
+    else {
+      // Keep reading lines until the brackets are balanced
+      while is_incomplete(&input_line, &i.c.cache) {
+        match rl.readline(". ") {
+          Ok(next_line) => {
+            input_line.push_str("\n");
+            input_line.push_str(next_line.as_str());
+          }
+          Err(_) => break,
         }
       }
+      match i.eval(&input_line) {
+        Ok(val) => println!("{}", val),
+        Err(e) => println!("Error occured: {}", e.display()),
+      }
     }
+    *completions.borrow_mut() = Completions::from_interpreter(&i);
+    rl.add_history_entry(input_line);
+    let _ = rl.save_history(&history);
   }
 }
@@ -0,0 +1,191 @@
+// Completion, highlighting and bracket matching for the repl's line editor
+
+use crate::{common, error, lexer, interpret, structure, types};
+use common::*;
+use error::TextMarker;
+use lexer::TokenType;
+use types::TypeContent;
+use interpret::Interpreter;
+use structure::TOP_LEVEL_FUNCTION_NAME;
+
+use rustyline::completion::{Completer, Pair};
+use rustyline::hint::Hinter;
+use rustyline::highlight::Highlighter;
+use rustyline::Helper;
+
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::iter;
+use std::rc::Rc;
+
+static KEYWORDS : &'static [&'static str] = &[
+  "if", "then", "else", "while", "for", "in", "struct", "union", "cbind", "fun", "with",
+  "static", "let", "type", "import", "as", "pub", "private", "return", "true", "false",
+];
+
+static KEYWORD_STYLE : &'static str = "\x1b[1;35m";
+static STRING_STYLE : &'static str = "\x1b[32m";
+static NUMBER_STYLE : &'static str = "\x1b[36m";
+static RESET_STYLE : &'static str = "\x1b[0m";
+
+/// The names that can be completed. These are taken from the interpreter after
+/// each line is evaluated, as the editor can't borrow it while reading a line.
+pub struct Completions {
+  symbols : Vec<RefStr>,
+
+  /// The fields of each global that has a struct type, for completing `global.field`
+  fields : HashMap<RefStr, Vec<RefStr>>,
+}
+
+impl Completions {
+
+  /// Finds the symbols that code evaluated by the interpreter can refer to. These include
+  /// cbinds, and the symbols of modules imported by the evaluated code.
+  pub fn from_interpreter(i : &Interpreter) -> Completions {
+    let cs = &i.c.code_store;
+    let mut units : HashSet<UnitId> = i.imports().iter().cloned().collect();
+    for uid in i.imports() {
+      units.extend(cs.get_imports(*uid));
+    }
+    let mut symbols = vec![];
+    let mut fields = HashMap::new();
+    for def in units.iter().flat_map(|uid| cs.types.get(uid)).flat_map(|t| t.symbols.values()) {
+      if def.name.as_ref() == TOP_LEVEL_FUNCTION_NAME {
+        continue;
+      }
+      symbols.push(def.name.clone());
+      let mut t = &def.type_tag;
+      while let Some(inner) = t.ptr() {
+        t = inner;
+      }
+      if let TypeContent::Def(name, uid) = &t.content {
+        if let Some(type_def) = cs.types.get(uid).and_then(|t| t.find_type_def(name)) {
+          let names = type_def.fields.iter().map(|(f, _)| f.name.clone()).collect();
+          fields.insert(def.name.clone(), names);
+        }
+      }
+    }
+    symbols.sort();
+    symbols.dedup();
+    Completions { symbols, fields }
+  }
+}
+
+pub struct ReplHelper {
+  completions : Rc<RefCell<Completions>>,
+  cache : StringCache,
+}
+
+impl ReplHelper {
+  pub fn new(completions : Rc<RefCell<Completions>>) -> ReplHelper {
+    ReplHelper { completions, cache: StringCache::new() }
+  }
+}
+
+fn is_identifier_char(c : char) -> bool {
+  c.is_alphanumeric() || c == '_'
+}
+
+/// Returns the identifier that ends at a byte offset
+fn identifier_before(line : &str, end : usize) -> &str {
+  let start =
+    line[..end].char_indices().rev()
+    .find(|(_, c)| !is_identifier_char(*c))
+    .map(|(i, c)| i + c.len_utf8())
+    .unwrap_or(0);
+  &line[start..end]
+}
+
+impl Completer for ReplHelper {
+  type Candidate = Pair;
+
+  fn complete(&self, line : &str, pos : usize) -> rustyline::Result<(usize, Vec<Pair>)> {
+    let word = identifier_before(line, pos);
+    let start = pos - word.len();
+    let completions = self.completions.borrow();
+    let mut names : Vec<&str> = vec![];
+    if line[..start].ends_with(".") {
+      // Fields, or functions called with method syntax
+      let container = identifier_before(line, start - 1);
+      if let Some(fields) = completions.fields.get(container) {
+        names.extend(fields.iter().map(|f| f.as_ref()));
+      }
+      names.extend(completions.symbols.iter().map(|s| s.as_ref()));
+    }
+    else {
+      names.extend(completions.symbols.iter().map(|s| s.as_ref()));
+      names.extend(KEYWORDS.iter().cloned());
+    }
+    let candidates =
+      names.into_iter()
+      .filter(|n| n.starts_with(word))
+      .map(|n| Pair { display: n.into(), replacement: n.into() })
+      .collect();
+    Ok((start, candidates))
+  }
+}
+
+impl Hinter for ReplHelper {}
+
+impl Highlighter for ReplHelper {
+  fn highlight<'l>(&self, line : &'l str, _pos : usize) -> Cow<'l, str> {
+    let tokens = match lexer::lex(no_source(), line, &self.cache) {
+      Ok(tokens) => tokens,
+      Err(_) => return Cow::Borrowed(line),
+    };
+    // Token locations are in characters, so they are converted into byte offsets
+    let char_offsets : Vec<usize> =
+      line.char_indices().map(|(i, _)| i).chain(iter::once(line.len())).collect();
+    let line_starts : Vec<usize> =
+      iter::once(0).chain(
+        line.chars().enumerate().filter(|(_, c)| *c == '\n').map(|(i, _)| i + 1))
+      .collect();
+    let offset = |m : TextMarker| char_offsets[line_starts[m.line - 1] + m.col];
+    let mut highlighted = String::new();
+    let mut end = 0;
+    for t in tokens.iter() {
+      let style = match t.token_type {
+        TokenType::Symbol => {
+          if KEYWORDS.contains(&t.to_string().as_str()) { KEYWORD_STYLE }
+          else { continue }
+        }
+        TokenType::StringLiteral => STRING_STYLE,
+        TokenType::FloatLiteral | TokenType::IntLiteral => NUMBER_STYLE,
+      };
+      let (start, token_end) = (offset(t.loc.start), offset(t.loc.end));
+      highlighted.push_str(&line[end..start]);
+      highlighted.push_str(style);
+      highlighted.push_str(&line[start..token_end]);
+      highlighted.push_str(RESET_STYLE);
+      end = token_end;
+    }
+    highlighted.push_str(&line[end..]);
+    Cow::Owned(highlighted)
+  }
+
+  fn highlight_char(&self, _line : &str, _pos : usize) -> bool {
+    true
+  }
+}
+
+impl Helper for ReplHelper {}
+
+/// Whether the code has brackets that haven't been closed yet, so that more lines
+/// should be read before evaluating it
+pub fn is_incomplete(code : &str, cache : &StringCache) -> bool {
+  let tokens = match lexer::lex(no_source(), code, cache) {
+    Ok(tokens) => tokens,
+    // Let evaluation report the error
+    Err(_) => return false,
+  };
+  let mut depth = 0;
+  for t in tokens.iter() {
+    match t.symbol().map(|s| s.as_ref()) {
+      Some("(") | Some("[") | Some("{") => depth += 1,
+      Some(")") | Some("]") | Some("}") => depth -= 1,
+      _ => (),
+    }
+  }
+  depth > 0
+}
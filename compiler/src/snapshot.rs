@@ -0,0 +1,468 @@
+// Serialises the global state of a unit to bytes, so that it can be written to
+// disk and restored into a compatible unit, even after a restart.
+//
+// A snapshot is a table of globals and a table of objects. Every global and every
+// allocation reachable from the globals is an object. Pointers inside objects are
+// replaced by the index of the object they point to, plus one, so that zero is null.
+
+use crate::{common, code_store, types, layout, structure, migrate, c_interface};
+use common::*;
+use code_store::CodeStore;
+use types::{Type, TypeContent, PType, SymbolInit};
+use layout::{type_layout, field_layouts, type_def};
+use structure::TypeKind;
+use migrate::MigrationReport;
+
+use std::collections::HashMap;
+use std::ptr;
+
+static MAGIC : &[u8] = b"CSNAPSHT";
+static VERSION : u64 = 1;
+
+/// Serialises every global in a unit, along with everything reachable from them.
+///
+/// Pointers are assumed to refer to the start of a live allocation, and values that are
+/// reachable in more than one way are only stored once. Function pointers can't outlive
+/// the code they point to, so they are stored as null. Unions are stored as they are,
+/// without following any pointers inside them.
+pub fn snapshot_globals(cs : &CodeStore, unit_id : UnitId) -> Vec<u8> {
+  let mut w = Writer { cs, objects: vec![], forwarded: HashMap::new() };
+  let lu = cs.llvm_unit(unit_id);
+  let mut globals = vec![];
+  for def in cs.types(unit_id).symbols.values() {
+    if let SymbolInit::Expression(_) = def.initialiser {
+      if let Some(address) = unsafe { lu.ee.get_global_address(&def.name) } {
+        let object = w.add_object(address as *const u8, &def.type_tag, 1);
+        globals.push((def.name.clone(), object));
+      }
+    }
+  }
+  let mut next = 0;
+  while next < w.objects.len() {
+    w.scan_object(next);
+    next += 1;
+  }
+  let mut out = vec![];
+  out.extend_from_slice(MAGIC);
+  write_u64(&mut out, VERSION);
+  write_u64(&mut out, globals.len() as u64);
+  for (name, object) in globals {
+    write_str(&mut out, &name);
+    write_u64(&mut out, object as u64);
+  }
+  write_u64(&mut out, w.objects.len() as u64);
+  for (o, _) in w.objects {
+    write_str(&mut out, &o.shape);
+    write_u64(&mut out, o.count as u64);
+    write_u64(&mut out, o.bytes.len() as u64);
+    out.extend_from_slice(&o.bytes);
+  }
+  out
+}
+
+/// Restores globals from a snapshot into the globals with the same names in a unit.
+/// A global is skipped if the layout of anything reachable from it has changed. If the
+/// snapshot is malformed, nothing is restored. Restored allocations are made with `malloc`, so the program can free them as usual.
+pub fn restore_globals(cs : &CodeStore, unit_id : UnitId, snapshot : &[u8])
+  -> Result<MigrationReport, String>
+{
+  let mut r = Reader { data: snapshot, pos: 0 };
+  if r.bytes(MAGIC.len())? != MAGIC {
+    return Err("not a snapshot".into());
+  }
+  let version = r.u64()?;
+  if version != VERSION {
+    return Err(format!("unsupported snapshot version {}", version));
+  }
+  let mut globals = vec![];
+  for _ in 0..r.u64()? {
+    let name = r.string()?;
+    let object = r.u64()? as usize;
+    globals.push((name, object));
+  }
+  let mut objects = vec![];
+  for _ in 0..r.u64()? {
+    let shape = r.string()?;
+    let count = r.u64()? as usize;
+    let length = r.u64()? as usize;
+    let bytes = r.bytes(length)?.to_vec();
+    objects.push(Object { shape, count, bytes });
+  }
+  let lu = cs.llvm_unit(unit_id);
+  let mut report = MigrationReport::default();
+  // Globals are only written once every object has been checked, so that a malformed
+  // snapshot doesn't leave the unit half-restored
+  let mut writes = vec![];
+  let mut allocations = vec![];
+  for (name, object) in globals {
+    let def = cs.types(unit_id).symbols.values().find(|def| {
+      def.name.as_ref() == name.as_str() &&
+        if let SymbolInit::Expression(_) = def.initialiser { true } else { false }
+    });
+    let def = if let Some(def) = def { def } else { continue };
+    let address = unsafe { lu.ee.get_global_address(&def.name) };
+    let mut l = Loader { cs, objects: &objects, restored: HashMap::new(), allocations: vec![] };
+    let restored = match address {
+      Some(address) => l.restore_global(object, &def.type_tag).map(|bytes| {
+        bytes.map(|bytes| (address as *mut u8, bytes))
+      }),
+      None => Ok(None),
+    };
+    match restored {
+      Ok(Some(write)) => {
+        writes.push(write);
+        allocations.extend(l.allocations);
+        report.migrated.push(def.name.clone());
+      }
+      Ok(None) => {
+        free_all(&l.allocations);
+        report.skipped.push(def.name.clone());
+      }
+      Err(e) => {
+        free_all(&l.allocations);
+        free_all(&allocations);
+        return Err(e);
+      }
+    }
+  }
+  for (dest, bytes) in writes {
+    unsafe { ptr::copy_nonoverlapping(bytes.as_ptr(), dest, bytes.len()) };
+  }
+  Ok(report)
+}
+
+fn free_all(allocations : &[*mut u8]) {
+  for &p in allocations {
+    unsafe { c_interface::free(p) };
+  }
+}
+
+struct Object {
+  /// Describes the layout of the object's elements, so that a snapshot is
+  /// only restored into types with the same layout
+  shape : String,
+  count : usize,
+  bytes : Vec<u8>,
+}
+
+/// Where a type stores a pointer to a buffer of elements, and the number of elements in it.
+/// Only that many elements are stored. If the buffer has room for more, which aren't
+/// initialised, its capacity is stored as an ordinary field, and the restored buffer is
+/// allocated with the same capacity.
+struct Buffer {
+  data_offset : usize,
+  count_offset : usize,
+  capacity_offset : Option<usize>,
+  element_type : Type,
+}
+
+impl Buffer {
+  /// The number of elements there is room for, given the bytes of the value holding the buffer
+  fn capacity(&self, bytes : &[u8], count : usize) -> usize {
+    match self.capacity_offset {
+      Some(offset) => read_u64(bytes, offset) as usize,
+      None => count,
+    }
+  }
+}
+
+/// The library types that point to more than one element. Any other pointer
+/// is assumed to point to a single value.
+fn buffer(cs : &CodeStore, t : &Type) -> Option<Buffer> {
+  if let TypeContent::Def(name, _) = &t.content {
+    match name.as_ref() {
+      // These both have the layout { data : ptr(T), length : u64 }
+      "array" => return Some(Buffer {
+        data_offset: 0, count_offset: 8, capacity_offset: None,
+        element_type: t.children()[0].clone(),
+      }),
+      "string" => return Some(Buffer {
+        data_offset: 0, count_offset: 8, capacity_offset: None,
+        element_type: PType::U8.into(),
+      }),
+      "inner_list" => {
+        let fields = field_layouts(cs, t);
+        let offset = |n : &str| fields.iter().find(|f| f.name.as_ref() == n).map(|f| f.offset);
+        return Some(Buffer {
+          data_offset: offset("data")?,
+          count_offset: offset("len")?,
+          capacity_offset: Some(offset("capacity")?),
+          element_type: t.children()[0].clone(),
+        });
+      }
+      _ => (),
+    }
+  }
+  None
+}
+
+/// A description of a type's layout. Pointers are described by the type they point
+/// to, but not its layout, which is described by the object that they point to.
+fn shape(cs : &CodeStore, t : &Type) -> String {
+  match &t.content {
+    TypeContent::Def(_, _) => {
+      let def = type_def(cs, t).unwrap();
+      let kind = if let TypeKind::Struct = def.kind { "struct" } else { "union" };
+      let fields : Vec<String> =
+        field_layouts(cs, t).iter().map(|f| format!("{}:{}", f.name, shape(cs, &f.t))).collect();
+      format!("{} {}{{{}}}", kind, t, fields.join(","))
+    }
+    _ => format!("{}", t),
+  }
+}
+
+fn write_u64(out : &mut Vec<u8>, v : u64) {
+  out.extend_from_slice(&v.to_le_bytes());
+}
+
+fn read_u64(bytes : &[u8], offset : usize) -> u64 {
+  let mut b = [0u8; 8];
+  b.copy_from_slice(&bytes[offset..offset + 8]);
+  u64::from_le_bytes(b)
+}
+
+fn write_str(out : &mut Vec<u8>, s : &str) {
+  write_u64(out, s.len() as u64);
+  out.extend_from_slice(s.as_bytes());
+}
+
+struct Writer<'l> {
+  cs : &'l CodeStore,
+  objects : Vec<(Object, Type)>,
+
+  /// Map from the address of each stored allocation to its object index
+  forwarded : HashMap<usize, usize>,
+}
+
+impl <'l> Writer<'l> {
+  fn add_object(&mut self, p : *const u8, element_type : &Type, count : usize) -> usize {
+    if let Some(&i) = self.forwarded.get(&(p as usize)) {
+      return i;
+    }
+    let size = type_layout(self.cs, element_type).size;
+    let bytes = unsafe { std::slice::from_raw_parts(p, size * count) }.to_vec();
+    let shape = shape(self.cs, element_type);
+    self.forwarded.insert(p as usize, self.objects.len());
+    self.objects.push((Object { shape, count, bytes }, element_type.clone()));
+    self.objects.len() - 1
+  }
+
+  fn scan_object(&mut self, i : usize) {
+    let (count, t) = {
+      let (o, t) = &self.objects[i];
+      (o.count, t.clone())
+    };
+    let size = type_layout(self.cs, &t).size;
+    for e in 0..count {
+      self.scan(i, e * size, &t);
+    }
+  }
+
+  /// Replaces the pointers in the value at `offset` in an object with object indices
+  fn scan(&mut self, i : usize, offset : usize, t : &Type) {
+    match &t.content {
+      TypeContent::Ptr => {
+        let p = self.read_ptr(i, offset);
+        let v = if p == 0 { 0 } else { self.add_object(p as *const u8, t.ptr().unwrap(), 1) + 1 };
+        self.write(i, offset, v as u64);
+      }
+      TypeContent::Fun => self.write(i, offset, 0),
+      TypeContent::Def(_, _) => {
+        if let Some(b) = buffer(self.cs, t) {
+          let p = self.read_ptr(i, offset + b.data_offset);
+          let count = self.read_ptr(i, offset + b.count_offset);
+          let capacity = b.capacity(&self.objects[i].0.bytes[offset..], count);
+          // An empty buffer with room for elements is kept, as its capacity says it exists
+          let v =
+            if p == 0 || capacity == 0 { 0 }
+            else { self.add_object(p as *const u8, &b.element_type, count) + 1 };
+          self.write(i, offset + b.data_offset, v as u64);
+          for f in field_layouts(self.cs, t) {
+            if f.offset != b.data_offset {
+              self.scan(i, offset + f.offset, &f.t);
+            }
+          }
+        }
+        else if let TypeKind::Struct = type_def(self.cs, t).unwrap().kind {
+          for f in field_layouts(self.cs, t) {
+            self.scan(i, offset + f.offset, &f.t);
+          }
+        }
+      }
+      _ => (),
+    }
+  }
+
+  fn read_ptr(&self, i : usize, offset : usize) -> usize {
+    read_u64(&self.objects[i].0.bytes, offset) as usize
+  }
+
+  fn write(&mut self, i : usize, offset : usize, v : u64) {
+    self.objects[i].0.bytes[offset..offset + 8].copy_from_slice(&v.to_le_bytes());
+  }
+}
+
+struct Reader<'l> {
+  data : &'l [u8],
+  pos : usize,
+}
+
+impl <'l> Reader<'l> {
+  fn bytes(&mut self, n : usize) -> Result<&'l [u8], String> {
+    if self.pos + n > self.data.len() {
+      return Err("snapshot was truncated".into());
+    }
+    let b = &self.data[self.pos..self.pos + n];
+    self.pos += n;
+    Ok(b)
+  }
+
+  fn u64(&mut self) -> Result<u64, String> {
+    let mut b = [0u8; 8];
+    b.copy_from_slice(self.bytes(8)?);
+    Ok(u64::from_le_bytes(b))
+  }
+
+  fn string(&mut self) -> Result<String, String> {
+    let n = self.u64()? as usize;
+    String::from_utf8(self.bytes(n)?.to_vec()).map_err(|_| "invalid string in snapshot".into())
+  }
+}
+
+struct Loader<'l> {
+  cs : &'l CodeStore,
+  objects : &'l [Object],
+
+  /// Map from object index to the address it was restored to, and the number of
+  /// elements there is room for
+  restored : HashMap<usize, (*mut u8, usize)>,
+
+  /// Everything allocated so far, to be freed if the restore is abandoned
+  allocations : Vec<*mut u8>,
+}
+
+impl <'l> Loader<'l> {
+  /// Checks that an object exists and that its bytes hold its elements. Returns
+  /// None if it doesn't match the type it is restored as.
+  fn object(&self, i : usize, element_type : &Type) -> Result<Option<&'l Object>, String> {
+    let o = self.objects.get(i).ok_or_else(|| format!("snapshot object {} doesn't exist", i))?;
+    if o.shape != shape(self.cs, element_type) {
+      return Ok(None);
+    }
+    let size = type_layout(self.cs, element_type).size;
+    if size.checked_mul(o.count) != Some(o.bytes.len()) {
+      return Err(format!(
+        "snapshot object {} has {} bytes, which don't hold {} elements of {} bytes",
+        i, o.bytes.len(), o.count, size));
+    }
+    Ok(Some(o))
+  }
+
+  /// Restores an object as the value of a global. Returns the bytes to write to the
+  /// global, or None if the object doesn't match the global's type.
+  fn restore_global(&mut self, i : usize, t : &Type) -> Result<Option<Vec<u8>>, String> {
+    let o = match self.object(i, t)? { Some(o) => o, None => return Ok(None) };
+    if o.count != 1 {
+      return Err(format!("snapshot object {} is a global, but has {} elements", i, o.count));
+    }
+    let mut bytes = o.bytes.clone();
+    if !self.fix_pointers(&mut bytes, 0, t)? {
+      return Ok(None);
+    }
+    Ok(Some(bytes))
+  }
+
+  /// Restores an object into a new allocation with room for `capacity` elements, where
+  /// the first `count` elements are used. Returns its address, or None if the object
+  /// doesn't match the type it is restored as.
+  fn restore(&mut self, i : usize, element_type : &Type, count : usize, capacity : usize)
+    -> Result<Option<*mut u8>, String>
+  {
+    let o = match self.object(i, element_type)? { Some(o) => o, None => return Ok(None) };
+    if count > o.count || count > capacity {
+      return Err(format!(
+        "snapshot object {} has {} elements, but is used as {} elements with room for {}",
+        i, o.count, count, capacity));
+    }
+    let capacity = capacity.max(o.count);
+    if let Some(&(p, room)) = self.restored.get(&i) {
+      if capacity > room {
+        return Err(format!("snapshot object {} is used with different capacities", i));
+      }
+      return Ok(Some(p));
+    }
+    let size = type_layout(self.cs, element_type).size;
+    let bytes_needed = size.checked_mul(capacity)
+      .ok_or_else(|| format!("snapshot object {} is too large", i))?;
+    let p = unsafe { c_interface::malloc(bytes_needed.max(1)) };
+    if p.is_null() {
+      return Err(format!("could not allocate {} bytes to restore a snapshot", bytes_needed));
+    }
+    self.allocations.push(p);
+    // Filled in before the pointers, so that cycles point back to it
+    self.restored.insert(i, (p, capacity));
+    let mut bytes = o.bytes.clone();
+    for e in 0..o.count {
+      if !self.fix_pointers(&mut bytes, e * size, element_type)? {
+        return Ok(None);
+      }
+    }
+    unsafe { ptr::copy_nonoverlapping(bytes.as_ptr(), p, bytes.len()) };
+    Ok(Some(p))
+  }
+
+  /// Replaces the object index at `offset` with a pointer to the restored object
+  fn fix_pointer(
+    &mut self, bytes : &mut [u8], offset : usize, element_type : &Type, count : usize, capacity : usize)
+      -> Result<bool, String>
+  {
+    let index = read_u64(bytes, offset) as usize;
+    let p =
+      if index == 0 { Some(ptr::null_mut()) }
+      else { self.restore(index - 1, element_type, count, capacity)? };
+    match p {
+      Some(p) => {
+        bytes[offset..offset + 8].copy_from_slice(&(p as u64).to_le_bytes());
+        Ok(true)
+      }
+      None => Ok(false),
+    }
+  }
+
+  /// Replaces the object indices in the value at `offset` with pointers
+  fn fix_pointers(&mut self, bytes : &mut [u8], offset : usize, t : &Type) -> Result<bool, String> {
+    match &t.content {
+      TypeContent::Ptr => self.fix_pointer(bytes, offset, t.ptr().unwrap(), 1, 1),
+      TypeContent::Def(_, _) => {
+        if let Some(b) = buffer(self.cs, t) {
+          let count = read_u64(bytes, offset + b.count_offset) as usize;
+          let capacity = b.capacity(&bytes[offset..], count);
+          if count > 0 && read_u64(bytes, offset + b.data_offset) == 0 {
+            return Err(format!("snapshot has a buffer of {} elements with no data", count));
+          }
+          if !self.fix_pointer(bytes, offset + b.data_offset, &b.element_type, count, capacity)? {
+            return Ok(false);
+          }
+          for f in field_layouts(self.cs, t) {
+            if f.offset != b.data_offset && !self.fix_pointers(bytes, offset + f.offset, &f.t)? {
+              return Ok(false);
+            }
+          }
+          Ok(true)
+        }
+        else if let TypeKind::Struct = type_def(self.cs, t).unwrap().kind {
+          for f in field_layouts(self.cs, t) {
+            if !self.fix_pointers(bytes, offset + f.offset, &f.t)? {
+              return Ok(false);
+            }
+          }
+          Ok(true)
+        }
+        else {
+          Ok(true)
+        }
+      }
+      _ => Ok(true),
+    }
+  }
+}
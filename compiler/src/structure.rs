@@ -4,7 +4,7 @@ use crate::error::{Error, error, TextLocation};
 use crate::expr::{Expr, ExprContent};
 use crate::intrinsics::UNSAFE_ZERO_INIT;
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
 pub static TOP_LEVEL_FUNCTION_NAME : &'static str = "__top_level";
 
@@ -54,6 +54,10 @@ pub enum Content {
   // TODO: this is not yet implemented
   TypeAlias{ alias: Box<Expr>, type_aliased: Box<Expr> },
 
+  /// Imports the module with this name. Without a namespace its symbols are
+  /// found unqualified, otherwise they are referred to as `namespace.symbol`.
+  Import{ path: RefStr, namespace: Option<RefStr> },
+
   // TODO: this could probably be a generic intrinsic instead
   Assignment{ assignee: NodeId , value: NodeId },
 
@@ -122,6 +126,15 @@ pub struct NodeConverter<'l> {
 
   symbols : HashMap<ReferenceId, Reference>,
 
+  /// Namespaces imported so far, so that qualified names can be recognised
+  namespaces : HashSet<RefStr>,
+
+  public : HashSet<NodeId>,
+  public_fields : HashSet<ReferenceId>,
+
+  /// The top-level `private` statements, which make unmarked definitions private
+  private_statements : HashSet<TextLocation>,
+
   cache: &'l StringCache,
 }
 
@@ -135,9 +148,29 @@ pub struct Nodes {
   pub nodes : HashMap<NodeId, Node>,
   pub symbols : HashMap<ReferenceId, Reference>,
   pub root : NodeId,
+
+  /// Definitions marked `pub`
+  pub public : HashSet<NodeId>,
+
+  /// Struct and union fields marked `pub`
+  pub public_fields : HashSet<ReferenceId>,
+
+  /// Set by a top-level `private` statement. Otherwise everything is public,
+  /// whether or not it is marked `pub`.
+  pub private_by_default : bool,
 }
 
 impl Nodes {
+  /// Whether a definition is visible to the modules that import this one
+  pub fn is_public(&self, id : NodeId) -> bool {
+    !self.private_by_default || self.public.contains(&id)
+  }
+
+  /// Whether a field is visible to the modules that import this one
+  pub fn is_public_field(&self, field : &Reference) -> bool {
+    !self.private_by_default || self.public_fields.contains(&field.id)
+  }
+
   pub fn node(&self, id : NodeId) -> &Node {
     self.nodes.get(&id).unwrap()
   }
@@ -185,11 +218,24 @@ pub fn to_nodes(
     uid_generator,
     nodes: HashMap::new(),
     symbols: HashMap::new(),
+    namespaces: HashSet::new(),
+    public: HashSet::new(),
+    public_fields: HashSet::new(),
+    private_statements: HashSet::new(),
     cache,
   };
+  for e in expr.children() {
+    if let Some(("private", [])) = e.try_construct() {
+      nc.private_statements.insert(e.loc);
+    }
+  }
+  let private_by_default = !nc.private_statements.is_empty();
   let mut fc = FunctionConverter::new(&mut nc, vec![]);
   let top_level = fc.top_level_expression(expr)?;
-  Ok(Nodes{ root: top_level, nodes: nc.nodes, symbols: nc.symbols })
+  Ok(Nodes{
+    root: top_level, nodes: nc.nodes, symbols: nc.symbols,
+    public: nc.public, public_fields: nc.public_fields, private_by_default,
+  })
 }
 
 impl <'l> NodeConverter<'l> {
@@ -245,10 +291,76 @@ impl <'l, 'lt> FunctionConverter<'l, 'lt> {
     }
   }
 
+  /// Converts a struct or union field, which may be marked `pub`
+  fn field(&mut self, e : &Expr) -> Result<(Reference, Option<Box<Expr>>), Error> {
+    if let Some(("pub", [e])) = e.try_construct() {
+      let field = self.typed_symbol(e)?;
+      self.t.public_fields.insert(field.0.id);
+      return Ok(field);
+    }
+    self.typed_symbol(e)
+  }
+
+  fn public_to_node(&mut self, expr : &Expr, e : &Expr) -> Result<NodeId, Error> {
+    let id = self.to_node(e)?;
+    let is_definition = match &self.t.nodes.get(&id).unwrap().content {
+      FunctionDefinition{..} | CBind{..} | TypeDefinition{..} => true,
+      VariableInitialise{ var_scope: VarScope::Global(_), .. } => true,
+      _ => false,
+    };
+    if !is_definition {
+      return error(expr, "only functions, types and globals can be marked 'pub'");
+    }
+    self.t.public.insert(id);
+    Ok(id)
+  }
+
   fn cached(&self, s : &str) -> RefStr {
     self.t.cache.get(s)
   }
 
+  fn is_namespace(&self, name : &str) -> bool {
+    self.t.namespaces.contains(name) && self.find_var(name).is_none()
+  }
+
+  /// Returns the qualified name for expressions of the form `namespace.name`
+  fn qualified_name(&self, e : &Expr) -> Option<RefStr> {
+    if let Some((".", [namespace, name])) = e.try_construct() {
+      let (namespace, name) = (namespace.try_symbol()?, name.try_symbol()?);
+      if self.is_namespace(namespace) {
+        return Some(self.cached(&format!("{}.{}", namespace, name)));
+      }
+    }
+    None
+  }
+
+  /// Definitions can't share a name with a namespace, as references to them would be ambiguous
+  fn check_not_namespace(&self, e : &Expr, name : &str) -> Result<(), Error> {
+    if self.t.namespaces.contains(name) {
+      return error(e, format!("'{}' is already the name of an imported namespace", name));
+    }
+    Ok(())
+  }
+
+  fn import_to_node(&mut self, expr : &Expr, e : &Expr) -> Result<NodeId, Error> {
+    let (path, namespace) = match (&e.content, e.try_construct()) {
+      (ExprContent::LiteralString(path), _) => (path.as_str(), None),
+      (_, Some(("as", [path, namespace]))) => match &path.content {
+        ExprContent::LiteralString(path) => (path.as_str(), Some(namespace.unwrap_symbol()?)),
+        _ => return error(expr, "expected an import of the form 'import \"module\" as name'"),
+      }
+      _ => return error(expr, "expected an import of the form 'import \"module\" as name'"),
+    };
+    let path = self.cached(path);
+    let namespace = namespace.map(|ns| self.cached(ns));
+    if let Some(ns) = &namespace {
+      if !self.t.namespaces.insert(ns.clone()) {
+        return error(expr, format!("namespace '{}' is imported more than once", ns));
+      }
+    }
+    Ok(self.node(expr, Import{ path, namespace }))
+  }
+
   fn compile_template_arguments(&mut self, e : &Expr, args : &mut Vec<NodeId>) -> Result<(), Error> {
     match e.try_construct() {
       Some(("$", [e])) => {
@@ -297,7 +409,10 @@ impl <'l, 'lt> FunctionConverter<'l, 'lt> {
     }
     let name_expr = &exprs[0];
     let field_exprs = &exprs[1..];
-    let name = self.expr_to_symbol(name_expr)?;
+    let name = match self.qualified_name(name_expr) {
+      Some(name) => self.t.symbol(&name, name_expr.loc),
+      None => self.expr_to_symbol(name_expr)?,
+    };
     let field_values =
       field_exprs.iter().map(|e| {
         if let Some((":", [name, value])) = e.try_construct() {
@@ -324,6 +439,7 @@ impl <'l, 'lt> FunctionConverter<'l, 'lt> {
   )
     -> Result<NodeId, Error>
   {
+    self.check_not_namespace(expr, name.unwrap_symbol()?)?;
     let name = self.cached(name.unwrap_symbol()?);
     let args =
       args.children().iter()
@@ -366,6 +482,18 @@ impl <'l, 'lt> FunctionConverter<'l, 'lt> {
           }
           _ => (),
         }
+        // Method call syntax puts the namespace of a qualified call first
+        if let Some(namespace) = exprs.get(1).and_then(|e| e.try_symbol()) {
+          if self.is_namespace(namespace) {
+            let name = function_expr.unwrap_symbol()?;
+            let name = self.cached(&format!("{}.{}", namespace, name));
+            let args =
+              exprs[2..].iter().map(|e| self.to_node(e))
+              .collect::<Result<Vec<NodeId>, Error>>()?;
+            let function = self.node(function_expr, Content::Reference{ name, refers_to: None });
+            return Ok(self.node(expr, FunctionCall{ function, args }));
+          }
+        }
         let args =
           exprs[1..].iter().map(|e| self.to_node(e))
           .collect::<Result<Vec<NodeId>, Error>>()?;
@@ -381,6 +509,7 @@ impl <'l, 'lt> FunctionConverter<'l, 'lt> {
       ("static", [e]) => {
         if let Some(("=", [name_expr, value_expr])) = e.try_construct() {
           let (name, type_tag) = self.typed_symbol(name_expr)?;
+          self.check_not_namespace(expr, &name.name)?;
           let value = self.to_node(value_expr)?;
           let var_scope = VarScope::Global(GlobalType::Normal);
           let c = VariableInitialise { name, type_tag, value, var_scope };
@@ -408,6 +537,18 @@ impl <'l, 'lt> FunctionConverter<'l, 'lt> {
         }
         error(expr, "malformed let expression")
       }
+      ("import", [e]) => {
+        self.import_to_node(expr, e)
+      }
+      ("pub", [e]) => {
+        self.public_to_node(expr, e)
+      }
+      ("private", []) => {
+        if !self.t.private_statements.contains(&expr.loc) {
+          return error(expr, "'private' can only be used at the top level of a module");
+        }
+        Ok(self.node(expr, Literal(PrimitiveVal::Void)))
+      }
       ("#", [quoted_expr]) => {
         self.quote_to_node(expr, quoted_expr)
       }
@@ -466,6 +607,7 @@ impl <'l, 'lt> FunctionConverter<'l, 'lt> {
       }
       ("cbind", [e]) => {
         if let (":", [name_expr, type_expr]) = e.unwrap_construct()? {
+          self.check_not_namespace(expr, name_expr.unwrap_symbol()?)?;
           let name = self.cached(name_expr.unwrap_symbol()?);
           let type_tag = type_expr.clone().into();
           return Ok(self.node(expr, CBind{ name, type_tag }));
@@ -497,10 +639,11 @@ impl <'l, 'lt> FunctionConverter<'l, 'lt> {
         }
       }
       ("union", [name, fields_expr]) => {
+        self.check_not_namespace(expr, name.unwrap_symbol()?)?;
         let name = self.cached(name.unwrap_symbol()?);
         let fields =
           fields_expr.children().iter()
-          .map(|e| self.typed_symbol(e))
+          .map(|e| self.field(e))
           .collect::<Result<Vec<_>, Error>>()?;
         let td = TypeDefinition{name, kind: TypeKind::Union, fields, type_vars: vec![] };
         Ok(self.node(expr, td))
@@ -518,13 +661,17 @@ impl <'l, 'lt> FunctionConverter<'l, 'lt> {
             (name, vec![])
           }
         };
+        self.check_not_namespace(expr, &name)?;
         let fields =
           fields_expr.children().iter()
-          .map(|e| self.typed_symbol(e))
+          .map(|e| self.field(e))
           .collect::<Result<Vec<_>, Error>>()?;
         Ok(self.node(expr, TypeDefinition{name, kind: TypeKind::Struct, fields, type_vars }))
       }
       (".", [container_expr, field_expr]) => {
+        if let Some(name) = self.qualified_name(expr) {
+          return Ok(self.node(expr, Content::Reference{ name, refers_to: None }));
+        }
         let container = self.to_node(container_expr)?;
         let field = self.expr_to_symbol(field_expr)?;
         let c = FieldAccess{ container, field };
@@ -547,6 +694,17 @@ impl <'l, 'lt> FunctionConverter<'l, 'lt> {
         }
         error(expr, "malformed index expression")
       }
+      ("in", [value_expr, region_expr]) => {
+        // `alloc(v) in r` allocates `v` inside region `r`
+        if let Some(("call", [f, v])) = value_expr.try_construct() {
+          if f.try_symbol() == Some("alloc") {
+            let region = self.to_node(region_expr)?;
+            let v = self.to_node(v)?;
+            return Ok(self.function_call(expr, "alloc", vec![region, v]));
+          }
+        }
+        error(expr, "expected expression of the form 'alloc(value) in region'")
+      }
       (construct, _) => {
         error(expr, format!("invalid '{}' expression", construct))
       }
@@ -1,9 +1,10 @@
 
-use crate::error::Error;
+use crate::error::{Error, ErrorContent};
 use crate::interpret::{Interpreter, interpreter};
 use crate::structure::TOP_LEVEL_FUNCTION_NAME;
 use crate::compiler::Val;
 use crate::c_interface::SStr;
+use crate::{snapshot, node_graph, repl, repl_helper, embed, watcher};
 
 fn result_string(r : Result<Val, Error>) -> String {
   match r {
@@ -223,6 +224,16 @@ rusty_fork_test! {
       (*&a).content.data.literal_int
     ";
     assert_result(b, Val::I64(5));
+    // Unions are read as raw bytes, so a pointer field is never followed
+    let c = "
+      union baz {
+        u : u64
+        s : ptr(string)
+      }
+      baz.new(u : 16 as u64)
+    ";
+    let bytes = [16, 0, 0, 0, 0, 0, 0, 0].iter().map(|&b| Val::U8(b)).collect();
+    assert_result(c, Val::Struct("baz".into(), vec![("bytes".into(), Val::Array(bytes))]));
   }
 
   #[test]
@@ -357,6 +368,188 @@ rusty_fork_test! {
     assert_result(code, Val::I64(61));
   }
 
+  #[test]
+  fn test_rich_values() {
+    assert_result("\"hello\"", Val::String("hello".into()));
+    assert_result("[1, 2, 3]", Val::Array(vec![Val::I64(1), Val::I64(2), Val::I64(3)]));
+    let code = "
+      struct point {
+        x : i64
+        y : f32
+      }
+      [point.new(x: 1, y: 2.5), point.new(x: 3, y: 4.5)]
+    ";
+    let mut i = interpreter();
+    let val = i.eval(code).unwrap();
+    let point = |x, y| Val::Struct("point".into(), vec![("x".into(), Val::I64(x)), ("y".into(), Val::F32(y))]);
+    assert_eq!(val, Val::Array(vec![point(1, 2.5), point(3, 4.5)]));
+    assert_eq!(format!("{}", val), "[point { x: 1, y: 2.5 }, point { x: 3, y: 4.5 }]");
+    match i.eval("malloc(8)").unwrap() {
+      Val::Ptr(p) => assert!(p != 0),
+      v => panic!("expected a pointer, found {:?}", v),
+    }
+  }
+
+  #[test]
+  fn test_embedding() {
+    #[repr(C)]
+    struct Point {
+      x : i64,
+      y : f32,
+    }
+    script_struct!(Point, "point", x : i64, y : f32);
+    let code = "
+      struct point {
+        x : i64
+        y : f32
+      }
+      fun add(a : i64, b : i64) { a + b }
+      fun half(a : f32) { a * 0.5 }
+      fun half(a : i64) { a / 2 }
+      fun sum(p : ptr(point)) { p.x + (p.y as i64) }
+      fun read(p : ptr(i64)) { *p }
+    ";
+    let mut i = interpreter();
+    i.run_module(code, "game").unwrap();
+    let uid = i.c.code_store.named_unit("game").unwrap();
+    let add : embed::ScriptFunction<(i64, i64), i64> = embed::get_function(&i.c, uid, "add").unwrap();
+    assert_eq!(add.call(2, 3).unwrap(), 5);
+    let half : embed::ScriptFunction<(f32,), f32> = embed::get_function(&i.c, uid, "half").unwrap();
+    assert_eq!(half.call(3.0).unwrap(), 1.5);
+    let half : embed::ScriptFunction<(i64,), i64> = embed::get_function(&i.c, uid, "half").unwrap();
+    assert_eq!(half.call(9).unwrap(), 4);
+    let mut p = Point { x: 4, y: 2.0 };
+    let sum : embed::ScriptFunction<(*mut Point,), i64> = embed::get_function(&i.c, uid, "sum").unwrap();
+    assert_eq!(sum.call(&mut p).unwrap(), 6);
+    // Calls are guarded, so a crash is returned as an error
+    let read : embed::ScriptFunction<(*const i64,), i64> = embed::get_function(&i.c, uid, "read").unwrap();
+    assert!(read.call(std::ptr::null()).is_err());
+    assert!(embed::get_function::<(f64,), f64>(&i.c, uid, "half").is_err());
+    assert!(embed::get_function::<(), i64>(&i.c, uid, "missing").is_err());
+  }
+
+  #[test]
+  fn test_register_fn() {
+    extern "C" fn scale(a : f64, n : i64) -> f64 { a * (n as f64) }
+    extern "C" fn scale_i64(a : i64, n : i64) -> i64 { a * n }
+    extern "C" fn string_length(s : &SStr) -> u64 { s.as_str().len() as u64 }
+    let mut i = interpreter();
+    i.c.register_fn("scale", scale as extern "C" fn(f64, i64) -> f64);
+    i.c.register_fn("string_length", string_length as extern "C" fn(&'static SStr) -> u64);
+    assert_result_with_interpreter(&mut i, "scale(1.5, 4)", Val::F64(6.0));
+    assert_result_with_interpreter(&mut i, "let s = \"hello\"\n string_length(&s)", Val::U64(5));
+    assert!(i.eval("scale(true, 4)").is_err());
+    // Registering a name again leaves loaded modules working, and new ones see the new type
+    let (lib, _) = i.c.load_module("fun use_scale() { scale(1.5, 2) }", Some("lib"), &[]).unwrap();
+    i.c.register_fn("scale", scale_i64 as extern "C" fn(i64, i64) -> i64);
+    let (_, val) = i.c.load_module("scale(3, 2) + (use_scale() as i64)", None, &[lib]).unwrap();
+    assert_eq!(val, Val::I64(9));
+    assert!(i.eval("scale(1.5, 2)").is_err());
+  }
+
+  #[test]
+  fn test_guarded_execution() {
+    let mut i = interpreter();
+    let fault = |e : Error| match e.message {
+      ErrorContent::Fault { message, function, .. } => (message, function.to_string()),
+      m => panic!("expected a fault, found {:?}", m),
+    };
+    let e = i.eval("let p = 0 as u64 as ptr(i64)\n*p").unwrap_err();
+    assert_eq!(fault(e), ("segmentation fault".into(), TOP_LEVEL_FUNCTION_NAME.into()));
+    let code = "
+      cbind panic : fun(s : ptr(string))
+      fun fail() {
+        let s = \"boom\"
+        panic(&s)
+      }
+      fail()
+    ";
+    let e = i.eval(code).unwrap_err();
+    assert_eq!(fault(e), ("panicked: boom".into(), "fail".into()));
+    // The compiler can still be used after a fault
+    assert_result_with_interpreter(&mut i, "3 + 4", Val::I64(7));
+  }
+
+  #[test]
+  fn test_fault_stack_trace() {
+    let code = "
+      cbind panic : fun(s : ptr(string))
+      fun fail(t : T) with T {
+        let s = \"boom\"
+        panic(&s)
+      }
+      fun outer() {
+        fail(5)
+      }
+      outer()
+    ";
+    let e = interpreter().eval(code).unwrap_err();
+    match e.message {
+      ErrorContent::Fault { function, unit, trace, .. } => {
+        assert_eq!(function.as_ref(), "fail");
+        assert!(unit.starts_with("@poly["));
+        let names : Vec<&str> = trace.iter().map(|f| f.function.as_ref()).collect();
+        assert_eq!(names, vec!["fail", "outer", TOP_LEVEL_FUNCTION_NAME]);
+        // Each frame is located at the call it was making
+        assert_eq!(trace[0].location.start.line, 5);
+        assert_eq!(trace[1].location.start.line, 8);
+        assert_eq!(trace[2].location.start.line, 10);
+      }
+      m => panic!("expected a fault, found {:?}", m),
+    }
+  }
+
+  #[test]
+  fn test_debug_info() {
+    let mut i = interpreter();
+    i.c.debug_info = true;
+    let code = "
+      struct node {
+        value : i64
+        next : ptr(node)
+      }
+      fun sum(n : ptr(node), count : i64) => i64 {
+        if count == 0 { 0 }
+        else { n.value + sum(n.next, count - 1) }
+      }
+      fun first(a : T, b : T) => T with T { a }
+      let last = node.new(3, 0 as u64 as ptr(node))
+      let head = node.new(first(4, 5), &last)
+      sum(&head, 2)
+    ";
+    assert_result_with_interpreter(&mut i, code, Val::I64(7));
+    let ir : String = i.c.code_store.llvm_units.values()
+      .map(|lu| lu.llvm_module.print_to_string().to_string()).collect();
+    assert!(ir.contains("DISubprogram(name: \"sum\""));
+    assert!(ir.contains("DILocalVariable(name: \"n\""));
+    assert!(ir.contains("DICompositeType(tag: DW_TAG_structure_type, name: \"node\""));
+  }
+
+  #[test]
+  fn test_bounds_checked_arrays() {
+    let mut i = interpreter();
+    i.c.bounds_checking = true;
+    let code = "
+      let a = [0, 1, 2, 3, 6]
+      a[1 as u32] = 50
+      a[1] + a[4 as i32] + (a.length as i64)
+    ";
+    assert_result_with_interpreter(&mut i, code, Val::I64(61));
+    let code = "
+      let a = [0, 1, 2]
+      let i = 3
+      a[i]
+    ";
+    match i.eval(code).unwrap_err().message {
+      ErrorContent::Fault { message, .. } => {
+        assert!(message.starts_with("panicked: array index out of bounds"), "{}", message);
+        assert!(message.contains("(line: 4, column: 6 to"), "{}", message);
+        assert!(message.ends_with("(index 3, length 3)"), "{}", message);
+      }
+      m => panic!("expected a fault, found {:?}", m),
+    }
+  }
+
   #[test]
   fn test_struct_format() {
     let mut i = interpreter();
@@ -528,6 +721,559 @@ rusty_fork_test! {
     assert_result(b, Val::I64(44));
   }
 
+  #[test]
+  fn test_region_alloc() {
+    let code = "
+      struct blah { x : i64; y : f64 }
+      let r = create_region()
+      let a = alloc(5) in r
+      let b = alloc(blah.new(10, 2.0)) in r
+      b.x = b.x + *a
+      let total = b.x + (b.y as i64)
+      drop_region(r)
+      total
+    ";
+    assert_result(code, Val::I64(17));
+  }
+
+  #[test]
+  fn test_event_replay() {
+    let code = "
+      static total = 0
+      fun add_event(e : ptr(i64)) { total = total + *e }
+      let log = create_event_log(sizeof(i64))
+      log.record(1)
+      log.record(2)
+      log.next_frame()
+      log.record(10)
+      log.next_frame()
+      log.record(100)
+      log.seek(2 as u64)
+      log.replay(add_event)
+      // Recording after seeking discards the later frames
+      log.record(5)
+      log.replay(add_event)
+      drop_event_log(log)
+      total
+    ";
+    assert_result(code, Val::I64(31));
+  }
+
+  #[test]
+  fn test_region_compaction() {
+    let mut i = interpreter();
+    let code = r#"
+      struct node { v : i64; next : ptr(node) }
+      let this = get_module("regions").unwrap()
+      let r = create_region()
+      let garbage = alloc(node.new(100, 0 as u64 as ptr(node))) in r
+      let root = alloc(node.new(1, 0 as u64 as ptr(node))) in r
+      root.next = alloc(node.new(2, 0 as u64 as ptr(node))) in r
+      let before = region_bytes_used(r)
+      let new_root = compact(&r, root, this, "node")
+      let after = region_bytes_used(r)
+      let total = new_root.v + new_root.next.v
+      drop_region(r)
+      if after < before { total } else { 0 }
+    "#;
+    assert_eq!(i.run_module(code, "regions"), Ok(Val::I64(3)));
+    // Module regions are owned by the code store, and are freed when the module is
+    // unloaded. The type is found in the given module, even though another module
+    // defines a type with the same name.
+    let code = r#"
+      struct node { v : i64; next : ptr(node) }
+      let this = get_module("module_regions").unwrap()
+      let m = load_module(#(1)).unwrap()
+      let r = module_region(m)
+      let garbage = alloc(node.new(100, 0 as u64 as ptr(node))) in r
+      let root = alloc(node.new(4, 0 as u64 as ptr(node))) in r
+      let new_root = compact(&r, root, this, "node")
+      let v = new_root.v
+      unload_module(m)
+      v
+    "#;
+    assert_eq!(i.run_module(code, "module_regions"), Ok(Val::I64(4)));
+    let code = r#"
+      struct leaf { v : i64 }
+      let r = create_region()
+      let root = alloc(leaf.new(1)) in r
+      compact(&r, root, get_module("regions").unwrap(), "leaf")
+    "#;
+    assert!(i.eval(code).is_err());
+  }
+
+  #[test]
+  fn test_state_migration() {
+    let mut i = interpreter();
+    let old = "
+      struct counter { a : i64; b : f64 }
+      static c = counter.new(1, 2.0)
+      c.a = 5
+    ";
+    let new = "
+      struct counter { b : f64; extra : i64; a : i64 }
+      static c = counter.new(0.0, 7, 0)
+    ";
+    let (old, _) = i.c.load_module(old, None, &[]).unwrap();
+    let (new, _) = i.c.load_module(new, None, &[]).unwrap();
+    i.c.migrate_state(old, new);
+    i.c.code_store.remove_unit(old);
+    let (_, val) = i.c.load_module("c.a + c.extra + (c.b as i64)", None, &[new]).unwrap();
+    assert_eq!(val, Val::I64(14));
+    // A pointer to a value whose layout changed can't be carried over
+    let old = "
+      struct node { v : i64 }
+      static n = node.new(5)
+      static p = &n
+      static k = 3
+    ";
+    let new = "
+      struct node { extra : i64; v : i64 }
+      static n = node.new(0, 0)
+      static p = &n
+      static k = 0
+    ";
+    let (old, _) = i.c.load_module(old, None, &[]).unwrap();
+    let (new, _) = i.c.load_module(new, None, &[]).unwrap();
+    let report = i.c.migrate_state(old, new);
+    i.c.code_store.remove_unit(old);
+    assert_eq!(report.skipped, vec![i.c.cache.get("p")]);
+    let (_, val) = i.c.load_module("p.v + k", None, &[new]).unwrap();
+    assert_eq!(val, Val::I64(8));
+  }
+
+  #[test]
+  fn test_snapshot_restore() {
+    let mut i = interpreter();
+    let old = "
+      struct counter { a : i64; b : f64 }
+      static c = counter.new(1, 2.0)
+      static values = list()
+      static changed = 3
+      c.a = 5
+      values.add(10)
+      values.add(20)
+    ";
+    let new = "
+      struct counter { a : i64; b : f64 }
+      static c = counter.new(0, 0.0)
+      static values = list()
+      static changed = 1.5
+      values.add(1)
+    ";
+    let (old, _) = i.c.load_module(old, None, &[]).unwrap();
+    let snapshot = snapshot::snapshot_globals(&i.c.code_store, old);
+    i.c.code_store.remove_unit(old);
+    let (new, _) = i.c.load_module(new, None, &[]).unwrap();
+    let report = snapshot::restore_globals(&i.c.code_store, new, &snapshot).unwrap();
+    // Globals whose types changed keep their new values
+    assert_eq!(report.skipped.len(), 1);
+    let (_, val) = i.c.load_module("
+      c.a + (c.b as i64) + values[0] + values[1] + (values.len() as i64) + (changed as i64)
+    ", None, &[new]).unwrap();
+    assert_eq!(val, Val::I64(40));
+  }
+
+  #[test]
+  fn test_snapshot_list_capacity() {
+    let mut i = interpreter();
+    let decls = "
+      static cleared : list(i64) = list()
+      static grown : list(i64) = list()
+    ";
+    let old = format!("{}
+      cleared.add(1)
+      cleared.add(2)
+      cleared.clear()
+      grown.add(1)
+      grown.add(2)
+      grown.add(3)
+    ", decls);
+    let (old, _) = i.c.load_module(&old, None, &[]).unwrap();
+    let snapshot = snapshot::snapshot_globals(&i.c.code_store, old);
+    i.c.code_store.remove_unit(old);
+    let (new, _) = i.c.load_module(decls, None, &[]).unwrap();
+    let report = snapshot::restore_globals(&i.c.code_store, new, &snapshot).unwrap();
+    assert_eq!(report.skipped.len(), 0);
+    // Only the initialised elements are restored, but the spare capacity is kept
+    let (_, val) = i.c.load_module("
+      let before = (cleared.len() + grown.len()) as i64
+      cleared.add(7)
+      grown.add(4)
+      before * 100 + cleared[0] + grown[0] + grown[2] + grown[3]
+    ", None, &[new]).unwrap();
+    assert_eq!(val, Val::I64(315));
+  }
+
+  #[test]
+  fn test_snapshot_malformed() {
+    let mut i = interpreter();
+    let (old, _) = i.c.load_module("static x = 5", None, &[]).unwrap();
+    let mut snapshot = snapshot::snapshot_globals(&i.c.code_store, old);
+    i.c.code_store.remove_unit(old);
+    let (new, _) = i.c.load_module("static x = 1", None, &[]).unwrap();
+    // The only object is the global, and its element count comes before its bytes
+    let n = snapshot.len();
+    snapshot[n - 24 .. n - 16].copy_from_slice(&2u64.to_le_bytes());
+    assert!(snapshot::restore_globals(&i.c.code_store, new, &snapshot).is_err());
+    assert!(snapshot::restore_globals(&i.c.code_store, new, &snapshot[..n - 4]).is_err());
+    let (_, val) = i.c.load_module("x", None, &[new]).unwrap();
+    assert_eq!(val, Val::I64(1));
+  }
+
+  #[test]
+  fn test_incremental_update() {
+    let mut i = interpreter();
+    let (lib, _) = i.c.load_module("
+      fun f() { 1 }
+      fun g() { 2 }
+    ", Some("lib"), &[]).unwrap();
+    let (a, _) = i.c.load_module("
+      static count = 10
+      count = count + 5
+      fun use_f() { f() + count }
+    ", Some("a"), &[lib]).unwrap();
+    let (b, _) = i.c.load_module("fun use_g() { g() }", Some("b"), &[lib]).unwrap();
+    let reloaded = i.c.update_module(lib, "
+      fun f() { 3 }
+      fun g() { 4 as f64 }
+    ").unwrap();
+    // Only the unit using the changed signature is reloaded
+    assert!(!reloaded.contains_key(&a));
+    let b = *reloaded.get(&b).unwrap();
+    let (_, val) = i.c.load_module("use_f() + (use_g() as i64)", None, &[a, b]).unwrap();
+    assert_eq!(val, Val::I64(22));
+    // If a dependent fails to compile, the update is rolled back
+    let update = i.c.update_module(lib, "
+      fun f() { true }
+      fun g() { 4 as f64 }
+    ");
+    assert!(update.is_err());
+    let (_, val) = i.c.load_module("use_f() + (use_g() as i64)", None, &[a, b]).unwrap();
+    assert_eq!(val, Val::I64(22));
+    // The updated unit keeps its globals, and only the functions that changed are compiled
+    let counter_code = "
+      static count = 10
+      count = count + 5
+      fun get() { count }
+      fun scaled() { count * 2 }
+    ";
+    let (counter, _) = i.c.load_module(counter_code, Some("counter"), &[]).unwrap();
+    i.c.load_module("count = 1", None, &[counter]).unwrap();
+    i.c.update_module(counter, &counter_code.replace("count * 2", "count * 3")).unwrap();
+    let (_, val) = i.c.load_module("get() + scaled()", None, &[counter]).unwrap();
+    assert_eq!(val, Val::I64(4));
+    let cs = &i.c.code_store;
+    let has_body = |name : &str| {
+      let def = cs.types(counter).symbols.values().find(|def| def.name.as_ref() == name).unwrap();
+      let f = cs.llvm_unit(counter).llvm_module.get_function(def.codegen_name().unwrap()).unwrap();
+      f.count_basic_blocks() > 0
+    };
+    assert!(!has_body("get"));
+    assert!(has_body("scaled"));
+  }
+
+  #[test]
+  fn test_replace_module() {
+    let mut i = interpreter();
+    let (lib, _) = i.c.load_module("
+      static x = 2
+      x = 5
+      fun f() { x }
+    ", Some("lib"), &[]).unwrap();
+    let (dep, _) = i.c.load_module("fun g() { f() + 1 }", Some("dep"), &[lib]).unwrap();
+    // A failed replacement leaves the old version running
+    assert!(i.c.replace_module(lib, "fun f() { sdfsdfsdf }").is_err());
+    let (_, val) = i.c.load_module("g()", None, &[dep]).unwrap();
+    assert_eq!(val, Val::I64(6));
+    let (remapped, _) = i.c.replace_module(lib, "
+      static x = 0
+      fun f() { x * 2 }
+    ").unwrap();
+    let dep = *remapped.get(&dep).unwrap();
+    assert_eq!(i.c.code_store.named_unit("lib"), remapped.get(&lib).cloned());
+    let (_, val) = i.c.load_module("g()", None, &[dep]).unwrap();
+    assert_eq!(val, Val::I64(11));
+    // Top levels of the replacements only run once all of them have compiled
+    let (log, _) = i.c.load_module("static runs = 0", Some("log"), &[]).unwrap();
+    let (lib, _) = i.c.load_module("fun f() { 1 }", Some("lib2"), &[log]).unwrap();
+    i.c.load_module("fun h() { f() + 1 }", Some("dep2"), &[lib]).unwrap();
+    let replaced = i.c.replace_module(lib, "
+      runs = runs + 1
+      fun f() { true }
+    ");
+    assert!(replaced.is_err());
+    let (_, val) = i.c.load_module("runs", None, &[log]).unwrap();
+    assert_eq!(val, Val::I64(0));
+  }
+
+  #[test]
+  fn test_hotload_while_running() {
+    let dir = std::env::temp_dir().join("cauldron_test_hotload");
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("lib.code");
+    std::fs::write(&path, "fun f() { 1 }").unwrap();
+    let mut i = interpreter();
+    i.c.function_indirection = true;
+    i.run_file(path.to_str().unwrap()).unwrap();
+    let mut hotloader = watcher::Hotloader::new();
+    hotloader.update_watched_files(&i.c);
+    i.c.hotloader = Some(hotloader);
+    std::fs::write(&path, "fun f() { 2 }").unwrap();
+    std::thread::sleep(std::time::Duration::from_millis(1500));
+    // A top level that is still running picks up the edit when it asks for it
+    let code = "
+      let before = f()
+      reload_changed_files()
+      before * 10 + f()
+    ";
+    assert_result_with_interpreter(&mut i, code, Val::I64(12));
+  }
+
+  #[test]
+  fn test_node_graph() {
+    let dir = std::env::temp_dir().join("cauldron_test_node_graph");
+    std::fs::create_dir_all(&dir).unwrap();
+    let files = [
+      ("graph.code", r#"
+        node(name: "config", source: "config.code", outputs: ["speed"])
+        node(name: "physics", source: "physics.code", inputs: ["config"])
+        node(name: "audio", source: "audio.code", entry: "start")
+      "#),
+      ("config.code", "fun speed() { 2 }"),
+      ("physics.code", "static position = speed() * 10"),
+      ("audio.code", "static volume = 5 ; fun start() { volume = volume + 1 }"),
+    ];
+    for (name, code) in files.iter() {
+      std::fs::write(dir.join(name), code).unwrap();
+    }
+    let mut i = interpreter();
+    i.load_graph(dir.join("graph.code").to_str().unwrap()).unwrap();
+    let audio = i.c.code_store.named_unit("audio");
+    let config = i.c.code_store.named_unit("config").unwrap();
+    i.c.load_module("static shown = speed()", Some("ui"), &[config]).unwrap();
+    std::fs::write(dir.join("config.code"), "fun speed() { 3 }").unwrap();
+    assert!(i.reload_file(&dir.join("config.code")).unwrap());
+    // Only the nodes downstream of the change are evaluated again
+    assert_eq!(i.c.code_store.named_unit("audio"), audio);
+    let physics = i.c.code_store.named_unit("physics").unwrap();
+    let (_, val) = i.c.load_module("position", None, &[physics]).unwrap();
+    assert_eq!(val, Val::I64(30));
+    // Units outside the graph that import a node are evaluated again too
+    let ui = i.c.code_store.named_unit("ui").unwrap();
+    let (_, val) = i.c.load_module("shown", None, &[ui]).unwrap();
+    assert_eq!(val, Val::I64(3));
+    // A node that doesn't define its outputs leaves the old version running
+    std::fs::write(dir.join("config.code"), "fun rate() { 4 }").unwrap();
+    assert!(i.reload_file(&dir.join("config.code")).is_err());
+    assert_eq!(i.c.code_store.named_unit("physics"), Some(physics));
+    // Entry points are called once the graph is loaded
+    let (_, val) = i.c.load_module("volume", None, &[audio.unwrap()]).unwrap();
+    assert_eq!(val, Val::I64(6));
+  }
+
+  #[test]
+  fn test_node_graph_format() {
+    let mut i = interpreter();
+    let graph = r#"
+      node(name: "a", source: "a.code", outputs: ["f", "g"])
+      node(source: "dir/b.code", inputs: ["a"], name: "b", entry: "main")
+    "#;
+    let expected = concat!(
+      "node(name: \"a\", source: \"a.code\", outputs: [\"f\", \"g\"])\n",
+      "node(name: \"b\", source: \"dir/b.code\", inputs: [\"a\"], entry: \"main\")\n");
+    let nodes = node_graph::parse_graph(&mut i.c, graph).unwrap();
+    assert_eq!(node_graph::graph_to_string(&nodes), expected);
+    let nodes = node_graph::parse_graph(&mut i.c, expected).unwrap();
+    assert_eq!(node_graph::graph_to_string(&nodes), expected);
+    // Escaped strings survive saving and loading the graph again
+    let graph = r#"
+      node(name: "a \"quoted\"", source: "dir\\a.code", entry: "\tmain")
+    "#;
+    let nodes = node_graph::parse_graph(&mut i.c, graph).unwrap();
+    let path = std::env::temp_dir().join("cauldron_test_node_graph_format.code");
+    node_graph::NodeGraph::new(nodes, std::path::Path::new("")).unwrap().save(&path).unwrap();
+    let loaded = node_graph::NodeGraph::from_file(&mut i.c, path.to_str().unwrap()).unwrap();
+    let n = &loaded.nodes[0];
+    assert_eq!(n.name.as_ref(), "a \"quoted\"");
+    assert_eq!(n.source, std::path::Path::new("dir\\a.code"));
+    assert_eq!(n.entry.as_ref().map(|e| e.as_ref()), Some("\tmain"));
+  }
+
+  #[test]
+  fn test_packages() {
+    let dir = std::env::temp_dir().join("cauldron_test_packages");
+    let files = [
+      ("math/package.code", r#"package(name: "math", root: "src", modules: ["vec", "util"])"#),
+      ("math/src/vec.code", "fun double(x : i64) { x * 2 }"),
+      ("math/src/util.code", "fun quadruple(x : i64) { double(double(x)) }"),
+      ("physics/package.code", r#"package(name: "physics", dependencies: ["../math"], modules: ["step"])"#),
+      ("physics/step.code", "fun step(x : i64) { quadruple(x) + 1 }"),
+      ("game/package.code", r#"package(name: "game", dependencies: ["../math", "../physics"], modules: ["main"])"#),
+      ("game/main.code", "static result = step(double(5))"),
+    ];
+    for (name, code) in files.iter() {
+      let path = dir.join(name);
+      std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+      std::fs::write(path, code).unwrap();
+    }
+    let mut i = interpreter();
+    // A package that fails part way through can be loaded again once it is fixed
+    std::fs::write(dir.join("math/src/util.code"), "fun quadruple(x : i64) { triple(x) }").unwrap();
+    assert!(i.load_package(dir.join("game").to_str().unwrap()).is_err());
+    assert!(i.c.code_store.named_unit("math/vec").is_none());
+    std::fs::write(dir.join("math/src/util.code"), files[2].1).unwrap();
+    i.load_package(dir.join("game").to_str().unwrap()).unwrap();
+    // Modules are named after their packages, and shared dependencies are only loaded once
+    assert!(i.c.code_store.named_unit("math/util").is_some());
+    assert!(i.c.code_store.named_unit("core/prelude").is_some());
+    assert_result_with_interpreter(&mut i, "result", Val::I64(41));
+  }
+
+  #[test]
+  fn test_namespaced_imports() {
+    let mut i = interpreter();
+    let lib = "
+      struct point {
+        x : i64
+        y : i64
+      }
+      fun f() { 10 }
+    ";
+    i.c.load_module(lib, Some("lib"), &[]).unwrap();
+    let code = r#"
+      import "lib" as l
+      let p = l.point.new(x: 20, y: 2)
+      l.f() + p.x + p.y
+    "#;
+    assert_result_with_interpreter(&mut i, code, Val::I64(32));
+    // Namespaced symbols can't be referred to without their namespace
+    assert!(i.eval("import \"lib\" as l\n f()").is_err());
+    assert!(i.eval("import \"lib\"\n f()") == Ok(Val::I64(10)));
+    assert!(i.eval("import \"nowhere\" as n\n 1").is_err());
+    assert_error("import \"lib\" as l\n import \"lib\" as l", "imported more than once");
+    assert_error("import \"lib\" as l\n fun l() { 1 }", "already the name of an imported namespace");
+  }
+
+  #[test]
+  fn test_relative_file_imports() {
+    let dir = std::env::temp_dir().join("cauldron_test_relative_imports");
+    std::fs::create_dir_all(dir.join("lib")).unwrap();
+    std::fs::write(dir.join("main.code"), "import \"lib/helper.code\"\n helper()").unwrap();
+    std::fs::write(dir.join("lib/helper.code"), "import \"value.code\"\n fun helper() { value() * 2 }").unwrap();
+    std::fs::write(dir.join("lib/value.code"), "fun value() { 21 }").unwrap();
+    // Imported files are found relative to the importing file, not the working directory
+    let mut i = interpreter();
+    let val = i.run_file(dir.join("main.code").to_str().unwrap()).unwrap();
+    assert_eq!(val, Val::I64(42));
+  }
+
+  #[test]
+  fn test_visibility() {
+    let mut i = interpreter();
+    let lib = "
+      private
+      pub struct counter {
+        pub count : i64
+        step : i64
+      }
+      fun helper(c : counter) { c.count + c.step }
+      pub fun next(c : counter) { helper(c) }
+      pub fun make() { counter.new(count: 1, step: 10) }
+    ";
+    i.c.load_module(lib, Some("lib"), &[]).unwrap();
+    assert_result_with_interpreter(&mut i, "import \"lib\"\n make().next() + make().count", Val::I64(12));
+    // Private symbols, fields and constructors can't be used by importers
+    assert!(i.eval("import \"lib\"\n helper(make())").is_err());
+    assert!(i.eval("import \"lib\"\n make().step").is_err());
+    assert!(i.eval("import \"lib\"\n counter.new(count: 1, step: 2).count").is_err());
+    assert_error("fun f() { pub let x = 5 }", "can be marked 'pub'");
+    // Without a top-level 'private' statement, unmarked definitions stay public
+    i.c.load_module("pub fun a() { 1 }\n fun b() { 2 }", Some("lib2"), &[]).unwrap();
+    assert_result_with_interpreter(&mut i, "import \"lib2\"\n a() + b()", Val::I64(3));
+    assert_error("fun f() { private\n 1 }", "top level of a module");
+  }
+
+  #[test]
+  fn test_repl_commands() {
+    let mut i = interpreter();
+    i.eval("static counter = 5\n fun add(a : i64, b : i64) { a + b }").unwrap();
+    // Typechecking an expression doesn't run it
+    assert_eq!(repl::meta_command(&mut i, ":type counter = 6").unwrap(), "Void");
+    assert_eq!(repl::meta_command(&mut i, ":type add(counter, 1)").unwrap(), "I64");
+    assert_result_with_interpreter(&mut i, "counter", Val::I64(5));
+    let symbols = repl::meta_command(&mut i, ":symbols").unwrap();
+    assert!(symbols.contains("counter : I64"));
+    assert!(repl::meta_command(&mut i, ":ir add").unwrap().contains("define"));
+    assert!(repl::meta_command(&mut i, ":units").unwrap().contains("core/prelude"));
+    i.c.load_module("fun f() { 1 }", Some("lib"), &[]).unwrap();
+    assert_eq!(repl::meta_command(&mut i, ":unload lib").unwrap(), "unloaded lib");
+    assert!(i.c.code_store.named_unit("lib").is_none());
+    // Only files that have changed since they were loaded are reloaded
+    let path = std::env::temp_dir().join("cauldron_test_repl_reload.code");
+    std::fs::write(&path, "fun reloaded_value() { 1 }").unwrap();
+    repl::meta_command(&mut i, &format!(":load {}", path.display())).unwrap();
+    assert_eq!(repl::meta_command(&mut i, ":reload").unwrap(), "reloaded 0 modules");
+    std::fs::write(&path, "fun reloaded_value() { 2 }").unwrap();
+    assert_eq!(repl::meta_command(&mut i, ":reload").unwrap(), "reloaded 1 modules");
+    assert_result_with_interpreter(&mut i, "reloaded_value()", Val::I64(2));
+    // Loading a file again reloads it, rather than loading a second copy
+    std::fs::write(&path, "fun reloaded_value() { 3 }").unwrap();
+    let load = format!(":load {}", path.display());
+    assert_eq!(repl::meta_command(&mut i, &load).unwrap(), format!("reloaded {}", path.display()));
+    assert_result_with_interpreter(&mut i, "reloaded_value()", Val::I64(3));
+    assert!(i.c.load_file(path.to_str().unwrap(), &[]).is_err());
+    assert!(repl::meta_command(&mut i, ":frobnicate").is_err());
+  }
+
+  #[test]
+  fn test_repl_helper() {
+    use rustyline::completion::Completer;
+    use rustyline::highlight::Highlighter;
+    use std::{rc::Rc, cell::RefCell};
+    let mut i = interpreter();
+    i.eval("struct point {\n x : i64\n y : i64\n }\n static origin = point.new(x: 0, y: 0)").unwrap();
+    let completions = repl_helper::Completions::from_interpreter(&i);
+    let helper = repl_helper::ReplHelper::new(Rc::new(RefCell::new(completions)));
+    let complete = |line : &str| -> Vec<String> {
+      let (_, candidates) = helper.complete(line, line.len()).unwrap();
+      candidates.into_iter().map(|c| c.replacement).collect()
+    };
+    assert!(complete("let a = ori").contains(&"origin".to_string()));
+    assert!(complete("origin.").contains(&"x".to_string()));
+    assert!(complete("stat").contains(&"static".to_string()));
+    // Brackets inside strings don't count
+    assert!(repl_helper::is_incomplete("fun f() {", &i.c.cache));
+    assert!(!repl_helper::is_incomplete("fun f() { \"{\" }", &i.c.cache));
+    // A string ending in a backslash is malformed, rather than crashing the lexer
+    assert_eq!(helper.highlight("\"abc\\", 0), "\"abc\\");
+    assert!(!repl_helper::is_incomplete("\"abc\\", &i.c.cache));
+  }
+
+  #[test]
+  fn test_function_indirection() {
+    let mut i = interpreter();
+    i.c.function_indirection = true;
+    let (lib, _) = i.c.load_module("fun f() { 1 }", Some("lib"), &[]).unwrap();
+    let trampoline = {
+      let cs = &i.c.code_store;
+      let def = cs.types(lib).symbols.values().find(|def| def.name.as_ref() == "f").unwrap();
+      cs.indirection.trampoline(cs, def).unwrap()
+    };
+    let f : extern "C" fn() -> i64 = unsafe { std::mem::transmute(trampoline) };
+    assert_eq!(f(), 1);
+    let lib = i.c.reload_module(lib, "fun f() { 2 }").unwrap()[&lib];
+    assert_eq!(f(), 2);
+    // A reload that fails to compile leaves the old version loaded
+    assert!(i.c.reload_module(lib, "fun f() { undefined_symbol }").is_err());
+    assert_eq!(i.c.code_store.named_unit("lib"), Some(lib));
+    assert_eq!(f(), 2);
+    // The old slot of a function whose signature changed panics instead of running freed code
+    let lib = i.c.reload_module(lib, "fun f() { true }").unwrap()[&lib];
+    let name = i.c.cache.get("f");
+    assert!(i.c.call_guarded(lib, &name, || f()).is_err());
+  }
+
   #[test]
   fn test_nonexistent_types(){
     let code = "
@@ -0,0 +1,103 @@
+// Compacting collector for regions. A region has a single root object, so
+// everything live in the region can be found by tracing from that root.
+
+use crate::{code_store, types, layout, region, structure};
+use code_store::CodeStore;
+use types::{Type, TypeContent};
+use layout::{type_layout, field_layouts, type_def};
+use region::Region;
+use structure::TypeKind;
+
+use std::collections::HashMap;
+use std::ptr;
+
+/// Copies everything reachable from `root` that lives in the `old` region into
+/// a new region, rewriting pointers as it goes. Returns the new region and the
+/// new address of the root. `root` must point to a value of type `root_type`.
+///
+/// Pointers into other memory are left alone. Pointers are assumed to refer to
+/// the start of an allocation, and unions are copied without being traced,
+/// because there is no way to tell which of their fields is active.
+pub fn compact(cs : &CodeStore, old : &Region, root : *mut u8, root_type : &Type)
+  -> (Box<Region>, *mut u8)
+{
+  let mut t = Tracer {
+    cs, old,
+    new: Box::new(Region::new()),
+    forwarded: HashMap::new(),
+    worklist: vec![],
+  };
+  let root = t.copy(root, root_type, 1);
+  while let Some((addr, element_type, count)) = t.worklist.pop() {
+    let size = type_layout(cs, &element_type).size;
+    for i in 0..count {
+      t.scan(addr + i * size, &element_type);
+    }
+  }
+  (t.new, root)
+}
+
+struct Tracer<'l> {
+  cs : &'l CodeStore,
+  old : &'l Region,
+  new : Box<Region>,
+
+  /// Map from the old address of each copied allocation to its new address
+  forwarded : HashMap<usize, usize>,
+
+  /// Allocations that have been copied but not yet scanned for pointers
+  worklist : Vec<(usize, Type, usize)>,
+}
+
+impl <'l> Tracer<'l> {
+
+  /// Copies an allocation of `count` elements if it lives in the old region
+  fn copy(&mut self, p : *mut u8, element_type : &Type, count : usize) -> *mut u8 {
+    if p.is_null() || !self.old.contains(p) {
+      return p;
+    }
+    if let Some(&new_p) = self.forwarded.get(&(p as usize)) {
+      return new_p as *mut u8;
+    }
+    let l = type_layout(self.cs, element_type);
+    let bytes = l.size * count;
+    let new_p = self.new.alloc(bytes, l.align);
+    unsafe { ptr::copy_nonoverlapping(p, new_p, bytes) };
+    self.forwarded.insert(p as usize, new_p as usize);
+    self.worklist.push((new_p as usize, element_type.clone(), count));
+    new_p
+  }
+
+  /// Rewrites any pointers in the value at `addr`, copying what they point to
+  fn scan(&mut self, addr : usize, t : &Type) {
+    match &t.content {
+      TypeContent::Ptr => {
+        let inner = t.ptr().unwrap();
+        let slot = addr as *mut *mut u8;
+        unsafe { *slot = self.copy(*slot, inner, 1) };
+      }
+      TypeContent::Def(name, _) => {
+        match name.as_ref() {
+          // These both have the layout { data : ptr(T), length : u64 }
+          "array" | "string" => {
+            let element_type =
+              if name.as_ref() == "array" { t.children()[0].clone() }
+              else { types::PType::U8.into() };
+            let slot = addr as *mut *mut u8;
+            let length = unsafe { *((addr + 8) as *const u64) } as usize;
+            unsafe { *slot = self.copy(*slot, &element_type, length) };
+          }
+          _ => {
+            let def = type_def(self.cs, t).unwrap();
+            if let TypeKind::Struct = def.kind {
+              for f in field_layouts(self.cs, t) {
+                self.scan(addr + f.offset, &f.t);
+              }
+            }
+          }
+        }
+      }
+      _ => (),
+    }
+  }
+}
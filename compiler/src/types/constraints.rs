@@ -296,6 +296,7 @@ impl <'l, 't> ConstraintGenerator<'l, 't> {
         type_tag: Type::any(),
         initialiser: SymbolInit::Function(f),
         type_vars: type_vars.iter().cloned().collect(),
+        is_public: n.is_public(id),
       }
     });
     // Bind the symbol definition to its type symbol
@@ -381,6 +382,7 @@ impl <'l, 't> ConstraintGenerator<'l, 't> {
             type_tag: Type::any(),
             initialiser,
             type_vars: vec![],
+            is_public: n.is_public(id),
           });
           self.constraint(SymbolDef{
             symbol_id,
@@ -498,8 +500,13 @@ impl <'l, 't> ConstraintGenerator<'l, 't> {
           initialiser: SymbolInit::CBind,
           type_tag: Type::any(),
           type_vars: vec![],
+          is_public: n.is_public(id),
         });
       }
+      Content::Import { .. } => {
+        // Imports are resolved by the compiler before typechecking
+        self.assert(slot, PType::Void);
+      }
       Content::TypeAlias { alias, type_aliased } => {
         // TODO: not yet implemented
         self.assert(slot, PType::Void);
@@ -529,6 +536,9 @@ impl <'l, 't> ConstraintGenerator<'l, 't> {
               fields: fields.iter().map(|(f, _)| (f.clone(), Type::any())).collect(),
               kind: *kind,
               type_vars,
+              is_public: n.is_public(id),
+              private_fields:
+                fields.iter().filter(|(f, _)| !n.is_public_field(f)).map(|(f, _)| f.name.clone()).collect(),
             };
             gc.mapping.type_def_nodes.insert(name.clone(), id);
             gc.t.create_type_def(def);
@@ -674,6 +684,12 @@ impl <'l, 't> ConstraintGenerator<'l, 't> {
         return Ok(gc.symbol_to_type(name));
       }
       match expr.try_construct() {
+        // A type from an imported namespace
+        Some((".", [namespace, name])) => {
+          if let (Some(namespace), Some(name)) = (namespace.try_symbol(), name.try_symbol()) {
+            return Ok(gc.symbol_to_type(&format!("{}.{}", namespace, name)));
+          }
+        }
         Some(("fun", es)) => {
           if let Some(args) = es.get(0) {
             let return_type = 
@@ -718,6 +734,14 @@ impl <'l, 't> ConstraintGenerator<'l, 't> {
 /// or in the other modules in scope.
 pub struct TypeDirectory<'a> {
   pub imports : Vec<UnitId>,
+
+  /// Units whose private definitions are visible. This is the unit being typechecked and,
+  /// for a polymorphic instance, the unit that defines the function.
+  pub internal_units : Vec<UnitId>,
+
+  /// Imported units whose definitions are only found by qualified names
+  pub namespaces : HashMap<RefStr, UnitId>,
+
   pub new_unit_id : UnitId,
   pub types : &'a mut HashMap<UnitId, TypeInfo>,
   polytype_bindings : HashMap<RefStr, Type>,
@@ -728,14 +752,24 @@ pub struct TypeDirectory<'a> {
 // This could probably be improved with some caching, although any caching needs to
 // be wary of new symbols being added.
 impl <'a> TypeDirectory<'a> {
-  pub fn new(imports : Vec<UnitId>, new_unit_id : UnitId, types : &'a mut HashMap<UnitId, TypeInfo>) -> Self {
+  pub fn new(
+    imports : Vec<UnitId>, namespaces : HashMap<RefStr, UnitId>,
+    new_unit_id : UnitId, types : &'a mut HashMap<UnitId, TypeInfo>)
+      -> Self
+  {
     TypeDirectory {
-      imports, new_unit_id, types,
+      imports, namespaces, new_unit_id, types,
+      internal_units: vec![new_unit_id],
       polytype_bindings: HashMap::new(),
       symbol_results: vec![],
     }
   }
 
+  /// Whether private definitions of the unit can be used
+  pub fn is_internal(&self, unit_id : UnitId) -> bool {
+    self.internal_units.contains(&unit_id)
+  }
+
   pub fn get_symbol(&self, id : SymbolId) -> &SymbolDefinition {
     self.types.get(&id.uid).unwrap().symbols.get(&id).unwrap()
   }
@@ -775,22 +809,47 @@ impl <'a> TypeDirectory<'a> {
   {
     self.polytype_bindings.clear();
     self.symbol_results.clear();
+    if let Some((namespace, name)) = split_qualified_name(name) {
+      if let Some(uid) = self.namespaces.get(namespace) {
+        let type_info = self.types.get(uid).unwrap();
+        let public_only = !self.internal_units.contains(uid);
+        type_info.find_symbol(
+          name, t, public_only, &mut self.polytype_bindings, &mut self.symbol_results);
+      }
+      return self.symbol_results.as_slice();
+    }
     self.types.get(&self.new_unit_id).unwrap()
-      .find_symbol(name, t, &mut self.polytype_bindings, &mut self.symbol_results);
+      .find_symbol(name, t, false, &mut self.polytype_bindings, &mut self.symbol_results);
     for uid in self.imports.iter() {
       let type_info = self.types.get(uid).unwrap();
-      type_info.find_symbol(name, t, &mut self.polytype_bindings, &mut self.symbol_results);
+      let public_only = !self.internal_units.contains(uid);
+      type_info.find_symbol(
+        name, t, public_only, &mut self.polytype_bindings, &mut self.symbol_results);
     }
     self.symbol_results.as_slice()
   }
 
   pub fn find_type_def(&self, name : &str) -> Option<&TypeDefinition> {
+    if let Some((namespace, name)) = split_qualified_name(name) {
+      let uid = self.namespaces.get(namespace)?;
+      let def = self.types.get(uid).unwrap().find_type_def(name)?;
+      return Some(def).filter(|def| def.is_public || self.is_internal(*uid));
+    }
     self.types.get(&self.new_unit_id).unwrap()
       .find_type_def(name).or_else(||
         self.imports.iter().rev().flat_map(|uid| {
           let type_info = self.types.get(uid).unwrap();
-          type_info.find_type_def(name)
+          type_info.find_type_def(name).filter(|def| def.is_public || self.is_internal(*uid))
         }).next()
       )
   }
 }
+
+/// Splits a name of the form `namespace.name`
+fn split_qualified_name(name : &str) -> Option<(&str, &str)> {
+  let i = name.find('.')?;
+  if i == 0 || i == name.len() - 1 {
+    return None;
+  }
+  Some((&name[..i], &name[i + 1..]))
+}
@@ -32,6 +32,7 @@ fn reference_type(n : Node) -> ReferenceType {
     Literal(_val) => Val,
     VariableInitialise{ name:_, type_tag:_, value:_, var_scope:_ } => Val,
     TypeAlias{ alias:_, type_aliased:_ } => Val,
+    Import{ path:_, namespace:_ } => Val,
     Assignment{ assignee :_, value:_ } => {
       // check that assignee is a ref
       Val
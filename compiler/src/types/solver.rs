@@ -47,8 +47,9 @@ pub fn typecheck_module(
   code_store.types.insert(unit_id, TypeInfo::new(unit_id));
   let mut mapping = TypeMapping::new();
   let mut errors = TypeErrors::new();
+  let namespaces = code_store.namespaces.get(&unit_id).cloned().unwrap_or_default();
   let mut type_directory =
-    TypeDirectory::new(imports, unit_id, &mut code_store.types);
+    TypeDirectory::new(imports, namespaces, unit_id, &mut code_store.types);
   let nodes = code_store.nodes.get(&unit_id).unwrap();
   let c =
     constraints::get_module_constraints(
@@ -78,11 +79,18 @@ pub fn typecheck_polymorphic_function_instance(
   code_store.types.insert(instance_unit, TypeInfo::new(instance_unit));
   let mut mapping = TypeMapping::new();
   let mut errors = TypeErrors::new();
-  let imports : Vec<_> = code_store.get_imports(instance_unit).cloned().collect();
+  // Instances refer to namespaces the same way as the function they are instances of
+  let namespaces =
+    code_store.namespaces.get(&poly_function_id.uid).cloned().unwrap_or_default();
+  let imports : Vec<_> =
+    code_store.get_imports(instance_unit).cloned()
+    .filter(|uid| namespaces.values().all(|ns| ns != uid))
+    .collect();
   let instanced_type_vars =
     code_store.symbol_def(poly_function_id).instanced_type_vars(instance_type);
   let mut type_directory =
-    TypeDirectory::new(imports, instance_unit, &mut code_store.types);
+    TypeDirectory::new(imports, namespaces, instance_unit, &mut code_store.types);
+  type_directory.internal_units.push(poly_function_id.uid);
   let nodes = code_store.nodes.get(&poly_function_id.uid).unwrap();
   let source_node =
     *code_store.type_mappings.get(&poly_function_id.uid).unwrap()
@@ -192,7 +200,8 @@ impl <'a> Inference<'a> {
             };
             children.push(c);
           }
-          let content = Def(name.clone(), def.unit_id);
+          // Qualified names are resolved to the name the type was defined with
+          let content = Def(def.name.clone(), def.unit_id);
           return Ok(Type::new(content, children));
         }
         else {
@@ -315,6 +324,10 @@ impl <'a> Inference<'a> {
             let def = self.t.get_type_def(name, *unit_id);
             match def.kind {
               TypeKind::Struct => {
+                if def.private_fields.len() > 0 && !self.t.is_internal(*unit_id) {
+                  let s = format!("type '{}' has private fields, so it can only be constructed by its own module", def.name);
+                  errors.push(error_raw(self.c.loc(*def_slot), s));
+                }
                 if fields.len() == def.fields.len() {
                   let it = fields.iter().zip(def.fields.iter());
                   let mut field_types = vec![];
@@ -429,6 +442,10 @@ impl <'a> Inference<'a> {
             g.register_typedef(name, c);
             let def = self.t.get_type_def(&name, *unit_id);
             let field_type = def.instanced_field_type(&field.name, t.children.as_slice());
+            if field_type.is_some() && !self.t.is_internal(*unit_id) && def.private_fields.contains(&field.name) {
+              let s = format!("field '{}' of type '{}' is private", field.name, def.name);
+              errors.push(error_raw(field.loc, s));
+            }
             if let Some(t) = field_type {
               slots.update_type(g, errors, *result, &t);
               return;
@@ -326,6 +326,12 @@ pub struct TypeDefinition {
   pub kind : TypeKind,
   pub fields : Vec<(Reference, Type)>,
   pub type_vars : Vec<RefStr>,
+
+  /// Whether units that import this one can use the type
+  pub is_public : bool,
+
+  /// Fields that can only be accessed by the unit that defines the type
+  pub private_fields : Vec<RefStr>,
 }
 
 impl TypeDefinition {
@@ -390,6 +396,9 @@ pub struct SymbolDefinition {
   pub type_tag : Type,
   pub initialiser : SymbolInit,
   pub type_vars : Vec<RefStr>,
+
+  /// Whether units that import this one can refer to the symbol
+  pub is_public : bool,
 }
 
 impl SymbolDefinition {
@@ -651,10 +660,11 @@ impl TypeInfo {
     &'a self,
     name : &str,
     t : &Type,
+    public_only : bool,
     polytypes : &mut HashMap<RefStr, Type>,
     results : &mut Vec<ResolvedSymbol>) {
     for sym in self.symbols.values() {
-      if sym.name.as_ref() == name {
+      if sym.name.as_ref() == name && (sym.is_public || !public_only) {
         if sym.is_polymorphic() {
           polytypes.clear();
           if polytype_match(polytypes, t, &sym.type_tag) {
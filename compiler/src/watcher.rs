@@ -1,18 +1,72 @@
-use notify::{Watcher, RecursiveMode, watcher, DebouncedEvent};
-use std::sync::mpsc::{channel, TryRecvError};
+use notify::{Watcher, RecommendedWatcher, RecursiveMode, watcher, DebouncedEvent};
+use std::sync::mpsc::{channel, TryRecvError, Sender};
 use std::time::Duration;
 use std::thread;
 use std::default::Default;
 
-use std::io::{BufReader, BufRead};
+use std::io::{BufReader, BufRead, Write};
+use std::net::{TcpListener, TcpStream, SocketAddr};
 use std::str;
 
 use subprocess::{Popen, PopenConfig, Redirection};
 
-pub fn run_process(path : &str) -> Popen {
+use crate::interpret::{Interpreter, interpreter};
+use crate::compiler::{Val, Compiler};
+use crate::error::Error;
+use crate::print_result;
+
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::fs;
+
+/// What a hotloading child process asks the parent to watch. Requests are sent as
+/// lines over a socket of their own, so that they can't be confused with program output.
+pub enum WatchRequest {
+  Watch(PathBuf),
+  Unwatch(PathBuf),
+}
+
+impl WatchRequest {
+  fn parse(line : &str) -> Option<WatchRequest> {
+    let line = line.trim_end();
+    if line.starts_with("watch ") {
+      Some(WatchRequest::Watch(PathBuf::from(&line["watch ".len()..])))
+    }
+    else if line.starts_with("unwatch ") {
+      Some(WatchRequest::Unwatch(PathBuf::from(&line["unwatch ".len()..])))
+    }
+    else {
+      None
+    }
+  }
+}
+
+/// Accepts connections from child processes, and forwards their watch requests
+fn listen_for_watch_requests(requests : Sender<WatchRequest>) -> SocketAddr {
+  let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+  let address = listener.local_addr().unwrap();
+  thread::spawn(move || {
+    for stream in listener.incoming() {
+      let stream = match stream { Ok(s) => s, Err(_) => continue };
+      for line in BufReader::new(stream).lines() {
+        let line = match line { Ok(l) => l, Err(_) => break };
+        if let Some(r) = WatchRequest::parse(&line) {
+          if requests.send(r).is_err() {
+            return;
+          }
+        }
+      }
+    }
+  });
+  address
+}
+
+pub fn run_process(path : &str, watch_requests : SocketAddr) -> Popen {
   let exe = std::env::current_exe().unwrap();
   let exe = exe.to_str().unwrap();
-  let mut p = Popen::create(&[exe, "run", path], PopenConfig {
+  let address = watch_requests.to_string();
+  let args = [exe, "hotload", path, "--watch-requests", address.as_str()];
+  let mut p = Popen::create(&args, PopenConfig {
       stdout: Redirection::Pipe, ..Default::default()
   }).unwrap();
   let stdout = p.stdout.take().unwrap();
@@ -47,7 +101,12 @@ fn spawn_stdin_channel() -> Receiver<String> {
 }
 
 pub fn watch(path : &str) {
-  let mut process = Some(run_process(path));
+  // The child process reports the files it loads, so that they can be watched
+  let (watch_tx, watch_rx) = channel();
+  let watch_address = listen_for_watch_requests(watch_tx);
+  let mut watched = HashSet::new();
+
+  let mut process = Some(run_process(path, watch_address));
 
   // Create a channel to receive the events.
   let (tx, rx) = channel();
@@ -58,14 +117,27 @@ pub fn watch(path : &str) {
   // The notification back-end is selected based on the platform.
   let mut watcher = watcher(tx, Duration::from_millis(500)).unwrap();
 
-  // Add a path to be watched. All files and directories at that path and
-  // below will be monitored for changes.
-  watcher.watch(path, RecursiveMode::Recursive).unwrap();
-  for &path in &["code/core/prelude.code", "code/core/list.code", "code/core/compiler.code"] {
-    watcher.watch(path, RecursiveMode::Recursive).unwrap();
-  }
+  watcher.watch(path, RecursiveMode::NonRecursive).unwrap();
 
   loop {
+    while let Ok(request) = watch_rx.try_recv() {
+      match request {
+        WatchRequest::Watch(p) => {
+          if !watched.contains(&p) {
+            if let Err(e) = watcher.watch(&p, RecursiveMode::NonRecursive) {
+              println!("failed to watch {}: {:?}", p.display(), e);
+            }
+            watched.insert(p);
+          }
+        }
+        WatchRequest::Unwatch(p) => {
+          if watched.remove(&p) {
+            let _ = watcher.unwatch(&p);
+          }
+        }
+      }
+    }
+
     if let Some(mut p) = process {
       // check if the process is still alive
       let exit_status = p.poll();
@@ -74,6 +146,7 @@ pub fn watch(path : &str) {
         let (_, err) = p.communicate(None).unwrap();
         if !exit_status.success() {
           println!("ERROR:\n{:?}", exit_status);
+          println!("Save a file or press enter to restart");
         }
         if let Some(err) = err {
           println!("{}", err);
@@ -90,7 +163,7 @@ pub fn watch(path : &str) {
       match c.try_recv() {
         Ok(_input_line) => {
           if process.is_none() {
-            process = Some(run_process(path));
+            process = Some(run_process(path, watch_address));
           }
         }
         Err(TryRecvError::Empty) => (),
@@ -101,16 +174,20 @@ pub fn watch(path : &str) {
       }
     }
 
-    // Read watch events, to restart the process
+    // Read watch events. The child process reloads the files it asked to have
+    // watched itself. Other changes restart it, as does any change once it has exited.
     match rx.try_recv() {
       Ok(event) => {
         match event {
-          DebouncedEvent::Write(_) => {
-            if let Some(p) = &mut process {
-              p.kill().unwrap();
-              println!("Child process killed");
+          DebouncedEvent::Write(changed) => {
+            let changed = fs::canonicalize(&changed).unwrap_or(changed);
+            if process.is_none() || !watched.contains(&changed) {
+              if let Some(p) = &mut process {
+                let _ = p.kill();
+                println!("Child process killed");
+              }
+              process = Some(run_process(path, watch_address));
             }
-            process = Some(run_process(path));
           }
           _ => {}
         }
@@ -123,4 +200,131 @@ pub fn watch(path : &str) {
     }
     thread::sleep(Duration::from_millis(10));
   }
-}
\ No newline at end of file
+}
+/// Watches the files that loaded modules came from, so that they can be reloaded
+/// when they change
+pub struct Hotloader {
+  watcher : RecommendedWatcher,
+  events : Receiver<DebouncedEvent>,
+  watched : HashSet<PathBuf>,
+
+  /// The parent process in watch mode, which is told what to watch as well
+  parent : Option<TcpStream>,
+}
+
+impl Hotloader {
+  pub fn new() -> Hotloader {
+    let (tx, events) = channel();
+    let watcher = watcher(tx, Duration::from_millis(500)).unwrap();
+    Hotloader { watcher, events, watched: HashSet::new(), parent: None }
+  }
+
+  /// Watches exactly the set of files that the loaded modules came from
+  pub fn update_watched_files(&mut self, c : &Compiler) {
+    let files = c.code_store.source_files();
+    for p in self.watched.difference(&files) {
+      let _ = self.watcher.unwatch(p);
+      self.tell_parent("unwatch", p);
+    }
+    for p in files.difference(&self.watched) {
+      if let Err(e) = self.watcher.watch(p, RecursiveMode::NonRecursive) {
+        println!("failed to watch {}: {:?}", p.display(), e);
+      }
+      self.tell_parent("watch", p);
+    }
+    self.watched = files;
+  }
+
+  fn tell_parent(&self, request : &str, path : &Path) {
+    if let Some(mut parent) = self.parent.as_ref() {
+      let _ = writeln!(parent, "{} {}", request, path.display());
+    }
+  }
+
+  /// The files written since the last call. If `wait` is true, blocks until there is one.
+  pub fn changed_files(&mut self, wait : bool) -> Vec<PathBuf> {
+    let mut changed = vec![];
+    if wait {
+      match self.events.recv() {
+        Ok(DebouncedEvent::Write(p)) => changed.push(p),
+        Ok(_) => (),
+        Err(e) => println!("watch error: {:?}", e),
+      }
+    }
+    while let Ok(event) = self.events.try_recv() {
+      if let DebouncedEvent::Write(p) = event {
+        if !changed.contains(&p) {
+          changed.push(p);
+        }
+      }
+    }
+    changed
+  }
+}
+
+fn report_reload(path : &Path, result : Result<bool, Error>) {
+  match result {
+    Ok(true) => println!("Reloaded {}", path.display()),
+    Ok(false) => (),
+    Err(e) => println!("Failed to reload {}:\n{}", path.display(), e.display()),
+  }
+}
+
+/// Reloads the modules whose files changed since the last call. Running programs call
+/// this through the `reload_changed_files` cbind, so that a top level that never returns,
+/// such as a game loop, still picks up edits. Does nothing unless the compiler is hotloading.
+pub fn reload_changed_files(c : &mut Compiler) {
+  if let Some(mut hotloader) = c.hotloader.take() {
+    for path in hotloader.changed_files(false) {
+      let result = c.reload_file(&path);
+      report_reload(&path, result);
+    }
+    hotloader.update_watched_files(c);
+    c.hotloader = Some(hotloader);
+  }
+}
+
+/// Runs a program in this process, then reloads modules whenever their source files
+/// change. Only the changed units and the units that depend on them are recompiled.
+/// Programs that don't return, such as game loops, pick up edits by calling
+/// `reload_changed_files`. Function indirection is enabled, so that code which is
+/// already running calls the new versions of reloaded functions.
+///
+/// If the process was started by `watch`, the files are watched by the parent as well,
+/// so that it can restart the program if it exits. `parent` is the address that the
+/// parent listens for watch requests on.
+pub fn hotload(path : &str, parent : Option<&str>) {
+  let mut i = interpreter();
+  i.c.function_indirection = true;
+  let mut hotloader = Hotloader::new();
+  if let Some(address) = parent {
+    match TcpStream::connect(address) {
+      Ok(stream) => hotloader.parent = Some(stream),
+      Err(e) => println!("failed to connect to the watching process: {}", e),
+    }
+  }
+  hotloader.update_watched_files(&i.c);
+  i.c.hotloader = Some(hotloader);
+  println!("{}", print_result(i.run_file(path)));
+  reload_on_change(&mut i);
+}
+
+/// Loads a node graph in this process and calls its entry points, then reloads nodes
+/// whenever their source files change, along with the nodes downstream of them.
+pub fn hotload_graph(path : &str) {
+  let mut i = interpreter();
+  println!("{}", print_result(i.load_graph(path).map(|_| Val::Void)));
+  reload_on_change(&mut i);
+}
+
+fn reload_on_change(i : &mut Interpreter) {
+  let mut hotloader = i.c.hotloader.take().unwrap_or_else(Hotloader::new);
+  hotloader.update_watched_files(&i.c);
+  loop {
+    for changed in hotloader.changed_files(true) {
+      let result = i.reload_file(&changed);
+      report_reload(&changed, result);
+    }
+    hotloader.update_watched_files(&i.c);
+  }
+}